@@ -1,7 +1,8 @@
-use crate::file::File;
+use crate::error::AwbiError;
+use crate::logging::DBG_BANK;
 use crate::memlist::MemEntry;
 use anyhow::{ensure, Result};
-use std::io::{Read, Seek, SeekFrom};
+use std::convert::TryFrom;
 
 #[derive(Default, Debug)]
 pub struct UnpackContext {
@@ -12,26 +13,31 @@ pub struct UnpackContext {
 }
 
 /// Packed data. Access values in reverse order.
-#[derive(Default)]
-struct PackedData {
-    data: Vec<u8>,
+struct PackedData<'a> {
+    data: &'a [u8],
     pos: usize,
 }
 
-impl PackedData {
-    fn new(data: Vec<u8>) -> Self {
-        let pos = data.len();
-        Self { data, pos }
+impl<'a> PackedData<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: data.len(),
+        }
     }
 
-    fn read(&mut self) -> u32 {
+    fn read(&mut self) -> Result<u32> {
+        ensure!(
+            self.pos >= 4,
+            AwbiError::bad_data("Bank::unpack() packed data is truncated")
+        );
         self.pos -= 4;
-        u32::from_be_bytes([
+        Ok(u32::from_be_bytes([
             self.data[self.pos],
             self.data[self.pos + 1],
             self.data[self.pos + 2],
             self.data[self.pos + 3],
-        ])
+        ]))
     }
 }
 
@@ -56,98 +62,131 @@ impl UnpackedData {
     }
 }
 
-#[derive(Default)]
-pub(crate) struct Bank {
-    unp_ctx: UnpackContext,
-    packed: PackedData,
-    unpacked: UnpackedData,
-}
+/// Data of the entry `me` in the bank file `bank`, unpacked if needed.
+pub(crate) fn read_entry_data(bank: &[u8], me: &MemEntry) -> Result<Vec<u8>> {
+    let packed = usize::try_from(me.bank_offset)
+        .ok()
+        .and_then(|offset| bank.get(offset..offset.checked_add(me.packed_size)?))
+        .ok_or_else(|| {
+            AwbiError::bad_data(format!(
+                "Resource at {:#X} of bank {} is out of the bank",
+                me.bank_offset, me.bank_id
+            ))
+        })?;
 
-impl Bank {
-    pub fn read(&mut self, data_dir: &str, me: &MemEntry) -> Result<Vec<u8>> {
-        let mut f = self.read_bank(data_dir, me.bank_id)?;
+    // Depending if the resource is packed or not we
+    // can read directly or unpack it.
 
-        self.read_entry_data(&mut f.file_impl, me)
+    if me.packed_size == me.size {
+        Ok(packed.to_vec())
+    } else {
+        unpack(packed, me.size)
     }
+}
 
-    pub fn read_bank(&self, data_dir: &str, bank_id: u8) -> Result<File> {
-        let bank_name = format!("bank{:02x}", bank_id);
-        File::open(&bank_name, data_dir, false)
+/// Unpack a resource of at most `max_size` bytes. Nothing is shared between calls, so
+/// resources can be unpacked on several threads.
+pub(crate) fn unpack(packed: &[u8], max_size: usize) -> Result<Vec<u8>> {
+    Unpacker {
+        unp_ctx: UnpackContext::default(),
+        packed: PackedData::new(packed),
+        unpacked: UnpackedData::default(),
     }
+    .unpack(max_size)
+}
 
-    pub fn read_entry_data<T: Read + Seek>(
-        &mut self,
-        src: &mut T,
-        me: &MemEntry,
-    ) -> Result<Vec<u8>> {
-        let mut buf = vec![0; me.packed_size];
-
-        src.seek(SeekFrom::Start(me.bank_offset))?;
-        src.read_exact(&mut buf)?;
-
-        // Depending if the resource is packed or not we
-        // can read directly or unpack it.
-
-        if me.packed_size == me.size {
-            Ok(buf)
-        } else {
-            self.packed = PackedData::new(buf);
-            self.unpack()
-        }
-    }
+struct Unpacker<'a> {
+    unp_ctx: UnpackContext,
+    packed: PackedData<'a>,
+    unpacked: UnpackedData,
+}
 
-    fn unpack(&mut self) -> Result<Vec<u8>> {
+impl<'a> Unpacker<'a> {
+    fn unpack(mut self, max_size: usize) -> Result<Vec<u8>> {
         self.unp_ctx.size = 0;
-        self.unp_ctx.data_size = self.packed.read();
-        self.unp_ctx.crc = self.packed.read();
-        self.unp_ctx.chk = self.packed.read();
+        self.unp_ctx.data_size = self.packed.read()?;
+        self.unp_ctx.crc = self.packed.read()?;
+        self.unp_ctx.chk = self.packed.read()?;
         self.unp_ctx.crc ^= self.unp_ctx.chk;
 
+        ensure!(
+            self.unp_ctx.data_size as usize <= max_size,
+            AwbiError::bad_data(format!(
+                "Bank::unpack() unpacked size {} is over {}",
+                self.unp_ctx.data_size, max_size
+            ))
+        );
         self.unpacked = UnpackedData::new(self.unp_ctx.data_size as usize);
 
         while self.unp_ctx.data_size > 0 {
-            if !self.next_chunk() {
+            if !self.next_chunk()? {
                 self.unp_ctx.size = 1;
-                if !self.next_chunk() {
-                    self.dec_unk1(3, 0);
+                if !self.next_chunk()? {
+                    self.dec_unk1(3, 0)?;
                 } else {
-                    self.dec_unk2(8);
+                    self.dec_unk2(8)?;
                 }
             } else {
-                let c = self.get_code(2);
+                let c = self.get_code(2)?;
                 if c == 3 {
-                    self.dec_unk1(8, 8);
+                    self.dec_unk1(8, 8)?;
                 } else if c < 2 {
                     self.unp_ctx.size = c + 2;
-                    self.dec_unk2(c as u8 + 9);
+                    self.dec_unk2(c as u8 + 9)?;
                 } else {
-                    self.unp_ctx.size = self.get_code(8);
-                    self.dec_unk2(12);
+                    self.unp_ctx.size = self.get_code(8)?;
+                    self.dec_unk2(12)?;
                 }
             }
         }
-        ensure!(self.unp_ctx.crc == 0, "CRC should be 0");
+        ensure!(
+            self.unp_ctx.crc == 0,
+            AwbiError::bad_data("Bank::unpack() CRC should be 0")
+        );
 
-        Ok(self.unpacked.data.clone())
+        Ok(self.unpacked.data)
     }
 
-    fn dec_unk1(&mut self, num_chunks: u8, add_count: u8) {
-        let count = self.get_code(num_chunks) + add_count as u16 + 1;
-        // debug(DBG_BANK, "Bank::decUnk1(%d, %d) count=%d", numChunks, addCount, count);
-        self.unp_ctx.data_size -= count as u32;
+    // Counts are checked against the remaining size, so the writes stay in the buffer.
+    fn consume(&mut self, count: u16) -> Result<()> {
+        self.unp_ctx.data_size = self
+            .unp_ctx
+            .data_size
+            .checked_sub(count as u32)
+            .ok_or_else(|| {
+                AwbiError::bad_data("Bank::unpack() data overflows the unpacked size")
+            })?;
+        Ok(())
+    }
+
+    fn dec_unk1(&mut self, num_chunks: u8, add_count: u8) -> Result<()> {
+        let count = self.get_code(num_chunks)? + add_count as u16 + 1;
+        trace!(
+            DBG_BANK,
+            "Bank::dec_unk1({}, {}) count={}",
+            num_chunks,
+            add_count,
+            count
+        );
+        self.consume(count)?;
         for _ in 0..count {
-            let val = self.get_code(8) as u8;
+            let val = self.get_code(8)? as u8;
             self.unpacked.write(val);
         }
+        Ok(())
     }
 
     /*
        Note from fab: This look like run-length encoding.
     */
-    fn dec_unk2(&mut self, num_chunks: u8) {
-        let i = self.get_code(num_chunks) as usize;
+    fn dec_unk2(&mut self, num_chunks: u8) -> Result<()> {
+        let i = self.get_code(num_chunks)? as usize;
         let count = self.unp_ctx.size + 1;
-        self.unp_ctx.data_size -= count as u32;
+        self.consume(count)?;
+        ensure!(
+            i > 0 && self.unpacked.pos + i <= self.unpacked.data.len(),
+            AwbiError::bad_data(format!("Bank::unpack() invalid back-reference {}", i))
+        );
 
         // println!("dec_unk2({}): i={} count={} unp_pos={} size={}",
         //     num_chunks, i, count, self.unpacked.pos, self.unpacked.data.len());
@@ -156,27 +195,28 @@ impl Bank {
             let val = self.unpacked.data[self.unpacked.pos + i - 1];
             self.unpacked.write(val);
         }
+        Ok(())
     }
 
-    fn get_code(&mut self, num_chunks: u8) -> u16 {
+    fn get_code(&mut self, num_chunks: u8) -> Result<u16> {
         let mut c = 0;
         for _ in 0..num_chunks {
             c <<= 1;
-            if self.next_chunk() {
+            if self.next_chunk()? {
                 c |= 1;
             }
         }
-        c
+        Ok(c)
     }
 
-    fn next_chunk(&mut self) -> bool {
+    fn next_chunk(&mut self) -> Result<bool> {
         let mut cf = self.rcr(false);
         if self.unp_ctx.chk == 0 {
-            self.unp_ctx.chk = self.packed.read();
+            self.unp_ctx.chk = self.packed.read()?;
             self.unp_ctx.crc ^= self.unp_ctx.chk;
             cf = self.rcr(true);
         }
-        cf
+        Ok(cf)
     }
 
     fn rcr(&mut self, cf: bool) -> bool {
@@ -188,3 +228,176 @@ impl Bank {
         rcf
     }
 }
+
+// Bits in the order `unpack` consumes them.
+#[derive(Default)]
+struct BitWriter {
+    bits: Vec<bool>,
+}
+
+impl BitWriter {
+    fn put(&mut self, bit: bool) {
+        self.bits.push(bit);
+    }
+
+    // Most significant bit first, as `get_code` reads it.
+    fn put_code(&mut self, num_bits: u8, code: u16) {
+        for i in (0..num_bits).rev() {
+            self.put(code & (1 << i) != 0);
+        }
+    }
+
+    fn put_literals(&mut self, literals: &[u8]) {
+        for chunk in literals.chunks(264) {
+            if chunk.len() <= 8 {
+                self.put(false);
+                self.put(false);
+                self.put_code(3, chunk.len() as u16 - 1);
+            } else {
+                self.put(true);
+                self.put_code(2, 3);
+                self.put_code(8, chunk.len() as u16 - 9);
+            }
+            for b in chunk {
+                self.put_code(8, *b as u16);
+            }
+        }
+    }
+
+    fn put_match(&mut self, len: usize, dist: usize) {
+        match len {
+            2 => {
+                self.put(false);
+                self.put(true);
+                self.put_code(8, dist as u16);
+            }
+            3 | 4 => {
+                self.put(true);
+                self.put_code(2, len as u16 - 3);
+                self.put_code(len as u8 + 6, dist as u16);
+            }
+            _ => {
+                self.put(true);
+                self.put_code(2, 2);
+                self.put_code(8, len as u16 - 1);
+                self.put_code(12, dist as u16);
+            }
+        }
+    }
+}
+
+// Longest back-reference the format can encode for its length.
+fn max_dist(len: usize) -> usize {
+    match len {
+        2 => 0xFF,
+        3 => 0x1FF,
+        4 => 0x3FF,
+        _ => 0xFFF,
+    }
+}
+
+// Longest run of the bytes preceding `pos` that repeats the already unpacked data after it.
+fn find_match(data: &[u8], pos: usize) -> Option<(usize, usize)> {
+    let mut best: Option<(usize, usize)> = None;
+
+    for dist in 1..=usize::min(0xFFF, data.len() - pos) {
+        let mut len = 0;
+        while len < 256 && len < pos && data[pos - 1 - len] == data[pos - 1 - len + dist] {
+            len += 1;
+        }
+        while len >= 2 && dist > max_dist(len) {
+            len -= 1;
+        }
+        let longer = match best {
+            Some((best_len, _)) => len > best_len,
+            None => true,
+        };
+        if len >= 2 && longer {
+            best = Some((len, dist));
+        }
+    }
+
+    best
+}
+
+/// Pack `data` in the format `unpack` reads. The matches are found greedily, so the
+/// output is valid but bigger than the one of the original packer. Used to build test data.
+pub(crate) fn pack(data: &[u8]) -> Vec<u8> {
+    let mut bits = BitWriter::default();
+    let mut literals = Vec::new();
+
+    // Data is unpacked from the end.
+    let mut pos = data.len();
+    while pos > 0 {
+        match find_match(data, pos) {
+            Some((len, dist)) => {
+                bits.put_literals(&literals);
+                literals.clear();
+                bits.put_match(len, dist);
+                pos -= len;
+            }
+            None => {
+                literals.push(data[pos - 1]);
+                pos -= 1;
+            }
+        }
+    }
+    bits.put_literals(&literals);
+
+    let words: Vec<u32> = bits
+        .bits
+        .chunks(32)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .enumerate()
+                .fold(0, |word, (i, bit)| word | ((*bit as u32) << i))
+        })
+        .collect();
+
+    // The first check word holds only the end marker, the data starts with the next word.
+    let chk = 1;
+    let crc = words.iter().fold(chk, |crc, word| crc ^ word);
+
+    let mut packed = Vec::with_capacity((words.len() + 3) * 4);
+    for word in words.iter().rev().chain(&[chk, crc, data.len() as u32]) {
+        packed.extend_from_slice(&word.to_be_bytes());
+    }
+    packed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unpack(packed: Vec<u8>) -> Result<Vec<u8>> {
+        super::unpack(&packed, 0xFFFF)
+    }
+
+    #[test]
+    fn test_pack() -> Result<()> {
+        let mut data: Vec<u8> = (0..=255).collect();
+        data.extend_from_slice(&[0x55; 1000]);
+        data.extend((0..400).map(|i| (i % 7) as u8));
+        data.extend_from_slice(b"Another World");
+
+        let packed = pack(&data);
+        assert!(packed.len() < data.len());
+        assert_eq!(unpack(packed.clone())?, data);
+
+        assert_eq!(unpack(pack(&[]))?, Vec::<u8>::new());
+        assert_eq!(unpack(pack(&[7]))?, vec![7]);
+
+        // Broken checksum.
+        let mut broken = packed;
+        let last = broken.len() - 5;
+        broken[last] ^= 1;
+        assert!(unpack(broken).is_err());
+
+        // Truncated data and sizes over the limit are errors, not panics.
+        assert!(unpack(vec![0; 7]).is_err());
+        assert!(super::unpack(&pack(&data), 100).is_err());
+
+        Ok(())
+    }
+}
@@ -1,4 +1,7 @@
+use crate::error::AwbiError;
 use crate::slice_reader::SliceReader;
+use crate::staticres::VARIABLE_NAME_BY_INDEX;
+use crate::symbols::Symbols;
 use anyhow::{bail, Result};
 use std::fmt;
 
@@ -98,12 +101,11 @@ impl fmt::Debug for ResetType {
 }
 
 fn var_name(id: u8) -> String {
-    format!("0x{:02X}", id)
-    // if let Some(name) = VARIABLE_NAME_BY_INDEX.get(&(id as usize)) {
-    //     name.to_string()
-    // } else {
-    //     format!("0x{:02X}", id)
-    // }
+    if let Some(name) = VARIABLE_NAME_BY_INDEX.get(&(id as usize)) {
+        name.to_string()
+    } else {
+        format!("0x{:02X}", id)
+    }
 }
 
 pub(crate) enum Command {
@@ -226,71 +228,69 @@ impl Command {
     pub fn parse(opcode: u8, sr: &mut SliceReader) -> Result<Self> {
         let res = match opcode {
             0x00 => Self::MovConst {
-                var_id: OpVar(sr.read_u8()),
-                val: sr.read_u16(),
+                var_id: OpVar(sr.read_u8()?),
+                val: sr.read_u16()?,
             },
             0x01 => Self::Mov {
-                dst_id: OpVar(sr.read_u8()),
-                src_id: OpVar(sr.read_u8()),
+                dst_id: OpVar(sr.read_u8()?),
+                src_id: OpVar(sr.read_u8()?),
             },
             0x02 => Self::Add {
-                dst_id: OpVar(sr.read_u8()),
-                src_id: OpVar(sr.read_u8()),
+                dst_id: OpVar(sr.read_u8()?),
+                src_id: OpVar(sr.read_u8()?),
             },
             0x03 => Self::AddConst {
-                var_id: OpVar(sr.read_u8()),
-                val: sr.read_u16(),
+                var_id: OpVar(sr.read_u8()?),
+                val: sr.read_u16()?,
             },
             0x04 => Self::Call {
-                offset: sr.read_u16(),
+                offset: sr.read_u16()?,
             },
             0x05 => Self::Ret,
             0x06 => Self::PauseThread,
             0x07 => Self::Jmp {
-                offset: sr.read_u16(),
+                offset: sr.read_u16()?,
             },
             0x08 => Self::SetVect {
-                thr_id: sr.read_u8(),
-                offset: sr.read_u16(),
+                thr_id: sr.read_u8()?,
+                offset: sr.read_u16()?,
             },
             0x09 => Self::Jnz {
-                var_id: OpVar(sr.read_u8()),
-                offset: sr.read_u16(),
+                var_id: OpVar(sr.read_u8()?),
+                offset: sr.read_u16()?,
             },
             0x0A => {
-                let oc = sr.read_u8();
-                let var_id = OpVar(sr.read_u8());
-                let c = sr.read_u8();
+                let oc = sr.read_u8()?;
+                let var_id = OpVar(sr.read_u8()?);
+                let c = sr.read_u8()?;
                 let op2 = if oc & 0x80 != 0 {
                     OpType::Var(c)
                 } else if oc & 0x40 != 0 {
-                    OpType::Val2((c as u16) * 256 + sr.read_u8() as u16)
+                    OpType::Val2((c as u16) * 256 + sr.read_u8()? as u16)
                 } else {
                     OpType::Val1(c)
                 };
 
+                // Unknown jump types are kept to be reported by the validator, executing
+                // them fails.
                 let jmp_type = JmpType::new(oc & 7)?;
 
-                if let JmpType::Unknown(jt) = jmp_type {
-                    bail!("Command::parse() invalid jmp opcode {}", jt)
-                }
-
                 Self::CondJmp {
                     jmp_type,
                     var_id,
                     op2,
-                    offset: sr.read_u16(),
+                    offset: sr.read_u16()?,
                 }
             }
             0x0B => Self::SetPalette {
-                pal_id: sr.read_u16(),
+                pal_id: sr.read_u16()?,
             },
             0x0C => {
-                let first = sr.read_u8();
-                let last = sr.read_u8();
+                let first = sr.read_u8()?;
+                let last = sr.read_u8()?;
 
                 if last < first {
-                    println!("Command::parse(): first({}) > last({})", first, last);
+                    warning!("Command::parse(): first({}) > last({})", first, last);
 
                     Self::ResetThread {
                         reset_type: ResetType::None,
@@ -298,10 +298,10 @@ impl Command {
                         last,
                     }
                 } else {
-                    let reset_type = ResetType::new(sr.read_u8());
+                    let reset_type = ResetType::new(sr.read_u8()?);
 
                     if let ResetType::Unknown(rt) = reset_type {
-                        println!("Command::parse() invalid resetThread opcode {}", rt);
+                        warning!("Command::parse() invalid resetThread opcode {}", rt);
                     }
 
                     Self::ResetThread {
@@ -312,66 +312,66 @@ impl Command {
                 }
             }
             0x0D => Self::SelectVideoPage {
-                page_id: sr.read_u8(),
+                page_id: sr.read_u8()?,
             },
             0x0E => Self::FillVideoPage {
-                page_id: sr.read_u8(),
-                color: sr.read_u8(),
+                page_id: sr.read_u8()?,
+                color: sr.read_u8()?,
             },
             0x0F => Self::CopyVideoPage {
-                src_page_id: sr.read_u8(),
-                dst_page_id: sr.read_u8(),
+                src_page_id: sr.read_u8()?,
+                dst_page_id: sr.read_u8()?,
             },
             0x10 => Self::BlitFramebuffer {
-                page_id: sr.read_u8(),
+                page_id: sr.read_u8()?,
             },
             0x11 => Self::KillThread,
             0x12 => Self::DrawString {
-                str_id: sr.read_u16(),
-                x: sr.read_u8(),
-                y: sr.read_u8(),
-                color: sr.read_u8(),
+                str_id: sr.read_u16()?,
+                x: sr.read_u8()?,
+                y: sr.read_u8()?,
+                color: sr.read_u8()?,
             },
             0x13 => Self::Sub {
-                dst_id: OpVar(sr.read_u8()),
-                src_id: OpVar(sr.read_u8()),
+                dst_id: OpVar(sr.read_u8()?),
+                src_id: OpVar(sr.read_u8()?),
             },
             0x14 => Self::And {
-                var_id: OpVar(sr.read_u8()),
-                val: sr.read_u16(),
+                var_id: OpVar(sr.read_u8()?),
+                val: sr.read_u16()?,
             },
             0x15 => Self::Or {
-                var_id: OpVar(sr.read_u8()),
-                val: sr.read_u16(),
+                var_id: OpVar(sr.read_u8()?),
+                val: sr.read_u16()?,
             },
             0x16 => Self::Shl {
-                var_id: OpVar(sr.read_u8()),
-                val: sr.read_u16(),
+                var_id: OpVar(sr.read_u8()?),
+                val: sr.read_u16()?,
             },
             0x17 => Self::Shr {
-                var_id: OpVar(sr.read_u8()),
-                val: sr.read_u16(),
+                var_id: OpVar(sr.read_u8()?),
+                val: sr.read_u16()?,
             },
             0x18 => Self::PlaySound {
-                res_id: sr.read_u16(),
-                freq: sr.read_u8(),
-                vol: sr.read_u8(),
-                channel: sr.read_u8(),
+                res_id: sr.read_u16()?,
+                freq: sr.read_u8()?,
+                vol: sr.read_u8()?,
+                channel: sr.read_u8()?,
             },
             0x19 => Self::UpdateMemList {
-                res_id: sr.read_u16(),
+                res_id: sr.read_u16()?,
             },
             0x1A => Self::PlayMusic {
-                res_id: sr.read_u16(),
-                delay: sr.read_u16(),
-                pos: sr.read_u8(),
+                res_id: sr.read_u16()?,
+                delay: sr.read_u16()?,
+                pos: sr.read_u8()?,
             },
             _ => {
                 if opcode & 0x80 != 0 {
                     let offset =
-                        ((((opcode as usize) << 8) | (sr.read_u8() as usize)) * 2) & 0xFFFF;
-                    let mut x = sr.read_u8();
-                    let mut y = sr.read_u8();
+                        ((((opcode as usize) << 8) | (sr.read_u8()? as usize)) * 2) & 0xFFFF;
+                    let mut x = sr.read_u8()?;
+                    let mut y = sr.read_u8()?;
 
                     if y > 199 {
                         y = 199;
@@ -380,12 +380,12 @@ impl Command {
 
                     Self::Video1 { offset, x, y }
                 } else if opcode & 0x40 != 0 {
-                    let offset = (sr.read_u16() as usize) * 2;
-                    let x_val = sr.read_u8();
+                    let offset = (sr.read_u16()? as usize) * 2;
+                    let x_val = sr.read_u8()?;
 
                     let x = if opcode & 0x20 == 0 {
                         if opcode & 0x10 == 0 {
-                            OpType::Val2(((x_val as u16) << 8) | (sr.read_u8() as u16))
+                            OpType::Val2(((x_val as u16) << 8) | (sr.read_u8()? as u16))
                         } else {
                             OpType::Var(x_val)
                         }
@@ -395,10 +395,10 @@ impl Command {
                         OpType::Val1(x_val)
                     };
 
-                    let y_val = sr.read_u8();
+                    let y_val = sr.read_u8()?;
                     let y = if opcode & 8 == 0 {
                         if opcode & 4 == 0 {
-                            OpType::Val2(((y_val as u16) << 8) | (sr.read_u8() as u16))
+                            OpType::Val2(((y_val as u16) << 8) | (sr.read_u8()? as u16))
                         } else {
                             OpType::Var(y_val)
                         }
@@ -411,13 +411,13 @@ impl Command {
                         if opcode & 1 == 0 {
                             OpType::Val1(0x40)
                         } else {
-                            OpType::Var(sr.read_u8())
+                            OpType::Var(sr.read_u8()?)
                         }
                     } else if opcode & 1 != 0 {
                         cinematic = false;
                         OpType::Val1(0x40)
                     } else {
-                        OpType::Val1(sr.read_u8())
+                        OpType::Val1(sr.read_u8()?)
                     };
 
                     Self::Video2 {
@@ -428,60 +428,218 @@ impl Command {
                         zoom,
                     }
                 } else {
-                    bail!("Command::parse() invalid opcode=0x{:02X}", opcode);
+                    bail!(AwbiError::bad_data(format!(
+                        "Command::parse() invalid opcode=0x{:02X}",
+                        opcode
+                    )));
                 }
             }
         };
 
         Ok(res)
     }
+
+    /// Format the command using the names of `symbols`.
+    pub fn display<'a>(&'a self, symbols: Option<&'a Symbols>, part_id: u16) -> CommandDisplay<'a> {
+        CommandDisplay {
+            cmd: self,
+            symbols,
+            part_id,
+        }
+    }
+
+    /// Name of the command kind, used by the profiler.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::MovConst { .. } => "MovConst",
+            Self::Mov { .. } => "Mov",
+            Self::Add { .. } => "Add",
+            Self::AddConst { .. } => "AddConst",
+            Self::Call { .. } => "Call",
+            Self::Ret => "Ret",
+            Self::PauseThread => "PauseThread",
+            Self::Jmp { .. } => "Jmp",
+            Self::SetVect { .. } => "SetVect",
+            Self::Jnz { .. } => "Jnz",
+            Self::CondJmp { .. } => "CondJmp",
+            Self::SetPalette { .. } => "SetPalette",
+            Self::ResetThread { .. } => "ResetThread",
+            Self::SelectVideoPage { .. } => "SelectVideoPage",
+            Self::FillVideoPage { .. } => "FillVideoPage",
+            Self::CopyVideoPage { .. } => "CopyVideoPage",
+            Self::BlitFramebuffer { .. } => "BlitFramebuffer",
+            Self::KillThread => "KillThread",
+            Self::DrawString { .. } => "DrawString",
+            Self::Sub { .. } => "Sub",
+            Self::And { .. } => "And",
+            Self::Or { .. } => "Or",
+            Self::Shl { .. } => "Shl",
+            Self::Shr { .. } => "Shr",
+            Self::PlaySound { .. } => "PlaySound",
+            Self::UpdateMemList { .. } => "UpdateMemList",
+            Self::PlayMusic { .. } => "PlayMusic",
+            Self::Video1 { .. } => "Video1",
+            Self::Video2 { .. } => "Video2",
+        }
+    }
+
+    /// Variables the command reads.
+    pub fn src_vars(&self) -> Vec<u8> {
+        let op_var = |op: &OpType| match op {
+            OpType::Var(var_id) => Some(*var_id),
+            _ => None,
+        };
+
+        match self {
+            Self::Mov { src_id, .. } => vec![src_id.0],
+            Self::Add { dst_id, src_id } | Self::Sub { dst_id, src_id } => {
+                vec![dst_id.0, src_id.0]
+            }
+            Self::AddConst { var_id, .. }
+            | Self::Jnz { var_id, .. }
+            | Self::And { var_id, .. }
+            | Self::Or { var_id, .. }
+            | Self::Shl { var_id, .. }
+            | Self::Shr { var_id, .. } => vec![var_id.0],
+            Self::CondJmp { var_id, op2, .. } => {
+                std::iter::once(var_id.0).chain(op_var(op2)).collect()
+            }
+            Self::Video2 { x, y, zoom, .. } => {
+                [x, y, zoom].iter().filter_map(|op| op_var(op)).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Variable the command stores its result to.
+    pub fn dst_var(&self) -> Option<u8> {
+        match self {
+            Self::MovConst { var_id, .. }
+            | Self::AddConst { var_id, .. }
+            | Self::Jnz { var_id, .. }
+            | Self::And { var_id, .. }
+            | Self::Or { var_id, .. }
+            | Self::Shl { var_id, .. }
+            | Self::Shr { var_id, .. } => Some(var_id.0),
+            Self::Mov { dst_id, .. } | Self::Add { dst_id, .. } | Self::Sub { dst_id, .. } => {
+                Some(dst_id.0)
+            }
+            _ => None,
+        }
+    }
 }
 
-impl fmt::Debug for Command {
+/// Command formatted with the names from a symbol file.
+pub(crate) struct CommandDisplay<'a> {
+    cmd: &'a Command,
+    symbols: Option<&'a Symbols>,
+    part_id: u16,
+}
+
+impl CommandDisplay<'_> {
+    fn var(&self, var_id: &OpVar) -> String {
+        match self
+            .symbols
+            .and_then(|s| s.variable(self.part_id, var_id.0))
+        {
+            Some(name) => format!("[{}]", name),
+            None => format!("{:?}", var_id),
+        }
+    }
+
+    fn op(&self, op: &OpType) -> String {
+        match op {
+            OpType::Var(var_id) => self.var(&OpVar(*var_id)),
+            _ => format!("{:?}", op),
+        }
+    }
+
+    fn addr(&self, addr: u16) -> String {
+        match self.symbols.and_then(|s| s.label(self.part_id, addr)) {
+            Some(name) => format!("0x{:04X} <{}>", addr, name),
+            None => format!("0x{:04X}", addr),
+        }
+    }
+
+    fn thread(&self, thread_id: u8) -> String {
+        match self.symbols.and_then(|s| s.thread(self.part_id, thread_id)) {
+            Some(name) => format!("{} <{}>", thread_id, name),
+            None => thread_id.to_string(),
+        }
+    }
+
+    fn res(&self, res_id: u16) -> String {
+        match self.symbols.and_then(|s| s.resource(self.part_id, res_id)) {
+            Some(name) => format!("{} <{}>", res_id, name),
+            None => res_id.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for CommandDisplay<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::MovConst { var_id, val } => f.pad(&format!("mov {:?}, {}", var_id, val)),
-            Self::Mov { dst_id, src_id } => f.pad(&format!("mov {:?}, {:?}", dst_id, src_id)),
-            Self::Add { dst_id, src_id } => f.pad(&format!("add {:?}, {:?}", dst_id, src_id)),
-            Self::AddConst { var_id, val } => f.pad(&format!("add {:?}, {}", var_id, val)),
-            Self::Call { offset } => f.pad(&format!("call 0x{:04X}", offset)),
-            Self::Ret => f.pad("ret"),
-            Self::PauseThread => f.pad("pauseThread"),
-            Self::Jmp { offset } => f.pad(&format!("jmp 0x{:04X}", offset)),
-            Self::SetVect { thr_id, offset } => f.pad(&format!(
-                "setvec channel:{}, address:0x{:04X}",
-                thr_id, offset
+        match self.cmd {
+            Command::MovConst { var_id, val } => {
+                f.pad(&format!("mov {}, {}", self.var(var_id), val))
+            }
+            Command::Mov { dst_id, src_id } => {
+                f.pad(&format!("mov {}, {}", self.var(dst_id), self.var(src_id)))
+            }
+            Command::Add { dst_id, src_id } => {
+                f.pad(&format!("add {}, {}", self.var(dst_id), self.var(src_id)))
+            }
+            Command::AddConst { var_id, val } => {
+                f.pad(&format!("add {}, {}", self.var(var_id), val))
+            }
+            Command::Call { offset } => f.pad(&format!("call {}", self.addr(*offset))),
+            Command::Ret => f.pad("ret"),
+            Command::PauseThread => f.pad("pauseThread"),
+            Command::Jmp { offset } => f.pad(&format!("jmp {}", self.addr(*offset))),
+            Command::SetVect { thr_id, offset } => f.pad(&format!(
+                "setvec channel:{}, address:{}",
+                self.thread(*thr_id),
+                self.addr(*offset)
             )),
-            Self::Jnz { var_id, offset } => f.pad(&format!("jnz {:?}, 0x{:04X}", var_id, offset)),
-            Self::CondJmp {
+            Command::Jnz { var_id, offset } => {
+                f.pad(&format!("jnz {}, {}", self.var(var_id), self.addr(*offset)))
+            }
+            Command::CondJmp {
                 jmp_type,
                 var_id,
                 op2,
                 offset,
             } => f.pad(&format!(
-                "{:?} {:?}, {:?}, 0x{:04X}",
-                jmp_type, var_id, op2, offset
+                "{:?} {}, {}, {}",
+                jmp_type,
+                self.var(var_id),
+                self.op(op2),
+                self.addr(*offset)
             )),
-            Self::SetPalette { pal_id } => f.pad(&format!("setPalette {}", pal_id)),
-            Self::ResetThread {
+            Command::SetPalette { pal_id } => f.pad(&format!("setPalette {}", pal_id)),
+            Command::ResetThread {
                 reset_type,
                 first,
                 last,
-            } => f.pad(&format!("{:?}, first:{}, last:{}", reset_type, first, last)),
-            Self::SelectVideoPage { page_id } => f.pad(&format!("selectVideoPage {}", page_id)),
-            Self::FillVideoPage { page_id, color } => {
+            } => f.pad(&format!(
+                "{:?}, first:{}, last:{}",
+                reset_type,
+                self.thread(*first),
+                self.thread(*last)
+            )),
+            Command::SelectVideoPage { page_id } => f.pad(&format!("selectVideoPage {}", page_id)),
+            Command::FillVideoPage { page_id, color } => {
                 f.pad(&format!("fillVideoPage {}, color:{}", page_id, color))
             }
-            Self::CopyVideoPage {
+            Command::CopyVideoPage {
                 src_page_id,
                 dst_page_id,
             } => f.pad(&format!(
                 "copyVideoPage src:{}, dst:{}",
                 src_page_id, dst_page_id
             )),
-            Self::BlitFramebuffer { page_id } => f.pad(&format!("blitFramebuffer {}", page_id)),
-            Self::KillThread => f.pad("killThread"),
-            Self::DrawString {
+            Command::BlitFramebuffer { page_id } => f.pad(&format!("blitFramebuffer {}", page_id)),
+            Command::KillThread => f.pad("killThread"),
+            Command::DrawString {
                 str_id,
                 x,
                 y,
@@ -490,45 +648,54 @@ impl fmt::Debug for Command {
                 "drawString id:{}, x:{}, y:{}, color:{}",
                 str_id, x, y, color
             )),
-            // f.pad(&format!(
-            //     "drawString id:{}, x:{}, y:{}, color:{}  \"{}\"",
-            //     str_id,
-            //     x,
-            //     y,
-            //     color,
-            //     STRINGS_TABLE_ENG.get(&(*str_id as u16)).unwrap_or(&"")
-            // )),
-            Self::Sub { dst_id, src_id } => f.pad(&format!("sub {:?}, {:?}", dst_id, src_id)),
-            Self::And { var_id, val } => f.pad(&format!("and {:?}, {}", var_id, val)),
-            Self::Or { var_id, val } => f.pad(&format!("or {:?}, {}", var_id, val)),
-            Self::Shl { var_id, val } => f.pad(&format!("shl {:?}, {}", var_id, val)),
-            Self::Shr { var_id, val } => f.pad(&format!("shr {:?}, {}", var_id, val)),
-            Self::PlaySound {
+            Command::Sub { dst_id, src_id } => {
+                f.pad(&format!("sub {}, {}", self.var(dst_id), self.var(src_id)))
+            }
+            Command::And { var_id, val } => f.pad(&format!("and {}, {}", self.var(var_id), val)),
+            Command::Or { var_id, val } => f.pad(&format!("or {}, {}", self.var(var_id), val)),
+            Command::Shl { var_id, val } => f.pad(&format!("shl {}, {}", self.var(var_id), val)),
+            Command::Shr { var_id, val } => f.pad(&format!("shr {}, {}", self.var(var_id), val)),
+            Command::PlaySound {
                 res_id,
                 freq,
                 vol,
                 channel,
             } => f.pad(&format!(
                 "play id:{}, freq:{}, vol:{}, channel:{}",
-                res_id, freq, vol, channel
+                self.res(*res_id),
+                freq,
+                vol,
+                channel
             )),
-            Self::UpdateMemList { res_id } => f.pad(&format!("load id:{}", res_id)),
-            Self::PlayMusic { res_id, delay, pos } => {
-                f.pad(&format!("song id:{}, delay:{}, pos:{}", res_id, delay, pos))
-            }
-            Self::Video1 { offset, x, y } => {
+            Command::UpdateMemList { res_id } => f.pad(&format!("load id:{}", self.res(*res_id))),
+            Command::PlayMusic { res_id, delay, pos } => f.pad(&format!(
+                "song id:{}, delay:{}, pos:{}",
+                self.res(*res_id),
+                delay,
+                pos
+            )),
+            Command::Video1 { offset, x, y } => {
                 f.pad(&format!("video1: off={} x={} y={}", offset, x, y))
             }
-            Self::Video2 {
+            Command::Video2 {
                 cinematic: _,
                 offset,
                 x,
                 y,
                 zoom,
             } => f.pad(&format!(
-                "video2: off={} x={:?} y={:?} zoom:{:?}",
-                offset, x, y, zoom
+                "video2: off={} x={} y={} zoom:{}",
+                offset,
+                self.op(x),
+                self.op(y),
+                self.op(zoom)
             )),
         }
     }
 }
+
+impl fmt::Debug for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.display(None, 0), f)
+    }
+}
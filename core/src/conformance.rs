@@ -0,0 +1,613 @@
+// Conformance runs: the engine plays a fixed input script headlessly, the screen and the mixer
+// output of every frame are hashed and compared with a golden list. The list is recorded from
+// a known good build (or converted from the dumps of the reference implementation), so any
+// change of the rasterizer or the mixer shows up as the first frame that differs.
+
+use crate::{
+    debugger::RunMode, engine::Engine, reference::Ref, staticres::VM_VARIABLE_PAUSE_SLICES,
+    system::*,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    cell::RefCell,
+    fmt, fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+    str::FromStr,
+};
+
+pub const SCREEN_WIDTH: usize = 320;
+pub const SCREEN_HEIGHT: usize = 200;
+
+const GOLDEN_HEADER: &str = "# awbi golden";
+// Duration of one pause slice in milliseconds.
+const PAUSE_SLICE_MS: u32 = 20;
+
+// 64-bit FNV-1a, stable across platforms and Rust versions unlike the std hashers.
+fn fnv1a(hash: u64, data: &[u8]) -> u64 {
+    data.iter().fold(hash, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0000_0100_0000_01B3)
+    })
+}
+
+const FNV_OFFSET: u64 = 0xCBF2_9CE4_8422_2325;
+
+/// Input held from `frame` until the next event.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InputEvent {
+    pub frame: u64,
+    pub dir_mask: u8,
+    pub button: bool,
+    pub code: bool,
+}
+
+/// Per-frame player input, one event per line:
+///
+/// ```text
+/// # frame keys...
+/// 0
+/// 120 button
+/// 200 right up
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InputScript {
+    events: Vec<InputEvent>,
+}
+
+impl InputScript {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        fs::read_to_string(path)
+            .with_context(|| format!("Unable to read input script {:?}", path))?
+            .parse()
+            .with_context(|| format!("Bad input script {:?}", path))
+    }
+
+    /// Input of the frame, nothing is pressed before the first event.
+    pub fn input(&self, frame: u64) -> InputEvent {
+        self.events
+            .iter()
+            .rev()
+            .find(|event| event.frame <= frame)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn apply(&self, frame: u64, input: &mut PlayerInput) {
+        let event = self.input(frame);
+        input.dir_mask = event.dir_mask;
+        input.button = event.button;
+        input.code = event.code;
+    }
+}
+
+impl FromStr for InputScript {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut events: Vec<InputEvent> = Vec::new();
+
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut tokens = line.split_whitespace();
+            let mut event = InputEvent::default();
+            let frame = tokens.next().unwrap_or_default();
+            event.frame = frame
+                .parse()
+                .map_err(|_| anyhow!("Line {}: invalid frame '{}'", i + 1, frame))?;
+
+            for key in tokens {
+                match key {
+                    "left" => event.dir_mask |= DIR_LEFT,
+                    "right" => event.dir_mask |= DIR_RIGHT,
+                    "up" => event.dir_mask |= DIR_UP,
+                    "down" => event.dir_mask |= DIR_DOWN,
+                    "button" => event.button = true,
+                    "code" => event.code = true,
+                    _ => bail!("Line {}: unknown key '{}'", i + 1, key),
+                }
+            }
+
+            if matches!(events.last(), Some(last) if last.frame >= event.frame) {
+                bail!("Line {}: frames must increase", i + 1);
+            }
+            events.push(event);
+        }
+
+        Ok(Self { events })
+    }
+}
+
+/// Screen content: palette indices and the palette in 6-bit components.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Image {
+    pub palette: [u8; NUM_COLORS * BYTE_PER_PIXEL],
+    pub pixels: Vec<u8>,
+}
+
+impl Default for Image {
+    fn default() -> Self {
+        Self {
+            palette: [0; NUM_COLORS * BYTE_PER_PIXEL],
+            pixels: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+        }
+    }
+}
+
+impl Image {
+    pub fn hash(&self) -> u64 {
+        fnv1a(fnv1a(FNV_OFFSET, &self.palette), &self.pixels)
+    }
+
+    /// Binary PPM with 8-bit components.
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut ppm = format!("P6\n{} {}\n255\n", SCREEN_WIDTH, SCREEN_HEIGHT).into_bytes();
+        for pixel in &self.pixels {
+            let color = *pixel as usize * BYTE_PER_PIXEL;
+            ppm.extend(
+                self.palette[color..color + BYTE_PER_PIXEL]
+                    .iter()
+                    .map(|c| (c << 2) | (c >> 4)),
+            );
+        }
+        ppm
+    }
+
+    pub fn save_ppm<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        fs::write(path, self.to_ppm()).with_context(|| format!("Unable to write {:?}", path))
+    }
+}
+
+impl fmt::Debug for Image {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(&format!("Image({:016X})", self.hash()))
+    }
+}
+
+/*
+    Headless system keeping the displayed screen.
+*/
+#[derive(Default)]
+struct CaptureSystem {
+    input: PlayerInput,
+    screen: Rc<RefCell<Image>>,
+}
+
+impl System for CaptureSystem {
+    fn input(&self) -> &PlayerInput {
+        &self.input
+    }
+
+    fn input_mut(&mut self) -> &mut PlayerInput {
+        &mut self.input
+    }
+
+    fn init(&mut self, _title: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn destroy(&mut self) {}
+
+    fn set_palette(&mut self, s: u8, n: u8, buf: &[u8]) {
+        let start = s as usize * BYTE_PER_PIXEL;
+        let len = n as usize * BYTE_PER_PIXEL;
+        self.screen.borrow_mut().palette[start..start + len].copy_from_slice(&buf[..len]);
+    }
+
+    fn copy_rect(&mut self, x: u16, y: u16, w: u16, h: u16, buf: &[u8], pitch: u32) {
+        let mut screen = self.screen.borrow_mut();
+        let (x, y, w) = (x as usize, y as usize, w as usize);
+
+        // Two pixels per byte, the high nibble first.
+        for row in 0..h as usize {
+            let src = &buf[row * pitch as usize..];
+            let dst = (y + row) * SCREEN_WIDTH + x;
+            for col in 0..w {
+                let b = src[col / 2];
+                screen.pixels[dst + col] = if col % 2 == 0 { b >> 4 } else { b & 0x0F };
+            }
+        }
+    }
+
+    fn process_events(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn sleep(&self, _duration: u32) {}
+
+    fn get_timestamp(&self) -> u32 {
+        0
+    }
+
+    fn start_audio(&mut self, _callback: &AudioCallback) {}
+    fn stop_audio(&mut self) {}
+
+    fn get_output_sample_rate(&mut self) -> u32 {
+        22050 // sound sample rate
+    }
+
+    fn add_timer(&mut self, _delay: u32, _callback: &TimerCallback) -> TimerId {
+        TimerId::default()
+    }
+
+    fn remove_timer(&mut self, _timer_id: TimerId) {}
+
+    fn create_mutex(&mut self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn destroy_mutex(&mut self, _mutex: &[u8]) {}
+    fn lock_mutex(&mut self, _mutex: &[u8]) {}
+    fn unlock_mutex(&mut self, _mutex: &[u8]) {}
+
+    fn get_offscreen_framebuffer(&mut self) -> Vec<u8> {
+        vec![]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameHashes {
+    pub frame: u64,
+    /// Screen at the end of the frame.
+    pub video: u64,
+    /// Mixer output for the duration of the frame.
+    pub audio: u64,
+}
+
+impl fmt::Display for FrameHashes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(&format!(
+            "{} {:016X} {:016X}",
+            self.frame, self.video, self.audio
+        ))
+    }
+}
+
+impl FromStr for FrameHashes {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_whitespace().collect::<Vec<_>>()[..] {
+            [frame, video, audio] => Ok(Self {
+                frame: frame.parse()?,
+                video: u64::from_str_radix(video, 16)?,
+                audio: u64::from_str_radix(audio, 16)?,
+            }),
+            _ => bail!("Expected 'frame video audio'"),
+        }
+    }
+}
+
+/// Hashes of consecutive frames starting with frame 0.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Golden {
+    pub frames: Vec<FrameHashes>,
+}
+
+impl Golden {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        fs::read_to_string(path)
+            .with_context(|| format!("Unable to read golden list {:?}", path))?
+            .parse()
+            .with_context(|| format!("Bad golden list {:?}", path))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        fs::write(path, self.to_string())
+            .with_context(|| format!("Unable to write golden list {:?}", path))
+    }
+}
+
+impl fmt::Display for Golden {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", GOLDEN_HEADER)?;
+        writeln!(f, "# frame video audio")?;
+        for frame in &self.frames {
+            writeln!(f, "{}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Golden {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut lines = s.lines();
+        if lines.next() != Some(GOLDEN_HEADER) {
+            bail!("Missing '{}' header", GOLDEN_HEADER);
+        }
+
+        let mut frames: Vec<FrameHashes> = Vec::new();
+        for (i, line) in lines.enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let hashes: FrameHashes = line.parse().with_context(|| format!("Line {}", i + 2))?;
+            if hashes.frame != frames.len() as u64 {
+                bail!("Line {}: expected frame {}", i + 2, frames.len());
+            }
+            frames.push(hashes);
+        }
+
+        Ok(Self { frames })
+    }
+}
+
+/// First frame differing from the golden list.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    pub expected: FrameHashes,
+    pub actual: FrameHashes,
+    /// Screens saved for the frame.
+    pub images: Vec<PathBuf>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let what = match (
+            self.expected.video != self.actual.video,
+            self.expected.audio != self.actual.audio,
+        ) {
+            (true, true) => "video and audio",
+            (true, false) => "video",
+            _ => "audio",
+        };
+        writeln!(f, "Frame {} differs in {}", self.actual.frame, what)?;
+        writeln!(f, "  expected {}", self.expected)?;
+        writeln!(f, "  actual   {}", self.actual)?;
+        for image in &self.images {
+            writeln!(f, "  saved {:?}", image)?;
+        }
+        Ok(())
+    }
+}
+
+// Name of the screen of a frame in the reference dumps directory.
+fn dump_name(frame: u64) -> String {
+    format!("frame-{:06}.ppm", frame)
+}
+
+/// Engine running headlessly on scripted input.
+pub struct Harness {
+    engine: Engine,
+    sys: SystemRef,
+    screen: Rc<RefCell<Image>>,
+    frame: u64,
+}
+
+impl Harness {
+    pub fn new(data_dir: &str) -> Result<Self> {
+        let screen = Rc::new(RefCell::new(Image::default()));
+        let sys: SystemRef = Ref::new(Box::new(CaptureSystem {
+            screen: screen.clone(),
+            ..Default::default()
+        }));
+
+        let mut engine = Engine::new(sys.clone(), data_dir, data_dir);
+        engine.set_crash_dump_dir(None);
+        engine.init()?;
+
+        Ok(Self {
+            engine,
+            sys,
+            screen,
+            frame: 0,
+        })
+    }
+
+    pub fn engine(&self) -> &Engine {
+        &self.engine
+    }
+
+    pub fn engine_mut(&mut self) -> &mut Engine {
+        &mut self.engine
+    }
+
+    pub fn screen(&self) -> Image {
+        self.screen.borrow().clone()
+    }
+
+    /// Run one frame with the input of the script and hash the result.
+    pub fn run_frame(&mut self, script: &InputScript) -> Result<FrameHashes> {
+        script.apply(self.frame, self.sys.get_mut().input_mut());
+
+        if self.engine.resume(RunMode::NextFrame)?.is_none() {
+            bail!("The game quit at frame {}", self.frame);
+        }
+
+        // Mix as much sound as the frame lasts.
+        let slices = self.engine.vm().variables()[VM_VARIABLE_PAUSE_SLICES].max(1) as u32;
+        let rate = self.sys.get_mut().get_output_sample_rate();
+        let audio = self
+            .engine
+            .vm_mut()
+            .mix_audio((rate * slices * PAUSE_SLICE_MS / 1000) as usize);
+
+        let hashes = FrameHashes {
+            frame: self.frame,
+            video: self.screen.borrow().hash(),
+            audio: fnv1a(FNV_OFFSET, &audio),
+        };
+        self.frame += 1;
+
+        Ok(hashes)
+    }
+
+    /// Hashes of the first `frames` frames. The screens are saved into `dumps_dir` if given.
+    pub fn record(
+        &mut self,
+        script: &InputScript,
+        frames: u64,
+        dumps_dir: Option<&Path>,
+    ) -> Result<Golden> {
+        let mut golden = Golden::default();
+
+        for _ in 0..frames {
+            let hashes = self.run_frame(script)?;
+            if let Some(dir) = dumps_dir {
+                self.screen
+                    .borrow()
+                    .save_ppm(dir.join(dump_name(hashes.frame)))?;
+            }
+            golden.frames.push(hashes);
+        }
+
+        Ok(golden)
+    }
+
+    /// Run the frames of the golden list and stop at the first difference. The actual screen
+    /// is saved into `out_dir` next to the expected one taken from `dumps_dir`.
+    pub fn check(
+        &mut self,
+        script: &InputScript,
+        golden: &Golden,
+        out_dir: &Path,
+        dumps_dir: Option<&Path>,
+    ) -> Result<Option<Mismatch>> {
+        for expected in &golden.frames {
+            let actual = self.run_frame(script)?;
+            if actual == *expected {
+                continue;
+            }
+
+            let mut images = Vec::new();
+            if actual.video != expected.video {
+                let path = out_dir.join(format!("actual-{:06}.ppm", actual.frame));
+                self.screen.borrow().save_ppm(&path)?;
+                images.push(path);
+
+                let reference = dumps_dir.map(|dir| dir.join(dump_name(actual.frame)));
+                if let Some(reference) = reference.filter(|path| path.exists()) {
+                    let path = out_dir.join(format!("expected-{:06}.ppm", actual.frame));
+                    fs::copy(&reference, &path)
+                        .with_context(|| format!("Unable to copy {:?}", reference))?;
+                    images.push(path);
+                }
+            }
+
+            return Ok(Some(Mismatch {
+                expected: *expected,
+                actual,
+                images,
+            }));
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{subtitles::Subtitles, util::fixture_dir};
+
+    #[test]
+    fn test_conformance() -> Result<()> {
+        let script: InputScript = "# frame keys\n0\n10 button\n20 right up\n".parse()?;
+        assert_eq!(script.input(5), InputEvent::default());
+        assert!(script.input(15).button);
+        assert_eq!(script.input(100).dir_mask, DIR_RIGHT | DIR_UP);
+        assert!("10\n5".parse::<InputScript>().is_err());
+        assert!("0 jump".parse::<InputScript>().is_err());
+
+        let screen = Rc::new(RefCell::new(Image::default()));
+        let mut sys = CaptureSystem {
+            screen: screen.clone(),
+            ..Default::default()
+        };
+        let blank = screen.borrow().hash();
+        sys.set_palette(1, 1, &[0x3F, 0x00, 0x20]);
+        sys.copy_rect(0, 0, 4, 1, &[0x01, 0x10], 160);
+        assert_eq!(&screen.borrow().pixels[..4], &[0, 1, 1, 0]);
+        assert_ne!(screen.borrow().hash(), blank);
+
+        let ppm = screen.borrow().to_ppm();
+        assert!(ppm.starts_with(b"P6\n320 200\n255\n"));
+        assert_eq!(
+            &ppm[ppm.len() - SCREEN_WIDTH * SCREEN_HEIGHT * 3 + 3..][..3],
+            &[0xFF, 0x00, 0x82]
+        );
+
+        let golden = Golden {
+            frames: vec![
+                FrameHashes {
+                    frame: 0,
+                    video: blank,
+                    audio: 1,
+                },
+                FrameHashes {
+                    frame: 1,
+                    video: 2,
+                    audio: 3,
+                },
+            ],
+        };
+        assert_eq!(golden.to_string().parse::<Golden>()?, golden);
+        assert!(format!("{}\n1 0 0", GOLDEN_HEADER)
+            .parse::<Golden>()
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fixture_run() -> Result<()> {
+        let data_dir = fixture_dir()?;
+        let script = InputScript::default();
+
+        let golden = Harness::new(data_dir.to_str().unwrap())?.record(&script, 30, None)?;
+        assert!(golden
+            .frames
+            .iter()
+            .any(|f| f.video != golden.frames[0].video));
+        assert!(golden
+            .frames
+            .iter()
+            .any(|f| f.audio != golden.frames[0].audio));
+
+        // The same input replays the same frames.
+        let mut harness = Harness::new(data_dir.to_str().unwrap())?;
+        assert!(harness.check(&script, &golden, &data_dir, None)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_subtitle_overlay() -> Result<()> {
+        let data_dir = fixture_dir()?;
+        let data_dir = data_dir.to_str().unwrap();
+        let script = InputScript::default();
+        let plain = Harness::new(data_dir)?.record(&script, 8, None)?;
+
+        let mut harness = Harness::new(data_dir)?;
+        let subtitles = Subtitles::from_toml(
+            "[[captions]]\npart = \"0x3E80\"\nframe = 2\nframes = 3\ntext = \"Peanut 3000\"",
+        )?;
+        harness.engine_mut().vm_mut().set_subtitles(Some(subtitles));
+        let captioned = harness.record(&script, 8, None)?;
+
+        // Only the frames with the caption change, the game pages stay untouched.
+        let changed: Vec<bool> = plain
+            .frames
+            .iter()
+            .zip(&captioned.frames)
+            .map(|(plain, captioned)| plain.video != captioned.video)
+            .collect();
+        assert_eq!(
+            changed,
+            [false, false, true, true, true, false, false, false]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+// Bytecode coverage: how many times every command was executed during a play session. The
+// data of several sessions can be merged and rendered next to the part disassembly.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::{collections::BTreeMap, fmt, fmt::Write as _, fs, path::Path, str::FromStr};
+
+const HEADER: &str = "# awbi coverage";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Coverage {
+    // (part id, command address) -> number of executions
+    hits: BTreeMap<(u16, u16), u64>,
+}
+
+fn parse_hex(s: &str) -> Result<u16> {
+    u16::from_str_radix(s, 16).map_err(|_| anyhow!("Invalid hex number '{}'", s))
+}
+
+fn parse_entry(line: &str) -> Result<((u16, u16), u64)> {
+    match line.split_whitespace().collect::<Vec<_>>()[..] {
+        [part_id, addr, count] => Ok(((parse_hex(part_id)?, parse_hex(addr)?), count.parse()?)),
+        _ => bail!("Expected 'part addr count'"),
+    }
+}
+
+fn percent(covered: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        covered as f64 * 100.0 / total as f64
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+impl Coverage {
+    pub fn hit(&mut self, part_id: u16, addr: u16) {
+        *self.hits.entry((part_id, addr)).or_default() += 1;
+    }
+
+    /// Number of executions of the command at `addr` of the part.
+    pub fn hits(&self, part_id: u16, addr: u16) -> u64 {
+        self.hits.get(&(part_id, addr)).copied().unwrap_or_default()
+    }
+
+    /// Parts with at least one executed command.
+    pub fn parts(&self) -> Vec<u16> {
+        let mut parts: Vec<_> = self.hits.keys().map(|(part_id, _)| *part_id).collect();
+        parts.dedup();
+        parts
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hits.is_empty()
+    }
+
+    /// Add the counts of another session.
+    pub fn merge(&mut self, other: &Coverage) {
+        for (key, count) in &other.hits {
+            *self.hits.entry(*key).or_default() += count;
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        fs::read_to_string(path)
+            .with_context(|| format!("Unable to read coverage {:?}", path))?
+            .parse()
+            .with_context(|| format!("Bad coverage {:?}", path))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        fs::write(path, self.to_string())
+            .with_context(|| format!("Unable to write coverage {:?}", path))
+    }
+
+    /// Disassembly of a part with the execution count in front of every command,
+    /// `#####` marks the commands that never ran.
+    pub fn annotate(&self, part_id: u16, listing: &[(u16, String)]) -> String {
+        let covered = listing
+            .iter()
+            .filter(|(addr, _)| self.hits(part_id, *addr) > 0)
+            .count();
+
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# part 0x{:04X}: {}/{} commands executed ({:.1}%)",
+            part_id,
+            covered,
+            listing.len(),
+            percent(covered, listing.len())
+        );
+
+        for (addr, cmd) in listing {
+            let count = match self.hits(part_id, *addr) {
+                0 => "#####".to_string(),
+                n => n.to_string(),
+            };
+            let _ = writeln!(out, "{:>10}: {:04X}: {}", count, addr, cmd);
+        }
+
+        out
+    }
+
+    /// Standalone HTML page with the annotated disassembly of every part.
+    pub fn html(&self, parts: &[(u16, Vec<(u16, String)>)]) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>awbi coverage</title>\n\
+             <style>\n\
+             body {{ font-family: monospace; }}\n\
+             td {{ padding: 0 8px; white-space: pre; }}\n\
+             td.count {{ text-align: right; }}\n\
+             tr.hit {{ background: #dfd; }}\n\
+             tr.miss {{ background: #fdd; }}\n\
+             </style>\n</head>\n<body>\n<h1>Bytecode coverage</h1>"
+        );
+
+        let _ = writeln!(out, "<table>\n<tr><th>Part</th><th>Executed</th></tr>");
+        for (part_id, listing) in parts {
+            let covered = listing
+                .iter()
+                .filter(|(addr, _)| self.hits(*part_id, *addr) > 0)
+                .count();
+            let _ = writeln!(
+                out,
+                "<tr><td><a href=\"#part-{0:04X}\">0x{0:04X}</a></td><td>{1}/{2} ({3:.1}%)</td></tr>",
+                part_id,
+                covered,
+                listing.len(),
+                percent(covered, listing.len())
+            );
+        }
+        let _ = writeln!(out, "</table>");
+
+        for (part_id, listing) in parts {
+            let _ = writeln!(
+                out,
+                "<h2 id=\"part-{0:04X}\">Part 0x{0:04X}</h2>\n<table>",
+                part_id
+            );
+            for (addr, cmd) in listing {
+                let count = self.hits(*part_id, *addr);
+                let class = if count > 0 { "hit" } else { "miss" };
+                let _ = writeln!(
+                    out,
+                    "<tr class=\"{}\"><td class=\"count\">{}</td><td>{:04X}</td><td>{}</td></tr>",
+                    class,
+                    count,
+                    addr,
+                    escape_html(cmd)
+                );
+            }
+            let _ = writeln!(out, "</table>");
+        }
+
+        let _ = writeln!(out, "</body>\n</html>");
+
+        out
+    }
+}
+
+impl fmt::Display for Coverage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        writeln!(f, "# part addr count")?;
+        for ((part_id, addr), count) in &self.hits {
+            writeln!(f, "{:04X} {:04X} {}", part_id, addr, count)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Coverage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut coverage = Coverage::default();
+
+        let mut lines = s.lines();
+        if lines.next() != Some(HEADER) {
+            bail!("Missing '{}' header", HEADER);
+        }
+
+        for (i, line) in lines.enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, count) = parse_entry(line).with_context(|| format!("Line {}", i + 2))?;
+            *coverage.hits.entry(key).or_default() += count;
+        }
+
+        Ok(coverage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coverage() -> Result<()> {
+        let mut coverage = Coverage::default();
+        coverage.hit(0x3E80, 0x00);
+        coverage.hit(0x3E80, 0x00);
+        coverage.hit(0x3E80, 0x04);
+        coverage.hit(0x3E81, 0x10);
+
+        let loaded: Coverage = coverage.to_string().parse()?;
+        assert_eq!(loaded, coverage);
+        assert_eq!(loaded.parts(), vec![0x3E80, 0x3E81]);
+
+        let mut merged = loaded.clone();
+        merged.merge(&coverage);
+        assert_eq!(merged.hits(0x3E80, 0x00), 4);
+        assert_eq!(merged.hits(0x3E80, 0x08), 0);
+
+        let listing = vec![
+            (0x00, "mov [0x10], 5".to_string()),
+            (0x04, "jmp 0x0000".to_string()),
+            (0x08, "ret".to_string()),
+        ];
+        let text = coverage.annotate(0x3E80, &listing);
+        assert!(text.starts_with("# part 0x3E80: 2/3 commands executed (66.7%)"));
+        assert!(text.contains("         2: 0000: mov [0x10], 5"));
+        assert!(text.contains("     #####: 0008: ret"));
+
+        let html = coverage.html(&[(0x3E80, listing)]);
+        assert!(html.contains("<tr class=\"miss\"><td class=\"count\">0</td><td>0008</td>"));
+
+        assert!("not coverage".parse::<Coverage>().is_err());
+
+        Ok(())
+    }
+}
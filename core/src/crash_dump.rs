@@ -0,0 +1,269 @@
+// Post-mortem state of the virtual machine written when the bytecode faults. The text format
+// is meant to be attached to bug reports and loaded back into the debugger, the savestate at
+// the end restores the whole engine.
+
+use crate::vm_context::ThreadData;
+use anyhow::{anyhow, bail, Context, Result};
+use std::{fmt::Write as _, fs, path::Path, str::FromStr};
+
+const HEADER: &str = "# awbi crash dump";
+const BYTES_PER_LINE: usize = 32;
+
+#[derive(Clone, Debug, Default)]
+pub struct CrashDump {
+    pub fault: String,
+    pub part_id: u16,
+    pub thread_id: usize,
+    pub addr: u16,
+    /// Commands around `addr`.
+    pub disassembly: Vec<(u16, String)>,
+    /// Return addresses, the innermost call last.
+    pub call_stack: Vec<u16>,
+    pub variables: Vec<i16>,
+    pub threads: Vec<ThreadData>,
+    /// Savestate in the same format as the save slot files.
+    pub savestate: Vec<u8>,
+}
+
+fn parse_hex(s: &str) -> Result<u16> {
+    let hex = s.trim().trim_start_matches("0x");
+    u16::from_str_radix(hex, 16).map_err(|_| anyhow!("Invalid hex number '{}'", s))
+}
+
+fn parse_bool(s: &str) -> Result<bool> {
+    match s {
+        "1" => Ok(true),
+        "0" => Ok(false),
+        _ => bail!("Invalid flag '{}'", s),
+    }
+}
+
+impl CrashDump {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        fs::read_to_string(path)
+            .with_context(|| format!("Unable to read crash dump {:?}", path))?
+            .parse()
+            .with_context(|| format!("Bad crash dump {:?}", path))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        fs::write(path, self.to_string())
+            .with_context(|| format!("Unable to write crash dump {:?}", path))
+    }
+}
+
+impl std::fmt::Display for CrashDump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        writeln!(f, "fault: {}", self.fault)?;
+        writeln!(f, "part: 0x{:04X}", self.part_id)?;
+        writeln!(f, "thread: {}", self.thread_id)?;
+        writeln!(f, "addr: 0x{:04X}", self.addr)?;
+
+        writeln!(f, "\n[disassembly]")?;
+        for (addr, cmd) in &self.disassembly {
+            let marker = if *addr == self.addr { '>' } else { ' ' };
+            writeln!(f, "{} {:04X}: {}", marker, addr, cmd)?;
+        }
+
+        writeln!(f, "\n[call_stack]")?;
+        for addr in self.call_stack.iter().rev() {
+            writeln!(f, "0x{:04X}", addr)?;
+        }
+
+        writeln!(f, "\n[variables]")?;
+        for (i, chunk) in self.variables.chunks(16).enumerate() {
+            let values: Vec<_> = chunk.iter().map(|v| format!("{:6}", v)).collect();
+            writeln!(f, "0x{:02X}:{}", i * 16, values.join(""))?;
+        }
+
+        writeln!(f, "\n[threads]")?;
+        writeln!(f, "# id: pc requested_pc active requested_active")?;
+        for (i, td) in self.threads.iter().enumerate() {
+            writeln!(
+                f,
+                "{:2}: {:04X} {:04X} {} {}",
+                i,
+                td.pc_offset,
+                td.requested_pc_offset,
+                td.cur_state_active as u8,
+                td.requested_state_active as u8
+            )?;
+        }
+
+        writeln!(f, "\n[savestate]")?;
+        for chunk in self.savestate.chunks(BYTES_PER_LINE) {
+            let mut line = String::with_capacity(BYTES_PER_LINE * 2);
+            for b in chunk {
+                write!(line, "{:02X}", b)?;
+            }
+            writeln!(f, "{}", line)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for CrashDump {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut dump = CrashDump::default();
+        let mut section = "";
+
+        let mut lines = s.lines();
+        if lines.next() != Some(HEADER) {
+            bail!("Missing '{}' header", HEADER);
+        }
+
+        for (i, line) in lines.enumerate() {
+            let line_num = i + 2;
+            let line = line.trim_end();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = name;
+                continue;
+            }
+
+            let res = match section {
+                "" => dump.parse_field(line),
+                "disassembly" => dump.parse_command(line),
+                "call_stack" => parse_hex(line).map(|addr| dump.call_stack.insert(0, addr)),
+                "variables" => dump.parse_variables(line),
+                "threads" => dump.parse_thread(line),
+                "savestate" => dump.parse_savestate(line),
+                _ => Err(anyhow!("Unknown section '{}'", section)),
+            };
+            res.with_context(|| format!("Line {}", line_num))?;
+        }
+
+        Ok(dump)
+    }
+}
+
+impl CrashDump {
+    fn parse_field(&mut self, line: &str) -> Result<()> {
+        let (name, val) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Expected 'name: value'"))?;
+        let val = val.trim();
+
+        match name {
+            "fault" => self.fault = val.into(),
+            "part" => self.part_id = parse_hex(val)?,
+            "thread" => self.thread_id = val.parse()?,
+            "addr" => self.addr = parse_hex(val)?,
+            _ => bail!("Unknown field '{}'", name),
+        }
+        Ok(())
+    }
+
+    fn parse_command(&mut self, line: &str) -> Result<()> {
+        // Commands start with the '>' marking the faulting one or a space.
+        let line = line
+            .strip_prefix(&['>', ' '][..])
+            .ok_or_else(|| anyhow!("Expected '>' or ' ' before the command"))?;
+        let (addr, cmd) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Expected 'addr: command'"))?;
+        self.disassembly.push((parse_hex(addr)?, cmd.trim().into()));
+        Ok(())
+    }
+
+    fn parse_variables(&mut self, line: &str) -> Result<()> {
+        let (first, values) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Expected 'first: values'"))?;
+        if parse_hex(first)? as usize != self.variables.len() {
+            bail!("Variables are not in order");
+        }
+        for val in values.split_whitespace() {
+            self.variables.push(val.parse()?);
+        }
+        Ok(())
+    }
+
+    fn parse_thread(&mut self, line: &str) -> Result<()> {
+        let (_, fields) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Expected 'id: fields'"))?;
+        match fields.split_whitespace().collect::<Vec<_>>()[..] {
+            [pc, requested_pc, active, requested_active] => {
+                self.threads.push(ThreadData {
+                    pc_offset: parse_hex(pc)?,
+                    requested_pc_offset: parse_hex(requested_pc)?,
+                    cur_state_active: parse_bool(active)?,
+                    requested_state_active: parse_bool(requested_active)?,
+                });
+                Ok(())
+            }
+            _ => bail!("Expected 4 thread fields"),
+        }
+    }
+
+    fn parse_savestate(&mut self, line: &str) -> Result<()> {
+        if !line.len().is_multiple_of(2) {
+            bail!("Odd number of hex digits");
+        }
+        for i in (0..line.len()).step_by(2) {
+            let b = line
+                .get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or_else(|| anyhow!("Invalid savestate byte"))?;
+            self.savestate.push(b);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crash_dump_round_trip() -> Result<()> {
+        let dump = CrashDump {
+            fault: "VM fault: Part 0x3E80: return stack underflow at 0x0010".into(),
+            part_id: 0x3E80,
+            thread_id: 3,
+            addr: 0x10,
+            disassembly: vec![(0x0C, "mov [0x10], 5".into()), (0x10, "ret".into())],
+            call_stack: vec![0x20, 0x44],
+            variables: (0..256).map(|i| i as i16 - 128).collect(),
+            threads: vec![
+                ThreadData::default(),
+                ThreadData {
+                    pc_offset: 0x10,
+                    requested_pc_offset: 0xFFFF,
+                    cur_state_active: false,
+                    requested_state_active: true,
+                },
+            ],
+            savestate: (0..100).map(|i| i as u8).collect(),
+        };
+
+        let text = dump.to_string();
+        let loaded: CrashDump = text.parse()?;
+
+        assert_eq!(loaded.fault, dump.fault);
+        assert_eq!(loaded.disassembly, dump.disassembly);
+        assert_eq!(loaded.call_stack, dump.call_stack);
+        assert_eq!(loaded.variables, dump.variables);
+        assert_eq!(loaded.threads.len(), 2);
+        assert_eq!(loaded.threads[1].pc_offset, 0x10);
+        assert!(!loaded.threads[1].cur_state_active);
+        assert_eq!(loaded.savestate, dump.savestate);
+
+        assert!("not a dump".parse::<CrashDump>().is_err());
+
+        let bad_marker = text.replace("> 0010", "é0010");
+        assert!(bad_marker.parse::<CrashDump>().is_err());
+
+        Ok(())
+    }
+}
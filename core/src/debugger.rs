@@ -0,0 +1,409 @@
+use anyhow::{anyhow, Error, Result};
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+
+// Number of logged writes kept until they are taken, the oldest ones go first.
+const MAX_LOGGED_WRITES: usize = 1024;
+
+/// Code location to stop at before the command is executed.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Breakpoint {
+    pub part_id: u16,
+    pub addr: u16,
+}
+
+impl fmt::Debug for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(&format!("{:04X}:{:04X}", self.part_id, self.addr))
+    }
+}
+
+/// How far the virtual machine runs before giving control back to the debugger.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum RunMode {
+    /// Run until a breakpoint is hit.
+    #[default]
+    Continue,
+    /// Execute a single command.
+    Step,
+    /// Run until the current thread executes `pauseThread` (or `killThread`).
+    NextPause,
+    /// Run until the end of the current frame.
+    NextFrame,
+}
+
+/// Condition on the value written to a watched variable.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum WatchCondition {
+    #[default]
+    Any,
+    Eq(i16),
+    Ne(i16),
+    Gt(i16),
+    Ge(i16),
+    Lt(i16),
+    Le(i16),
+}
+
+impl WatchCondition {
+    pub fn matches(&self, val: i16) -> bool {
+        match *self {
+            Self::Any => true,
+            Self::Eq(v) => val == v,
+            Self::Ne(v) => val != v,
+            Self::Gt(v) => val > v,
+            Self::Ge(v) => val >= v,
+            Self::Lt(v) => val < v,
+            Self::Le(v) => val <= v,
+        }
+    }
+}
+
+/// Parse conditions like `> 100`, `==0x14` or an empty string for any value.
+impl FromStr for WatchCondition {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+
+        if s.is_empty() {
+            return Ok(Self::Any);
+        }
+
+        let op_len = s.find(|c| !"<>=!".contains(c)).unwrap_or(s.len());
+        let (op, val) = s.split_at(op_len);
+        let val = val.trim();
+        let val = if let Some(hex) = val.strip_prefix("0x") {
+            u16::from_str_radix(hex, 16).map(|v| v as i16)
+        } else {
+            val.parse::<i16>()
+        }
+        .map_err(|_| anyhow!("Invalid watch value '{}'", val))?;
+
+        let res = match op {
+            "==" | "=" => Self::Eq(val),
+            "!=" => Self::Ne(val),
+            ">" => Self::Gt(val),
+            ">=" => Self::Ge(val),
+            "<" => Self::Lt(val),
+            "<=" => Self::Le(val),
+            _ => return Err(anyhow!("Invalid watch condition '{}'", op)),
+        };
+
+        Ok(res)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WatchAction {
+    /// Stop the virtual machine.
+    Stop,
+    /// Record the write and continue.
+    Log,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Watchpoint {
+    pub var_id: u8,
+    pub condition: WatchCondition,
+    pub action: WatchAction,
+}
+
+/// Write of a watched variable.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct VarWrite {
+    pub part_id: u16,
+    pub thread_id: usize,
+    /// Address of the command that wrote the variable.
+    pub addr: u16,
+    pub var_id: u8,
+    pub old: i16,
+    pub new: i16,
+}
+
+impl fmt::Display for VarWrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "part 0x{:04X}, thread {}, 0x{:04X}: [0x{:02X}] {} -> {}",
+            self.part_id, self.thread_id, self.addr, self.var_id, self.old, self.new
+        )
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StopReason {
+    Breakpoint,
+    Step,
+    Pause,
+    FrameEnd,
+    Watchpoint { var_id: u8, old: i16, new: i16 },
+}
+
+/// Location the virtual machine stopped at.
+///
+/// For `Breakpoint` the address is the one of the command about to be executed,
+/// for `Step`, `Pause` and `Watchpoint` it is the one of the command that was just executed.
+/// `FrameEnd` reports the last executed thread.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DebugStop {
+    pub reason: StopReason,
+    pub part_id: u16,
+    pub thread_id: usize,
+    pub addr: u16,
+}
+
+impl DebugStop {
+    pub(crate) fn new(reason: StopReason, part_id: u16, thread_id: usize, addr: u16) -> Self {
+        Self {
+            reason,
+            part_id,
+            thread_id,
+            addr,
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct Debugger {
+    breakpoints: BTreeSet<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    write_log: VecDeque<VarWrite>,
+    mode: RunMode,
+}
+
+impl Debugger {
+    /// Returns `false` if the breakpoint was already set.
+    pub fn add_breakpoint(&mut self, part_id: u16, addr: u16) -> bool {
+        self.breakpoints.insert(Breakpoint { part_id, addr })
+    }
+
+    /// Returns `false` if there was no such breakpoint.
+    pub fn remove_breakpoint(&mut self, part_id: u16, addr: u16) -> bool {
+        self.breakpoints.remove(&Breakpoint { part_id, addr })
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.breakpoints.iter()
+    }
+
+    /// Replaces the watchpoint with the same variable and action.
+    pub fn add_watchpoint(&mut self, var_id: u8, condition: WatchCondition, action: WatchAction) {
+        self.remove_watchpoint(var_id, Some(action));
+        self.watchpoints.push(Watchpoint {
+            var_id,
+            condition,
+            action,
+        });
+    }
+
+    /// Remove watchpoints of the variable with the given action or all of them if `None`.
+    /// Returns `false` if nothing was removed.
+    pub fn remove_watchpoint(&mut self, var_id: u8, action: Option<WatchAction>) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints
+            .retain(|w| w.var_id != var_id || action.is_some_and(|a| a != w.action));
+        len != self.watchpoints.len()
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = &Watchpoint> {
+        self.watchpoints.iter()
+    }
+
+    pub fn has_watchpoints(&self) -> bool {
+        !self.watchpoints.is_empty()
+    }
+
+    /// Take writes recorded by `WatchAction::Log` watchpoints, at most the last
+    /// `MAX_LOGGED_WRITES`.
+    pub fn take_write_log(&mut self) -> Vec<VarWrite> {
+        std::mem::take(&mut self.write_log).into()
+    }
+
+    pub fn mode(&self) -> RunMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: RunMode) {
+        self.mode = mode;
+    }
+
+    pub(crate) fn is_breakpoint(&self, part_id: u16, addr: u16) -> bool {
+        !self.breakpoints.is_empty() && self.breakpoints.contains(&Breakpoint { part_id, addr })
+    }
+
+    /// Check whether execution should stop after a command was executed.
+    pub(crate) fn stop_after_step(&self, thread_yielded: bool) -> Option<StopReason> {
+        match self.mode {
+            RunMode::Step => Some(StopReason::Step),
+            RunMode::NextPause if thread_yielded => Some(StopReason::Pause),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn stop_at_frame_end(&self) -> bool {
+        self.mode == RunMode::NextFrame
+    }
+
+    /// Check watched variables after a command was executed. A variable counts as written
+    /// if it is the command destination (even if the value did not change) or if its value
+    /// changed as a side effect of the command.
+    pub(crate) fn check_writes(
+        &mut self,
+        loc: DebugStop,
+        dst_var: Option<u8>,
+        old_vars: &[i16],
+        new_vars: &[i16],
+    ) -> Option<DebugStop> {
+        let mut stop = None;
+
+        for w in &self.watchpoints {
+            let i = w.var_id as usize;
+            let (old, new) = (old_vars[i], new_vars[i]);
+
+            if (dst_var != Some(w.var_id) && old == new) || !w.condition.matches(new) {
+                continue;
+            }
+
+            match w.action {
+                WatchAction::Log => {
+                    if self.write_log.len() == MAX_LOGGED_WRITES {
+                        self.write_log.pop_front();
+                    }
+                    self.write_log.push_back(VarWrite {
+                        part_id: loc.part_id,
+                        thread_id: loc.thread_id,
+                        addr: loc.addr,
+                        var_id: w.var_id,
+                        old,
+                        new,
+                    });
+                }
+                WatchAction::Stop if stop.is_none() => {
+                    stop = Some(DebugStop {
+                        reason: StopReason::Watchpoint {
+                            var_id: w.var_id,
+                            old,
+                            new,
+                        },
+                        ..loc
+                    })
+                }
+                WatchAction::Stop => {}
+            }
+        }
+
+        stop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_breakpoints() {
+        let mut dbg = Debugger::default();
+
+        assert!(dbg.add_breakpoint(0x3E80, 0x10));
+        assert!(!dbg.add_breakpoint(0x3E80, 0x10));
+        assert!(dbg.add_breakpoint(0x3E81, 0x08));
+        assert!(dbg.is_breakpoint(0x3E80, 0x10));
+        assert!(!dbg.is_breakpoint(0x3E81, 0x10));
+
+        assert!(dbg.remove_breakpoint(0x3E80, 0x10));
+        assert!(!dbg.is_breakpoint(0x3E80, 0x10));
+        assert_eq!(dbg.breakpoints().count(), 1);
+    }
+
+    #[test]
+    fn test_run_modes() {
+        let mut dbg = Debugger::default();
+
+        assert_eq!(dbg.stop_after_step(true), None);
+
+        dbg.set_mode(RunMode::Step);
+        assert_eq!(dbg.stop_after_step(false), Some(StopReason::Step));
+
+        dbg.set_mode(RunMode::NextPause);
+        assert_eq!(dbg.stop_after_step(false), None);
+        assert_eq!(dbg.stop_after_step(true), Some(StopReason::Pause));
+
+        dbg.set_mode(RunMode::NextFrame);
+        assert!(dbg.stop_at_frame_end());
+    }
+
+    #[test]
+    fn test_watch_condition() -> Result<()> {
+        assert_eq!("".parse::<WatchCondition>()?, WatchCondition::Any);
+        assert_eq!("> 100".parse::<WatchCondition>()?, WatchCondition::Gt(100));
+        assert_eq!("<=-5".parse::<WatchCondition>()?, WatchCondition::Le(-5));
+        assert_eq!(
+            "== 0x14".parse::<WatchCondition>()?,
+            WatchCondition::Eq(0x14)
+        );
+        assert!("=> 1".parse::<WatchCondition>().is_err());
+        assert!("> abc".parse::<WatchCondition>().is_err());
+
+        assert!(WatchCondition::Gt(100).matches(101));
+        assert!(!WatchCondition::Gt(100).matches(100));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut dbg = Debugger::default();
+        let loc = DebugStop::new(StopReason::Step, 0x3E80, 3, 0x0120);
+        let old_vars = [0i16; 256];
+        let mut new_vars = [0i16; 256];
+
+        dbg.add_watchpoint(0xE4, WatchCondition::Gt(100), WatchAction::Stop);
+        dbg.add_watchpoint(0x10, WatchCondition::Any, WatchAction::Log);
+
+        // Same value written by the command destination is still a write
+        assert_eq!(
+            dbg.check_writes(loc, Some(0x10), &old_vars, &new_vars),
+            None
+        );
+        assert_eq!(dbg.take_write_log().len(), 1);
+
+        new_vars[0xE4] = 50;
+        assert_eq!(dbg.check_writes(loc, None, &old_vars, &new_vars), None);
+
+        new_vars[0xE4] = 150;
+        let stop = dbg.check_writes(loc, None, &old_vars, &new_vars).unwrap();
+        assert_eq!(stop.thread_id, 3);
+        assert_eq!(stop.addr, 0x0120);
+        assert_eq!(
+            stop.reason,
+            StopReason::Watchpoint {
+                var_id: 0xE4,
+                old: 0,
+                new: 150
+            }
+        );
+        assert!(dbg.take_write_log().is_empty());
+
+        assert!(dbg.remove_watchpoint(0xE4, None));
+        assert!(!dbg.remove_watchpoint(0xE4, None));
+
+        // Writes pile up to a limit if nobody takes them.
+        for value in 0..MAX_LOGGED_WRITES + 10 {
+            new_vars[0x10] = value as i16;
+            dbg.check_writes(loc, Some(0x10), &old_vars, &new_vars);
+        }
+        let writes = dbg.take_write_log();
+        assert_eq!(writes.len(), MAX_LOGGED_WRITES);
+        assert_eq!(writes[0].new, 10);
+    }
+}
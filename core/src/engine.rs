@@ -1,15 +1,24 @@
 use std::fmt;
 
+use crate::crash_dump::CrashDump;
+use crate::debugger::*;
+use crate::error::{AwbiError, VmFault};
 use crate::file::File;
-use crate::parts::*;
+use crate::font::Font;
+use crate::integrity::Release;
+use crate::logging::DBG_INFO;
+pub use crate::parts::{GAME_PART_FIRST, GAME_PART_LAST};
 use crate::reference::*;
 use crate::resource::*;
 use crate::serializer::*;
+use crate::strings::StringTable;
 use crate::system::*;
+use crate::validator::{Issue, Validator};
+use crate::vfs::{self, Vfs};
+use crate::xref::Xref;
 use crate::{storage::Storage, vm::*};
-use anyhow::{ensure, Result};
-
-trace::init_depth_var!();
+use anyhow::{ensure, Context, Result};
+use std::path::{Path, PathBuf};
 
 const MAX_SAVE_SLOTS: i8 = 100;
 const FORMAT_SIG: u32 = 1_096_242_006; // 'AWSV'
@@ -18,24 +27,42 @@ pub struct Engine {
     sys: SystemRef,
     vm: VirtualMachine,
     res: ResourceRef,
-    data_dir: String,
     save_dir: String,
     state_slot: u8,
+    crash_dump_dir: Option<PathBuf>,
+    last_crash_dump: Option<CrashDump>,
 }
 
 impl Engine {
+    /// `data_dir` is a directory or a zip archive with the game data. States are saved next to
+    /// the archive when `save_dir` is one.
     pub fn new(sys: SystemRef, data_dir: &str, save_dir: &str) -> Self {
-        let storage = Storage::new(data_dir);
+        Self::with_vfs(sys, vfs::open(data_dir), save_dir)
+    }
+
+    /// Engine reading the game data from `vfs`.
+    pub fn with_vfs(sys: SystemRef, vfs: Box<dyn Vfs>, save_dir: &str) -> Self {
+        let storage = Storage::new(vfs);
         let res = Ref::new(Box::new(Resource::new(storage)));
         let vm = VirtualMachine::new(res.clone(), sys.clone());
 
+        let save_dir = if vfs::is_zip(save_dir) {
+            Path::new(save_dir)
+                .parent()
+                .map(|dir| dir.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        } else {
+            save_dir.into()
+        };
+
         Self {
             sys,
             vm,
             res,
-            data_dir: data_dir.into(),
-            save_dir: save_dir.into(),
+            crash_dump_dir: Some(save_dir.clone().into()),
+            save_dir,
             state_slot: 0,
+            last_crash_dump: None,
         }
     }
 
@@ -43,8 +70,7 @@ impl Engine {
         self.sys.get().input().quit
     }
 
-    // #[trace]
-    pub fn init(&mut self) -> Result<()> {
+    pub fn init(&mut self) -> Result<(), AwbiError> {
         //Init system
         self.sys.get_mut().init("Out Of This World")?;
         self.res.get_mut().init()?;
@@ -68,32 +94,181 @@ impl Engine {
         Ok(())
     }
 
-    // #[trace]
-    pub fn run(&mut self) -> Result<()> {
+    pub fn run(&mut self) -> Result<(), AwbiError> {
+        self.resume(RunMode::Continue)?;
+
+        Ok(())
+    }
+
+    /// Run the game until the debugger stops the virtual machine according to `mode`.
+    /// Returns `None` if the game was quit.
+    pub fn resume(&mut self, mode: RunMode) -> Result<Option<DebugStop>, AwbiError> {
+        self.vm.debugger_mut().set_mode(mode);
+
         while !self.is_quit() {
+            match self.run_frame() {
+                Ok(Some(stop)) => return Ok(Some(stop)),
+                Ok(None) => {}
+                Err(err) => {
+                    if let Some(fault) = err.downcast_ref::<VmFault>() {
+                        self.write_crash_dump(*fault);
+                    }
+                    return Err(err.into());
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Directory crash dumps are written to on VM faults, `None` disables writing.
+    /// The save directory is used by default.
+    pub fn set_crash_dump_dir(&mut self, dir: Option<PathBuf>) {
+        self.crash_dump_dir = dir;
+    }
+
+    /// Bytes of game data kept in memory besides the resources of the current part, `None` keeps
+    /// every bank file and resource once read.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.res.get_mut().storage.set_memory_limit(limit);
+    }
+
+    /// Skip the protection screen of the retail data, to set before `init`. The bytecode is
+    /// left alone if it differs from the original one.
+    pub fn set_bypass_protection(&mut self, bypass: bool) {
+        self.res.get_mut().set_bypass_protection(bypass);
+    }
+
+    /// Translation of the strings drawn by the game, `None` for the original ones.
+    pub fn set_strings(&mut self, strings: Option<StringTable>) {
+        self.res.get_mut().set_strings(strings);
+    }
+
+    /// Font of the strings drawn by the game, the built-in one has the Latin-1 letters.
+    pub fn set_font(&mut self, font: Font) {
+        self.res.get_mut().set_font(font);
+    }
+
+    /// Unpack every resource now, in parallel, instead of when a part needs it.
+    pub fn preload(&mut self) -> Result<(), AwbiError> {
+        Ok(self.res.get_mut().storage.load_all()?)
+    }
+
+    /// Crash dump of the last VM fault.
+    pub fn last_crash_dump(&self) -> Option<&CrashDump> {
+        self.last_crash_dump.as_ref()
+    }
+
+    fn write_crash_dump(&mut self, fault: VmFault) {
+        let mut dump = self.vm.crash_dump(fault);
+
+        match self.save_state() {
+            Ok(state) => dump.savestate = state,
+            Err(err) => warning!("Unable to save state for the crash dump: {}", err),
+        }
+
+        if let Some(dir) = &self.crash_dump_dir {
+            let path = dir.join(format!("crash-{:04X}-{:04X}.txt", dump.part_id, dump.addr));
+            match dump.save(&path) {
+                Ok(()) => warning!("{}, crash dump written to {:?}", dump.fault, path),
+                Err(err) => warning!("{:#}", err),
+            }
+        }
+
+        self.last_crash_dump = Some(dump);
+    }
+
+    fn run_frame(&mut self) -> Result<Option<DebugStop>> {
+        // Frame preparation was already done if the debugger stopped in the middle of the frame.
+        if !self.vm.in_frame() {
             self.vm.check_thread_requests()?;
             self.vm.inp_update_player()?;
             self.process_input()?;
-            self.vm.host_frame()?;
         }
 
-        Ok(())
+        self.vm.host_frame()
     }
 
-    // #[trace]
-    fn process_input(&mut self) -> Result<()> {
-        let mut sys = self.sys.get_mut();
+    /// Release of the game data, known after `init`.
+    pub fn release(&self) -> Release {
+        self.res.get().variant().release
+    }
+
+    /// Game parts of the release, the demo has fewer.
+    pub fn parts(&self) -> Vec<u16> {
+        let res = self.res.get();
+        (GAME_PART_FIRST..=GAME_PART_LAST)
+            .filter(|part_id| res.variant().part(*part_id).is_some())
+            .collect()
+    }
 
-        if sys.input().load {
-            // self.load_game_state(self.state_slot)?; // TODO: uncomment
-            sys.input_mut().load = false;
-            todo!();
+    /// Disassemble the whole bytecode of a game part.
+    pub fn part_listing(&self, part_id: u16) -> Result<Vec<(u16, String)>, AwbiError> {
+        Ok(self.vm.part_program(part_id)?.listing(self.vm.symbols()))
+    }
+
+    /// Run the static checks over the bytecode of all game parts.
+    pub fn validate(&self) -> Result<Vec<Issue>, AwbiError> {
+        let res_types: Vec<_> = self
+            .res
+            .get()
+            .storage
+            .mem_list
+            .entries
+            .iter()
+            .map(|me| me.res_type)
+            .collect();
+
+        let mut validator = Validator::new(&res_types);
+        for part_id in self.parts() {
+            validator.check_program(&self.vm.part_program(part_id)?);
         }
-        if sys.input().save {
-            // self.save_game_state(self.state_slot, "quicksave"); // TODO: uncomment
-            sys.input_mut().save = false;
-            todo!();
+
+        Ok(validator.finish())
+    }
+
+    /// Build the cross-reference index of the bytecode of all game parts.
+    pub fn xref(&self) -> Result<Xref, AwbiError> {
+        let mut xref = Xref::default();
+        for part_id in self.parts() {
+            xref.add_program(&self.vm.part_program(part_id)?);
         }
+
+        Ok(xref)
+    }
+
+    pub fn vm(&self) -> &VirtualMachine {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut VirtualMachine {
+        &mut self.vm
+    }
+
+    fn process_input(&mut self) -> Result<()> {
+        let (load, save) = {
+            let mut sys = self.sys.get_mut();
+            let input = sys.input_mut();
+            (
+                std::mem::take(&mut input.load),
+                std::mem::take(&mut input.save),
+            )
+        };
+
+        // A broken savestate should not stop the game.
+        if load {
+            if let Err(err) = self.load_game_state(self.state_slot) {
+                warning!("{:#}", err);
+            }
+        }
+        if save {
+            if let Err(err) = self.save_game_state(self.state_slot, "quicksave") {
+                warning!("{:#}", err);
+            }
+        }
+
+        let mut sys = self.sys.get_mut();
+
         if sys.input().fast_mode {
             self.vm.toggle_fast_mode();
             sys.input_mut().fast_mode = false;
@@ -102,7 +277,7 @@ impl Engine {
             let slot = self.state_slot as i8 + sys.input().state_slot;
             if slot >= 0 && slot < MAX_SAVE_SLOTS {
                 self.state_slot = slot as u8;
-                // debug(DBG_INFO, "Current game state slot is %d", _stateSlot);
+                debug!(DBG_INFO, "Current game state slot is {}", self.state_slot);
             }
             sys.input_mut().state_slot = 0;
         }
@@ -113,33 +288,91 @@ impl Engine {
     fn save_game_state(&mut self, slot: u8, desc: &str) -> Result<()> {
         let state_file = format!("raw.s{:02}", slot);
 
-        let mut f = File::open(&state_file, &self.save_dir, false)?;
-        // warning("Unable to save state file '%s'", stateFile);
+        let f = File::create(&state_file, &self.save_dir)
+            .with_context(|| format!("Unable to save state file '{}'", state_file))?;
+        self.write_state(f, desc)?;
+
+        debug!(DBG_INFO, "Saved state to slot {}", slot);
+
+        Ok(())
+    }
 
+    fn load_game_state(&mut self, slot: u8) -> Result<()> {
+        let state_file = format!("raw.s{:02}", slot);
+
+        let f = File::open(&state_file, &self.save_dir, false)
+            .with_context(|| format!("Unable to open state file '{}'", state_file))?;
+        self.read_state(f)
+            .with_context(|| format!("Unable to load state file '{}'", state_file))?;
+
+        debug!(DBG_INFO, "Loaded state from slot {}", slot);
+
+        Ok(())
+    }
+
+    /// Serialize the engine state in the save slot file format.
+    pub fn save_state(&mut self) -> Result<Vec<u8>, AwbiError> {
+        let f = self.write_state(File::from_data(Vec::new()), "")?;
+
+        Ok(f.into_data().unwrap_or_default())
+    }
+
+    /// Restore the state returned by `save_state`, read from a save slot file or a crash dump.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), AwbiError> {
+        self.read_state(File::from_data(data.to_vec()))?;
+
+        Ok(())
+    }
+
+    /// Restore the savestate of a crash dump, the game continues from the faulting command of
+    /// its thread with the threads and the call stack of the dump.
+    pub fn load_crash_dump(&mut self, dump: &CrashDump) -> Result<(), AwbiError> {
+        let backup = self.save_state()?;
+        self.load_state(&dump.savestate)?;
+
+        if let Err(err) = self.vm.restore_crash(dump) {
+            self.load_state(&backup)?;
+            return Err(err.into());
+        }
+
+        Ok(())
+    }
+
+    fn write_state(&mut self, mut f: File, desc: &str) -> Result<File> {
         // header
         f.write_u32(FORMAT_SIG)?;
         f.write_u16(CUR_VER.0)?;
         f.write_u16(0)?;
-        f.write(&desc.as_bytes()[..32])?;
+        let mut hdrdesc = [0u8; 32];
+        let desc_len = usize::min(desc.len(), hdrdesc.len());
+        hdrdesc[..desc_len].copy_from_slice(&desc.as_bytes()[..desc_len]);
+        f.write(&hdrdesc)?;
 
         // contents
         let mut s = Serializer::new(f, Mode::Save, self.res.get().mem_buf.to_vec(), CUR_VER);
         self.vm.save_or_load(&mut s)?;
         self.res.get_mut().save_or_load(&mut s)?;
 
-        // debug(DBG_INFO, "Saved state to slot %d", _stateSlot);
-
-        Ok(())
+        Ok(s.into_stream())
     }
 
-    fn load_game_state(&mut self, slot: u8) -> Result<()> {
-        let state_file = format!("raw.s{:02}", slot);
+    // A state rejected halfway through must not leave the engine half restored.
+    fn read_state(&mut self, f: File) -> Result<()> {
+        let backup = self.save_state()?;
+
+        let res = self.restore_state(f);
+        if res.is_err() {
+            if let Err(err) = self.restore_state(File::from_data(backup)) {
+                warning!("Unable to restore the engine state: {:#}", err);
+            }
+        }
 
-        let mut f = File::open(&state_file, &self.save_dir, false)?;
-        // warning("Unable to open state file '%s'", stateFile);
+        res
+    }
 
+    fn restore_state(&mut self, mut f: File) -> Result<()> {
         let id = f.read_u32()?;
-        ensure!(id == FORMAT_SIG, "Bad savegame format");
+        ensure!(id == FORMAT_SIG, AwbiError::bad_data("Bad savegame format"));
 
         // header
         let ver = f.read_u16()?;
@@ -154,20 +387,14 @@ impl Engine {
         self.vm.save_or_load(&mut s)?;
         self.res.get_mut().save_or_load(&mut s)?;
 
-        // debug(DBG_INFO, "Loaded state from slot %d", _stateSlot);
-
-        Ok(())
-    }
-
-    fn data_dir(&self) -> &String {
-        &self.data_dir
+        // The part may have changed.
+        self.vm.load_program()
     }
 }
 
 impl fmt::Debug for Engine {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Engine")
-            .field("data_dir", &self.data_dir)
             .field("save_dir", &self.save_dir)
             .field("state_slot", &self.state_slot)
             // .field("sys", &self.sys.get())
@@ -180,11 +407,17 @@ impl fmt::Debug for Engine {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::util::data_dir;
+    use crate::util::fixture_dir;
+    use crate::validator::Severity;
+    use crate::vfs::MemVfs;
+
+    // Frames after which the mock quits the game.
+    const MOCK_FRAMES: u32 = 100;
 
     #[derive(Default)]
     struct SystemMock {
         input: PlayerInput,
+        frames: u32,
     }
 
     impl System for SystemMock {
@@ -203,6 +436,8 @@ mod tests {
         fn set_palette(&mut self, _s: u8, _n: u8, _buf: &[u8]) {}
         fn copy_rect(&mut self, _x: u16, _y: u16, _w: u16, _h: u16, _buf: &[u8], _pitch: u32) {}
         fn process_events(&mut self) -> Result<()> {
+            self.frames += 1;
+            self.input.quit = self.frames >= MOCK_FRAMES;
             Ok(())
         }
         fn sleep(&self, _duration: u32) {}
@@ -241,12 +476,180 @@ mod tests {
     #[test]
     // #[ignore]
     fn test_engine() -> Result<()> {
-        let data_dir = data_dir()?;
+        let data_dir = fixture_dir()?;
         let sys: Ref<Box<(dyn System)>> = Ref::new(Box::new(SystemMock::default()));
         let mut engine = Engine::new(sys, data_dir.to_str().unwrap(), data_dir.to_str().unwrap());
 
         engine.init()?;
         // println!("=== Engine State ===\n{:#?}=== Engine State ===", engine);
-        engine.run()
+        engine.run()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_debugger() -> Result<()> {
+        let data_dir = fixture_dir()?;
+        let sys: Ref<Box<dyn System>> = Ref::new(Box::new(SystemMock::default()));
+        let mut engine = Engine::new(sys, data_dir.to_str().unwrap(), data_dir.to_str().unwrap());
+        engine.init()?;
+
+        let stop_at = |reason, addr| Some(DebugStop::new(reason, GAME_PART_FIRST, 0, addr));
+
+        // `add [0x10], 4` of the fixture main loop.
+        engine
+            .vm_mut()
+            .debugger_mut()
+            .add_breakpoint(GAME_PART_FIRST, 0x002C);
+        assert_eq!(
+            engine.resume(RunMode::Continue)?,
+            stop_at(StopReason::Breakpoint, 0x002C)
+        );
+        assert_eq!(engine.vm().variables()[0x10], 0);
+        assert!(engine.vm().in_frame());
+
+        // The frame goes on from the command the debugger stopped at.
+        assert_eq!(
+            engine.resume(RunMode::Step)?,
+            stop_at(StopReason::Step, 0x002C)
+        );
+        assert_eq!(engine.vm().variables()[0x10], 4);
+        assert_eq!(
+            engine.resume(RunMode::Step)?,
+            stop_at(StopReason::Step, 0x0030)
+        );
+        assert_eq!(
+            engine.resume(RunMode::NextPause)?,
+            stop_at(StopReason::Pause, 0x0036)
+        );
+        assert!(engine.vm().in_frame());
+
+        let stop = engine.resume(RunMode::NextFrame)?.unwrap();
+        assert_eq!(stop.reason, StopReason::FrameEnd);
+        assert!(!engine.vm().in_frame());
+        assert_eq!(engine.vm().variables()[0x10], 4);
+
+        // The breakpoint is hit again in the next frame.
+        assert_eq!(
+            engine.resume(RunMode::Continue)?,
+            stop_at(StopReason::Breakpoint, 0x002C)
+        );
+        assert_eq!(engine.vm().variables()[0x10], 4);
+
+        engine.vm_mut().debugger_mut().clear_breakpoints();
+        engine.vm_mut().debugger_mut().add_watchpoint(
+            0x10,
+            WatchCondition::Eq(12),
+            WatchAction::Stop,
+        );
+        let watch = StopReason::Watchpoint {
+            var_id: 0x10,
+            old: 8,
+            new: 12,
+        };
+        assert_eq!(engine.resume(RunMode::Continue)?, stop_at(watch, 0x002C));
+
+        engine.vm_mut().debugger_mut().clear_watchpoints();
+        assert_eq!(engine.resume(RunMode::Continue)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_crash_dump() -> Result<()> {
+        let data_dir = fixture_dir()?;
+        let new_engine = || -> Result<Engine> {
+            let sys: Ref<Box<dyn System>> = Ref::new(Box::new(SystemMock::default()));
+            let mut engine =
+                Engine::new(sys, data_dir.to_str().unwrap(), data_dir.to_str().unwrap());
+            engine.set_crash_dump_dir(None);
+            engine.init()?;
+            Ok(engine)
+        };
+
+        // The watchdog stops the main thread in the subroutine drawing the string.
+        let mut engine = new_engine()?;
+        engine.vm_mut().watchdog_mut().set_budget(Some(12));
+        assert!(engine.resume(RunMode::Continue).is_err());
+        let dump: CrashDump = engine.last_crash_dump().unwrap().to_string().parse()?;
+        assert_eq!((dump.thread_id, dump.addr), (0, 0x003A));
+        assert_eq!(dump.call_stack, vec![0x002C]);
+
+        let mut engine = new_engine()?;
+        engine.load_crash_dump(&dump)?;
+        assert!(engine.vm().in_frame());
+        assert_eq!(engine.vm().threads(), &dump.threads[..]);
+
+        let stop_at = |addr| Some(DebugStop::new(StopReason::Step, GAME_PART_FIRST, 0, addr));
+        assert_eq!(engine.resume(RunMode::Step)?, stop_at(0x003A));
+        // Returns to the caller of the dump.
+        assert_eq!(engine.resume(RunMode::Step)?, stop_at(0x0040));
+        assert_eq!(engine.resume(RunMode::Step)?, stop_at(0x002C));
+
+        // A dump of another part is rejected.
+        let mut other = dump.clone();
+        other.part_id = GAME_PART_FIRST + 1;
+        assert!(engine.load_crash_dump(&other).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_data() {
+        let sys: Ref<Box<dyn System>> = Ref::new(Box::new(SystemMock::default()));
+        let mut engine = Engine::with_vfs(sys, Box::new(MemVfs::default()), "");
+
+        let err = engine.init().unwrap_err().to_string();
+        assert!(err.contains("'memlist.bin' is missing"), "{}", err);
+    }
+
+    #[test]
+    fn test_validate() -> Result<()> {
+        let data_dir = fixture_dir()?;
+        let sys: Ref<Box<dyn System>> = Ref::new(Box::new(SystemMock::default()));
+        let mut engine = Engine::new(sys, data_dir.to_str().unwrap(), data_dir.to_str().unwrap());
+
+        engine.init()?;
+        // The bytecode of the other parts is unpacked on demand and evicted right after.
+        engine.set_memory_limit(Some(0));
+
+        // The generated fixtures have no errors.
+        let errors: Vec<_> = engine
+            .validate()?
+            .into_iter()
+            .filter(|issue| issue.severity() == Severity::Error)
+            .collect();
+        assert!(errors.is_empty(), "{:#?}", errors);
+
+        // A copy with the types of the sound and the music swapped in the memlist reports both
+        // commands playing them.
+        let mut corrupted = MemVfs::default();
+        for entry in std::fs::read_dir(&data_dir)? {
+            let path = entry?.path();
+            corrupted.insert(
+                &path.file_name().unwrap().to_string_lossy(),
+                std::fs::read(&path)?,
+            );
+        }
+        let mut memlist = std::fs::read(data_dir.join("memlist.bin"))?;
+        memlist.swap(20 + 1, 2 * 20 + 1);
+        corrupted.insert("memlist.bin", memlist);
+
+        let sys: Ref<Box<dyn System>> = Ref::new(Box::new(SystemMock::default()));
+        let mut engine = Engine::with_vfs(sys, Box::new(corrupted), "");
+        engine.init()?;
+        let errors: Vec<_> = engine
+            .validate()?
+            .into_iter()
+            .filter(|issue| issue.severity() == Severity::Error)
+            .map(|issue| (issue.part_id, issue.addr, issue.to_string()))
+            .collect();
+        assert_eq!(errors.len(), 2, "{:#?}", errors);
+        assert_eq!((errors[0].0, errors[0].1), (GAME_PART_FIRST, 0x000E));
+        assert!(errors[0].2.contains("is not a music"), "{}", errors[0].2);
+        assert_eq!((errors[1].0, errors[1].1), (GAME_PART_FIRST, 0x0041));
+        assert!(errors[1].2.contains("is not a sound"), "{}", errors[1].2);
+
+        Ok(())
     }
 }
@@ -0,0 +1,170 @@
+// Errors surfaced to embedders. Internally the crate keeps using anyhow, the typed error is
+// raised at the failure site and recovered with a downcast at the `Engine` API boundary.
+
+use std::{error, fmt, io};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmFault {
+    /// Jump, call or thread start to an address that is not an instruction boundary.
+    BadAddress { part_id: u16, addr: u16 },
+    /// `Ret` without a matching `Call`.
+    StackUnderflow { part_id: u16, addr: u16 },
+    /// Thread id out of the `VM_NUM_THREADS` range.
+    BadThread {
+        part_id: u16,
+        addr: u16,
+        thread_id: u16,
+    },
+    /// Execution requested for a part whose bytecode is not loaded.
+    ProgramNotLoaded { part_id: u16 },
+    /// Thread exceeded the watchdog budget of commands per frame.
+    InfiniteLoop {
+        part_id: u16,
+        addr: u16,
+        thread_id: u16,
+    },
+}
+
+impl fmt::Display for VmFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadAddress { part_id, addr } => write!(
+                f,
+                "Part {:#06X}: bad bytecode address {:#06X}",
+                part_id, addr
+            ),
+            Self::StackUnderflow { part_id, addr } => write!(
+                f,
+                "Part {:#06X}: return stack underflow at {:#06X}",
+                part_id, addr
+            ),
+            Self::BadThread {
+                part_id,
+                addr,
+                thread_id,
+            } => write!(
+                f,
+                "Part {:#06X}: bad thread id {} at {:#06X}",
+                part_id, thread_id, addr
+            ),
+            Self::ProgramNotLoaded { part_id } => {
+                write!(f, "Part {:#06X}: bytecode is not loaded", part_id)
+            }
+            Self::InfiniteLoop {
+                part_id,
+                addr,
+                thread_id,
+            } => write!(
+                f,
+                "Part {:#06X}: thread {} doesn't yield, stopped at {:#06X}",
+                part_id, thread_id, addr
+            ),
+        }
+    }
+}
+
+impl error::Error for VmFault {}
+
+#[derive(Debug)]
+pub enum AwbiError {
+    /// Game data or savestate is corrupt or has an unexpected format.
+    BadData(String),
+    /// Feature that is not implemented by the engine.
+    Unsupported(String),
+    /// Bytecode executed something the virtual machine cannot do.
+    VmFault(VmFault),
+    Io(io::Error),
+}
+
+impl AwbiError {
+    pub fn bad_data<S: Into<String>>(msg: S) -> Self {
+        Self::BadData(msg.into())
+    }
+
+    pub fn unsupported<S: Into<String>>(msg: S) -> Self {
+        Self::Unsupported(msg.into())
+    }
+}
+
+impl fmt::Display for AwbiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadData(msg) => write!(f, "Bad data: {}", msg),
+            Self::Unsupported(msg) => write!(f, "Unsupported: {}", msg),
+            Self::VmFault(fault) => write!(f, "VM fault: {}", fault),
+            Self::Io(err) => write!(f, "I/O error: {}", err),
+        }
+    }
+}
+
+impl error::Error for AwbiError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<VmFault> for AwbiError {
+    fn from(fault: VmFault) -> Self {
+        Self::VmFault(fault)
+    }
+}
+
+impl From<io::Error> for AwbiError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<anyhow::Error> for AwbiError {
+    fn from(err: anyhow::Error) -> Self {
+        // Context added on the way up is kept for I/O and untyped errors only.
+        if let Some(io_err) = err.downcast_ref::<io::Error>() {
+            return Self::Io(io::Error::new(io_err.kind(), format!("{:#}", err)));
+        }
+
+        match err.downcast::<AwbiError>() {
+            Ok(err) => err,
+            Err(err) => match err.downcast::<VmFault>() {
+                Ok(fault) => Self::VmFault(fault),
+                Err(err) => Self::BadData(format!("{:#}", err)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Result};
+
+    #[test]
+    fn test_from_anyhow() -> Result<()> {
+        let err: anyhow::Error = AwbiError::unsupported("zip").into();
+        assert!(matches!(AwbiError::from(err), AwbiError::Unsupported(msg) if msg == "zip"));
+
+        let fault = VmFault::StackUnderflow {
+            part_id: 0x3E80,
+            addr: 0x10,
+        };
+        let err = anyhow::Error::from(fault).context("Thread 3");
+        assert!(matches!(AwbiError::from(err), AwbiError::VmFault(f) if f == fault));
+
+        let err =
+            anyhow::Error::from(io::Error::from(io::ErrorKind::NotFound)).context("memlist.bin");
+        match AwbiError::from(err) {
+            AwbiError::Io(err) => {
+                assert_eq!(err.kind(), io::ErrorKind::NotFound);
+                assert!(err.to_string().starts_with("memlist.bin"));
+            }
+            err => panic!("Unexpected error {:?}", err),
+        }
+
+        let err = AwbiError::from(anyhow!("CRC should be 0"));
+        assert!(matches!(err, AwbiError::BadData(_)));
+
+        Ok(())
+    }
+}
@@ -1,8 +1,14 @@
+use crate::vfs::{self, ZipVfs};
 use anyhow::{bail, Result};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::path::*;
 
-pub(crate) trait FileImpl: std::fmt::Debug + Read + Seek + Write {}
+pub(crate) trait FileImpl: std::fmt::Debug + Read + Seek + Write {
+    /// Content of in-memory files.
+    fn into_data(self: Box<Self>) -> Option<Vec<u8>> {
+        None
+    }
+}
 
 #[derive(Debug)]
 pub(crate) struct File {
@@ -10,18 +16,42 @@ pub(crate) struct File {
 }
 
 impl File {
+    /// Open `filename` in `directory`, or in the archive `directory` if `zipped`.
     pub fn open<P: AsRef<Path>>(filename: &str, directory: P, zipped: bool) -> Result<Self> {
-        let mut path = directory.as_ref().to_path_buf();
-        path.push(filename);
-
         let file_impl: Box<dyn FileImpl> = if zipped {
-            Box::new(ZipFile::open(path)?)
+            Box::new(ZipFile::open(directory, filename)?)
         } else {
+            let mut path = directory.as_ref().to_path_buf();
+            path.push(filename);
             Box::new(StdFile::open(&path)?)
         };
         Ok(Self { file_impl })
     }
 
+    /// Create or truncate a file for writing.
+    pub fn create<P: AsRef<Path>>(filename: &str, directory: P) -> Result<Self> {
+        let mut path = directory.as_ref().to_path_buf();
+        path.push(filename);
+
+        Ok(Self {
+            file_impl: Box::new(StdFile::create(&path)?),
+        })
+    }
+
+    /// In-memory file positioned at the start of `data`.
+    pub fn from_data(data: Vec<u8>) -> Self {
+        Self {
+            file_impl: Box::new(MemFile {
+                data: Cursor::new(data),
+            }),
+        }
+    }
+
+    /// Content of the file if it was created in memory.
+    pub fn into_data(self) -> Option<Vec<u8>> {
+        self.file_impl.into_data()
+    }
+
     pub fn seek(&mut self, off: u64) -> Result<()> {
         self.file_impl.seek(SeekFrom::Start(off))?;
         Ok(())
@@ -100,6 +130,12 @@ impl StdFile {
             file: std::fs::File::open(path.as_ref())?,
         })
     }
+
+    fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self {
+            file: std::fs::File::create(path.as_ref())?,
+        })
+    }
 }
 
 impl FileImpl for StdFile {}
@@ -125,38 +161,77 @@ impl Write for StdFile {
     }
 }
 
+#[derive(Debug)]
+struct MemFile {
+    data: Cursor<Vec<u8>>,
+}
+
+impl FileImpl for MemFile {
+    fn into_data(self: Box<Self>) -> Option<Vec<u8>> {
+        Some(self.data.into_inner())
+    }
+}
+
+impl Seek for MemFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.data.seek(pos)
+    }
+}
+
+impl Read for MemFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.data.read(buf)
+    }
+}
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.data.write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Zip archive entry, read into memory when opened.
 #[derive(Debug)]
 struct ZipFile {
-    file: std::fs::File,
+    data: Cursor<Vec<u8>>,
 }
 
 impl ZipFile {
-    fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+    fn open<P: AsRef<Path>>(path: P, name: &str) -> Result<Self> {
+        let data = vfs::read_file(&ZipVfs::new(path), name)?;
+
         Ok(Self {
-            file: std::fs::File::open(path.as_ref())?,
+            data: Cursor::new(data),
         })
     }
 }
 
+fn read_only() -> std::io::Error {
+    std::io::Error::new(ErrorKind::PermissionDenied, "Zip archives are read only")
+}
+
 impl FileImpl for ZipFile {}
 
 impl Seek for ZipFile {
-    fn seek(&mut self, _pos: SeekFrom) -> std::io::Result<u64> {
-        todo!();
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.data.seek(pos)
     }
 }
 
 impl Read for ZipFile {
-    fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
-        todo!();
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.data.read(buf)
     }
 }
 
 impl Write for ZipFile {
     fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
-        todo!();
+        Err(read_only())
     }
     fn flush(&mut self) -> std::io::Result<()> {
-        todo!();
+        Ok(())
     }
 }
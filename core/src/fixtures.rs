@@ -0,0 +1,246 @@
+// Synthetic game data: a `memlist.bin` and bank files with hand-assembled bytecode, a palette,
+// cinematic polygons, a sound and a music module. Nothing comes from the original game, so
+// loading, unpacking, the VM loop, drawing and audio can be tested without its files.
+
+use crate::{bank::pack, parts::*};
+use anyhow::{Context, Result};
+use std::{fs, path::Path};
+
+/// Resource ids of the fixture sound and music module.
+pub const FIXTURE_SOUND: u16 = 0x01;
+pub const FIXTURE_MUSIC: u16 = 0x02;
+
+// Last resource referenced by `MEM_LIST_PARTS`.
+const NUM_ENTRIES: usize = 0x80;
+const NUM_BANKS: u8 = 13;
+// Memlist entry type of the unused entries.
+const RES_TYPE_NONE: u8 = 0xFF;
+
+const RES_TYPE_SOUND: u8 = 0;
+const RES_TYPE_MUSIC: u8 = 1;
+const RES_TYPE_PALETTE: u8 = 3;
+const RES_TYPE_BYTECODE: u8 = 4;
+const RES_TYPE_POLY_CINEMATIC: u8 = 5;
+
+// Protection screen part: draws a quad, a polygon hierarchy following variable 0x10 and a
+// string every frame, thread 1 plays the sound every 20 frames.
+#[rustfmt::skip]
+pub(crate) const MAIN_CODE: [u8; 0x53] = [
+    0x00, 0xFF, 0x00, 0x01,             // 0000: mov [PAUSE_SLICES], 1
+    0x08, 0x01, 0x00, 0x41,             // 0004: setvec 1, 0x0041
+    0x19, 0x00, 0x01,                   // 0008: update_mem_list 0x01
+    0x19, 0x00, 0x02,                   // 000B: update_mem_list 0x02
+    0x1A, 0x00, 0x02, 0x00, 0x00, 0x00, // 000E: play_music 0x02, 0, 0
+    0x0B, 0x00, 0x00,                   // 0014: set_palette 0
+    0x00, 0x10, 0x00, 0x00,             // 0017: mov [0x10], 0
+    0x0D, 0x00,                         // 001B: select_page 0
+    0x0E, 0x00, 0x01,                   // 001D: fill_page 0, 1
+    0x80, 0x00, 0xA0, 0x64,             // 0020: video1 0x0000, 160, 100
+    0x58, 0x00, 0x06, 0x10, 0x50,       // 0024: video2 0x000C, [0x10], 80
+    0x04, 0x00, 0x3A,                   // 0029: call 0x003A
+    0x03, 0x10, 0x00, 0x04,             // 002C: add [0x10], 4
+    0x14, 0x10, 0x00, 0xFF,             // 0030: and [0x10], 0xFF
+    0x10, 0x00,                         // 0034: blit 0
+    0x06,                               // 0036: pause
+    0x07, 0x00, 0x1B,                   // 0037: jmp 0x001B
+    0x12, 0x00, 0x01, 0x01, 0x0A, 0x0F, // 003A: draw_string 0x001, 1, 10, 15
+    0x05,                               // 0040: ret
+    0x18, 0x00, 0x01, 0x0A, 0x3F, 0x00, // 0041: play_sound 0x01, 10, 0x3F, 0
+    0x00, 0x11, 0x00, 0x14,             // 0047: mov [0x11], 20
+    0x06,                               // 004B: pause
+    0x09, 0x11, 0x00, 0x4B,             // 004C: jnz [0x11], 0x004B
+    0x07, 0x00, 0x41,                   // 0050: jmp 0x0041
+];
+
+// The other parts wait forever.
+#[rustfmt::skip]
+const IDLE_CODE: [u8; 4] = [
+    0x06,                               // 0000: pause
+    0x07, 0x00, 0x00,                   // 0001: jmp 0x0000
+];
+
+#[rustfmt::skip]
+pub(crate) const POLYGONS: [u8; 26] = [
+    // 0000: quad 80x60 of color 5
+    0xC5, 0x50, 0x3C, 0x04,
+    0x50, 0x00, 0x50, 0x3C, 0x00, 0x3C, 0x00, 0x00,
+    // 000C: hierarchy of two quads, the first one blended with color 0x10
+    0x02, 0x28, 0x1E, 0x02,
+    0x80, 0x00, 0x00, 0x00, 0x10, 0x00,
+    0x00, 0x00, 0x30, 0x10,
+];
+
+// 32 palettes of 16 colors, 0x0RGB in 4 bits per component.
+fn palette() -> Vec<u8> {
+    (0..32u16)
+        .flat_map(|pal| {
+            (0..16u16).flat_map(move |i| {
+                let rgb = ((i + pal) & 0xF) << 8 | (i << 4) | (0xF - i);
+                rgb.to_be_bytes()
+            })
+        })
+        .collect()
+}
+
+// Header with the length and the loop length in words, then a square wave.
+fn sound() -> Vec<u8> {
+    let samples: Vec<u8> = (0..512)
+        .map(|i| if i & 16 == 0 { 0x40 } else { 0xC0 })
+        .collect();
+
+    let mut data = Vec::new();
+    data.extend_from_slice(&(samples.len() as u16 / 2).to_be_bytes());
+    data.extend_from_slice(&0u16.to_be_bytes());
+    data.extend_from_slice(&[0; 4]);
+    data.extend(samples);
+    data
+}
+
+// Delay, 15 instruments, the order table and one pattern playing the sound on channel 0.
+fn music() -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(&0x2000u16.to_be_bytes());
+    for i in 0..15 {
+        let (res_id, volume) = if i == 0 {
+            (FIXTURE_SOUND, 0x30)
+        } else {
+            (0, 0)
+        };
+        data.extend_from_slice(&res_id.to_be_bytes());
+        data.extend_from_slice(&(volume as u16).to_be_bytes());
+    }
+    data.extend_from_slice(&1u16.to_be_bytes()); // 0x3E: number of orders
+    data.extend_from_slice(&[0; 0x80]); // 0x40: order table
+
+    let mut pattern = vec![0; 1024];
+    pattern[..4].copy_from_slice(&[0x01, 0xAC, 0x10, 0x00]); // note 0x1AC, instrument 1
+    data.extend(pattern);
+    data
+}
+
+struct Entry {
+    res_type: u8,
+    data: Vec<u8>,
+}
+
+fn entries() -> Vec<Entry> {
+    let mut entries: Vec<_> = (0..NUM_ENTRIES)
+        .map(|_| Entry {
+            res_type: RES_TYPE_NONE,
+            data: Vec::new(),
+        })
+        .collect();
+
+    entries[FIXTURE_SOUND as usize] = Entry {
+        res_type: RES_TYPE_SOUND,
+        data: sound(),
+    };
+    entries[FIXTURE_MUSIC as usize] = Entry {
+        res_type: RES_TYPE_MUSIC,
+        data: music(),
+    };
+
+    for (i, part) in MEM_LIST_PARTS.iter().enumerate() {
+        let code: &[u8] = if i == 0 { &MAIN_CODE } else { &IDLE_CODE };
+
+        entries[part[MEMLIST_PART_PALETTE] as usize] = Entry {
+            res_type: RES_TYPE_PALETTE,
+            data: palette(),
+        };
+        entries[part[MEMLIST_PART_CODE] as usize] = Entry {
+            res_type: RES_TYPE_BYTECODE,
+            data: code.to_vec(),
+        };
+        for idx in [part[MEMLIST_PART_POLY_CINEMATIC], part[MEMLIST_PART_VIDEO2]].iter() {
+            if *idx as usize != MEMLIST_PART_NONE {
+                entries[*idx as usize] = Entry {
+                    res_type: RES_TYPE_POLY_CINEMATIC,
+                    data: POLYGONS.to_vec(),
+                };
+            }
+        }
+    }
+
+    entries
+}
+
+/// Write `memlist.bin` and the bank files into `dir`. Sounds and music go to the second bank,
+/// everything else to the first one. Resources are packed unless that makes them bigger.
+pub fn write_fixtures<P: AsRef<Path>>(dir: P) -> Result<()> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir).with_context(|| format!("Unable to create {:?}", dir))?;
+
+    let mut banks = vec![Vec::new(); NUM_BANKS as usize];
+    let mut memlist = Vec::new();
+
+    for entry in entries() {
+        let bank_id = match entry.res_type {
+            RES_TYPE_SOUND | RES_TYPE_MUSIC => 2,
+            _ => 1,
+        };
+
+        let packed = pack(&entry.data);
+        let stored = if packed.len() < entry.data.len() {
+            packed
+        } else {
+            entry.data.clone()
+        };
+
+        let bank = &mut banks[bank_id as usize - 1];
+        let bank_offset = bank.len() as u32;
+        bank.extend_from_slice(&stored);
+
+        memlist.push(0); // state
+        memlist.push(entry.res_type);
+        memlist.extend_from_slice(&[0; 4]); // buffer offset, unknown
+        memlist.push(0); // rank
+        memlist.push(bank_id);
+        memlist.extend_from_slice(&bank_offset.to_be_bytes());
+        memlist.extend_from_slice(&[0; 2]);
+        memlist.extend_from_slice(&(stored.len() as u16).to_be_bytes());
+        memlist.extend_from_slice(&[0; 2]);
+        memlist.extend_from_slice(&(entry.data.len() as u16).to_be_bytes());
+    }
+
+    // End of the list.
+    memlist.push(0xFF);
+    memlist.extend_from_slice(&[0; 19]);
+
+    let write = |name: &str, data: &[u8]| {
+        let path = dir.join(name);
+        fs::write(&path, data).with_context(|| format!("Unable to write {:?}", path))
+    };
+
+    write("memlist.bin", &memlist)?;
+    for (i, bank) in banks.iter().enumerate() {
+        write(&format!("bank{:02x}", i + 1), bank)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{memlist::ResType, resource::Resource, storage::Storage, util::fixture_dir, vfs};
+
+    #[test]
+    fn test_fixtures() -> Result<()> {
+        let data_dir = fixture_dir()?;
+        let mut res = Resource::new(Storage::new(vfs::open(&data_dir)));
+        res.init()?;
+
+        let entries = &res.storage.mem_list.entries;
+        assert_eq!(entries.len(), NUM_ENTRIES);
+        assert_eq!(entries[FIXTURE_SOUND as usize].res_type, ResType::Sound);
+        assert_eq!(entries[FIXTURE_MUSIC as usize].bank_id, 2);
+
+        // Unpacked data matches the generated one.
+        let palette_idx = MEM_LIST_PARTS[0][MEMLIST_PART_PALETTE] as usize;
+        assert!(entries[palette_idx].packed_size < entries[palette_idx].size);
+        assert_eq!(res.entry_data(palette_idx)?, &palette()[..]);
+        assert_eq!(res.entry_data(FIXTURE_MUSIC as usize)?, &music()[..]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,376 @@
+// 8x8 font of the strings drawn by the bytecode. The built-in font has the ASCII glyphs of the
+// original game and the Latin-1 letters, accented ones are composed from their base letter.
+// Fonts can also be loaded from BDF files, every glyph has to fit in the 8x8 cell.
+//
+// Characters without a glyph are drawn with the fallback glyph: a hollow box, or the
+// `DEFAULT_CHAR` of a BDF font.
+
+use crate::staticres::FONT;
+use anyhow::{anyhow, bail, Context, Result};
+use std::{collections::HashMap, fmt, fs, path::Path};
+
+pub const GLYPH_SIZE: usize = 8;
+
+/// Rows from the top, the high bit is the leftmost pixel.
+pub type Glyph = [u8; GLYPH_SIZE];
+
+pub const FALLBACK_GLYPH: Glyph = [0x7C, 0x44, 0x44, 0x44, 0x44, 0x44, 0x7C, 0x00];
+
+// Two rows above the lowercase letters, centered on the fifth column.
+const GRAVE: [u8; 2] = [0x20, 0x10];
+const ACUTE: [u8; 2] = [0x08, 0x10];
+const CIRCUMFLEX: [u8; 2] = [0x10, 0x28];
+const TILDE: [u8; 2] = [0x34, 0x58];
+const DIAERESIS: [u8; 2] = [0x28, 0x00];
+const RING: [u8; 2] = [0x10, 0x28];
+
+#[rustfmt::skip]
+const ACCENTED: [(char, char, [u8; 2]); 55] = [
+    ('À', 'A', GRAVE), ('Á', 'A', ACUTE), ('Â', 'A', CIRCUMFLEX), ('Ã', 'A', TILDE),
+    ('Ä', 'A', DIAERESIS), ('Å', 'A', RING),
+    ('È', 'E', GRAVE), ('É', 'E', ACUTE), ('Ê', 'E', CIRCUMFLEX), ('Ë', 'E', DIAERESIS),
+    ('Ì', 'I', GRAVE), ('Í', 'I', ACUTE), ('Î', 'I', CIRCUMFLEX), ('Ï', 'I', DIAERESIS),
+    ('Ñ', 'N', TILDE),
+    ('Ò', 'O', GRAVE), ('Ó', 'O', ACUTE), ('Ô', 'O', CIRCUMFLEX), ('Õ', 'O', TILDE),
+    ('Ö', 'O', DIAERESIS),
+    ('Ù', 'U', GRAVE), ('Ú', 'U', ACUTE), ('Û', 'U', CIRCUMFLEX), ('Ü', 'U', DIAERESIS),
+    ('Ý', 'Y', ACUTE),
+    ('à', 'a', GRAVE), ('á', 'a', ACUTE), ('â', 'a', CIRCUMFLEX), ('ã', 'a', TILDE),
+    ('ä', 'a', DIAERESIS), ('å', 'a', RING),
+    ('è', 'e', GRAVE), ('é', 'e', ACUTE), ('ê', 'e', CIRCUMFLEX), ('ë', 'e', DIAERESIS),
+    ('ì', 'i', GRAVE), ('í', 'i', ACUTE), ('î', 'i', CIRCUMFLEX), ('ï', 'i', DIAERESIS),
+    ('ñ', 'n', TILDE),
+    ('ò', 'o', GRAVE), ('ó', 'o', ACUTE), ('ô', 'o', CIRCUMFLEX), ('õ', 'o', TILDE),
+    ('ö', 'o', DIAERESIS),
+    ('ù', 'u', GRAVE), ('ú', 'u', ACUTE), ('û', 'u', CIRCUMFLEX), ('ü', 'u', DIAERESIS),
+    ('ý', 'y', ACUTE), ('ÿ', 'y', DIAERESIS),
+    // The cedilla goes below, see `compose`.
+    ('Ç', 'C', [0; 2]), ('ç', 'c', [0; 2]),
+    // Upside down marks of Spanish.
+    ('¡', '!', [0; 2]), ('¿', '?', [0; 2]),
+];
+
+#[rustfmt::skip]
+const SYMBOLS: [(char, Glyph); 6] = [
+    ('\u{A0}', [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]),
+    ('ß', [0x38, 0x44, 0x44, 0x58, 0x44, 0x44, 0x58, 0x40]),
+    ('«', [0x00, 0x00, 0x24, 0x48, 0x90, 0x48, 0x24, 0x00]),
+    ('»', [0x00, 0x00, 0x90, 0x48, 0x24, 0x48, 0x90, 0x00]),
+    ('°', [0x30, 0x48, 0x48, 0x30, 0x00, 0x00, 0x00, 0x00]),
+    ('·', [0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00]),
+];
+
+#[derive(Clone)]
+pub struct Font {
+    glyphs: HashMap<char, Glyph>,
+    fallback: Glyph,
+}
+
+impl Font {
+    /// ASCII glyphs of the game and the Latin-1 letters.
+    pub fn builtin() -> Self {
+        let mut glyphs: HashMap<char, Glyph> = FONT
+            .chunks(GLYPH_SIZE)
+            .enumerate()
+            .map(|(i, rows)| {
+                let mut glyph = [0; GLYPH_SIZE];
+                glyph.copy_from_slice(rows);
+                ((b' ' + i as u8) as char, glyph)
+            })
+            .collect();
+
+        for (ch, base, accent) in ACCENTED.iter() {
+            let glyph = compose(*ch, &glyphs[base], accent);
+            glyphs.insert(*ch, glyph);
+        }
+        glyphs.extend(SYMBOLS.iter().copied());
+
+        Self {
+            glyphs,
+            fallback: FALLBACK_GLYPH,
+        }
+    }
+
+    /// Load a BDF font.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text =
+            fs::read_to_string(path).with_context(|| format!("Unable to read font {:?}", path))?;
+
+        Self::from_bdf(&text).with_context(|| format!("Bad font {:?}", path))
+    }
+
+    /// Parse a BDF font, glyphs are placed on the font baseline.
+    pub fn from_bdf(text: &str) -> Result<Self> {
+        let mut glyphs = HashMap::new();
+        let mut ascent = None;
+        let mut default_char = None;
+        let mut lines = text.lines().enumerate();
+
+        while let Some((line_idx, line)) = lines.next() {
+            let mut words = line.split_whitespace();
+            let keyword = words.next().unwrap_or_default();
+            let values: Vec<i32> = match keyword {
+                "FONTBOUNDINGBOX" | "FONT_ASCENT" | "DEFAULT_CHAR" => {
+                    numbers(words).with_context(|| format!("Line {}", line_idx + 1))?
+                }
+                _ => Vec::new(),
+            };
+
+            match keyword {
+                "FONTBOUNDINGBOX" if values.len() == 4 => {
+                    ascent = ascent.or(Some(values[1] + values[3]));
+                }
+                "FONT_ASCENT" if values.len() == 1 => ascent = Some(values[0]),
+                "DEFAULT_CHAR" if values.len() == 1 => default_char = Some(values[0]),
+                "STARTCHAR" => {
+                    let ascent = ascent.ok_or_else(|| {
+                        anyhow!("Line {}: glyph before FONTBOUNDINGBOX", line_idx + 1)
+                    })?;
+                    if let Some((encoding, glyph)) =
+                        bdf_glyph(&mut lines, ascent).with_context(|| {
+                            let name = line.split_whitespace().nth(1).unwrap_or_default();
+                            format!("Glyph '{}'", name)
+                        })?
+                    {
+                        glyphs.insert(encoding, glyph);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if glyphs.is_empty() {
+            bail!("No glyphs");
+        }
+
+        let fallback = default_char
+            .and_then(|code| char::from_u32(code as u32))
+            .and_then(|ch| glyphs.get(&ch).copied())
+            .unwrap_or(FALLBACK_GLYPH);
+
+        Ok(Self { glyphs, fallback })
+    }
+
+    /// Glyph of `ch`, the fallback glyph if the font doesn't have it.
+    pub fn glyph(&self, ch: char) -> &Glyph {
+        self.glyphs.get(&ch).unwrap_or(&self.fallback)
+    }
+
+    pub fn has_glyph(&self, ch: char) -> bool {
+        self.glyphs.contains_key(&ch)
+    }
+
+    pub fn fallback(&self) -> &Glyph {
+        &self.fallback
+    }
+}
+
+impl Default for Font {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+impl fmt::Debug for Font {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Font")
+            .field("glyphs", &self.glyphs.len())
+            .finish()
+    }
+}
+
+// Glyph of `ch` made from the glyph `base`: the accent replaces the two top rows, the dot of
+// the `i` too, capitals lose one of two equal rows to make room for it.
+fn compose(ch: char, base: &Glyph, accent: &[u8; 2]) -> Glyph {
+    let mut glyph = *base;
+
+    match ch {
+        'Ç' => glyph[7] = 0x20,
+        'ç' => glyph[7] = 0x10,
+        // Upside down, the question mark also mirrored.
+        '¡' => glyph[..7].reverse(),
+        '¿' => {
+            glyph[..7].reverse();
+            glyph
+                .iter_mut()
+                .for_each(|row| *row = row.reverse_bits() << 1);
+        }
+        _ => {
+            if ch.is_uppercase() {
+                let rows = &base[..7];
+                let dropped = (0..6).find(|&i| rows[i] == rows[i + 1]).unwrap_or(1);
+                let kept = rows
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| *i != dropped)
+                    .map(|(_, row)| *row);
+                glyph[2..]
+                    .iter_mut()
+                    .zip(kept)
+                    .for_each(|(row, b)| *row = b);
+            }
+
+            // Over the middle of the letter.
+            let bits = base.iter().fold(0, |bits, row| bits | row);
+            let center = (bits.leading_zeros() + 7 - bits.trailing_zeros()) / 2;
+            for (row, b) in glyph[..2].iter_mut().zip(accent.iter()) {
+                *row = if center > 3 {
+                    b >> (center - 3)
+                } else {
+                    b << (3 - center)
+                };
+            }
+        }
+    }
+
+    glyph
+}
+
+fn numbers<'a, I: Iterator<Item = &'a str>>(words: I) -> Result<Vec<i32>> {
+    words
+        .map(|word| {
+            word.parse()
+                .map_err(|_| anyhow!("Invalid number '{}'", word))
+        })
+        .collect()
+}
+
+// Glyph up to ENDCHAR, `None` for glyphs without a character code.
+fn bdf_glyph<'a, I>(lines: &mut I, ascent: i32) -> Result<Option<(char, Glyph)>>
+where
+    I: Iterator<Item = (usize, &'a str)>,
+{
+    let mut encoding = None;
+    let mut bbx = None;
+    let mut glyph = [0; GLYPH_SIZE];
+
+    while let Some((line_idx, line)) = lines.next() {
+        let mut words = line.split_whitespace();
+        match words.next().unwrap_or_default() {
+            "ENCODING" => encoding = numbers(words)?.first().copied(),
+            "BBX" => match numbers(words)?[..] {
+                [w, h, x, y] => bbx = Some((w, h, x, y)),
+                _ => bail!("Line {}: BBX needs 4 values", line_idx + 1),
+            },
+            "BITMAP" => {
+                let (w, h, x, y) =
+                    bbx.ok_or_else(|| anyhow!("Line {}: BITMAP before BBX", line_idx + 1))?;
+                let top = ascent - y - h;
+                if w > 8 || x < 0 || x + w > 8 || top < 0 || top + h > 8 {
+                    bail!(
+                        "Line {}: the glyph doesn't fit in the 8x8 cell",
+                        line_idx + 1
+                    );
+                }
+
+                for row in top..top + h {
+                    let (line_idx, line) =
+                        lines.next().ok_or_else(|| anyhow!("Missing bitmap rows"))?;
+                    let bits = line.trim().get(..2).unwrap_or_default();
+                    let bits = u8::from_str_radix(bits, 16)
+                        .map_err(|_| anyhow!("Line {}: invalid bitmap row", line_idx + 1))?;
+                    glyph[row as usize] = bits >> x;
+                }
+            }
+            "ENDCHAR" => {
+                return Ok(encoding
+                    .and_then(|code| char::from_u32(code as u32))
+                    .map(|ch| (ch, glyph)));
+            }
+            _ => {}
+        }
+    }
+
+    bail!("Missing ENDCHAR")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_font() {
+        let font = Font::builtin();
+        assert_eq!(
+            &font.glyph('A')[..],
+            &FONT[(b'A' - b' ') as usize * 8..][..8]
+        );
+        assert!(font.has_glyph('~'));
+
+        // Accents over the lowercase letters, capitals squeezed below them.
+        let (e, e_acute) = (font.glyph('e'), font.glyph('é'));
+        assert_eq!(e_acute[..2], ACUTE);
+        assert_eq!(e_acute[2..], e[2..]);
+        assert_eq!(
+            font.glyph('É'),
+            &[0x08, 0x10, 0x7C, 0x40, 0x78, 0x40, 0x40, 0x7C]
+        );
+        assert_eq!(font.glyph('ï')[..3], [0x28, 0x00, 0x10]);
+        assert_eq!(font.glyph('ç')[..7], font.glyph('c')[..7]);
+        assert_eq!(
+            font.glyph('¡'),
+            &[0x10, 0x00, 0x10, 0x10, 0x10, 0x10, 0x10, 0x00]
+        );
+        assert_eq!(font.glyph('¿')[6], 0x7C);
+
+        for ch in (0xC0..=0xFFu32).filter_map(char::from_u32) {
+            assert!(font.has_glyph(ch) || "ÆÐ×ØÞæð÷øþ".contains(ch), "{}", ch);
+        }
+
+        assert!(!font.has_glyph('\u{263A}'));
+        assert_eq!(font.glyph('\u{263A}'), &FALLBACK_GLYPH);
+        assert_eq!(font.glyph('\t'), &FALLBACK_GLYPH);
+    }
+
+    #[test]
+    fn test_bdf_font() -> Result<()> {
+        let bdf = "\
+STARTFONT 2.1
+FONT -awbi-test-medium-r-normal--8-80-75-75-c-80-iso10646-1
+FONTBOUNDINGBOX 8 8 0 -1
+STARTPROPERTIES 1
+DEFAULT_CHAR 63
+ENDPROPERTIES
+CHARS 2
+STARTCHAR question
+ENCODING 63
+BBX 5 7 1 0
+BITMAP
+70
+88
+08
+30
+20
+00
+20
+ENDCHAR
+STARTCHAR eacute
+ENCODING 233
+BBX 4 3 2 2
+BITMAP
+60
+90
+60
+ENDCHAR
+ENDFONT
+";
+        let font = Font::from_bdf(bdf)?;
+        assert_eq!(
+            font.glyph('?'),
+            &[0x38, 0x44, 0x04, 0x18, 0x10, 0x00, 0x10, 0x00]
+        );
+        assert_eq!(
+            font.glyph('é'),
+            &[0x00, 0x00, 0x18, 0x24, 0x18, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(font.glyph('A'), font.glyph('?'));
+        assert_eq!(font.fallback(), font.glyph('?'));
+
+        let too_big = bdf.replace("BBX 4 3 2 2", "BBX 4 3 6 2");
+        assert!(Font::from_bdf(&too_big).is_err());
+        assert!(Font::from_bdf("STARTFONT 2.1\nENDFONT\n").is_err());
+
+        Ok(())
+    }
+}
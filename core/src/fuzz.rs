@@ -0,0 +1,181 @@
+// Entry points of the fuzz targets in `fuzz/`. Each one feeds arbitrary bytes to a parser of game
+// data or savestates, errors are expected, panics are bugs.
+
+use crate::{
+    bank,
+    command::Command,
+    engine::Engine,
+    profiler::Profiler,
+    reference::Ref,
+    resource::Resource,
+    slice_reader::SliceReader,
+    storage::Storage,
+    system::{HeadlessSystem, SystemRef},
+    vfs::MemVfs,
+    video::{Point, Video},
+};
+use anyhow::Result;
+
+// Memlist sizes are 16 bits.
+const MAX_RESOURCE_SIZE: usize = 0xFFFF;
+const NUM_BANKS: u8 = 13;
+
+/// Parse `data` as bytecode up to its end.
+pub fn parse_commands(data: &[u8]) -> Result<()> {
+    let mut sr = SliceReader::from(data);
+
+    while sr.can_read() {
+        let opcode = sr.read_u8()?;
+        Command::parse(opcode, &mut sr)?;
+    }
+
+    Ok(())
+}
+
+/// Unpack `data` as a packed bank resource.
+pub fn unpack(data: &[u8]) -> Result<Vec<u8>> {
+    bank::unpack(data, MAX_RESOURCE_SIZE)
+}
+
+/// Parse `data` as `memlist.bin` and load the entries from banks holding `data` too, so that
+/// the offsets and sizes of the entries point to something.
+pub fn load_mem_list(data: &[u8]) -> Result<()> {
+    let mut vfs = MemVfs::default();
+    vfs.insert("memlist.bin", data.to_vec());
+    for bank_id in 1..=NUM_BANKS {
+        vfs.insert(&format!("bank{:02x}", bank_id), data.to_vec());
+    }
+
+    let mut storage = Storage::new(Box::new(vfs));
+    storage.load()?;
+    storage.load_all()?;
+
+    Ok(())
+}
+
+/// Draw `data` as a polygon or a polygon hierarchy, the first two bytes are the zoom.
+pub fn draw_polygon(data: &[u8]) -> Result<()> {
+    let (zoom, shape) = match data {
+        [hi, lo, shape @ ..] => (u16::from_be_bytes([*hi, *lo]), shape),
+        _ => return Ok(()),
+    };
+
+    let sys: SystemRef = Ref::new(Box::new(HeadlessSystem::default()));
+    let res = Ref::new(Box::new(Resource::new(Storage::new(Box::new(
+        MemVfs::default(),
+    )))));
+    let mut video = Video::new(res, sys, Ref::new(Box::new(Profiler::default())));
+    video.init();
+
+    video.draw_shape_data(shape, 0, 0xFF, zoom, Point::new(160, 100))
+}
+
+/// Load `data` as a savestate into the game in `data_dir`.
+pub fn load_state(data_dir: &str, data: &[u8]) -> Result<()> {
+    let sys: SystemRef = Ref::new(Box::new(HeadlessSystem::default()));
+    let mut engine = Engine::new(sys, data_dir, data_dir);
+    engine.set_crash_dump_dir(None);
+    engine.init()?;
+
+    Ok(engine.load_state(data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bank::pack,
+        fixtures::{MAIN_CODE, POLYGONS},
+        util::fixture_dir,
+        video::VID_PAGE_SIZE,
+        vm_context::{VM_NUM_THREADS, VM_NUM_VARIABLES},
+    };
+
+    // Signature, version and description.
+    const STATE_HEADER_SIZE: usize = 40;
+    // Loaded resources, part, memory offsets and segment resource indices.
+    const RESOURCE_STATE_SIZE: usize = 64 + 2 + 4 * 4 + 1 + 4 * 4;
+
+    // Every prefix and every single byte change of valid data must fail cleanly.
+    fn check_mutations<F: FnMut(&[u8]) -> Result<()>>(data: &[u8], mut f: F) {
+        for len in 0..data.len() {
+            let _ = f(&data[..len]);
+        }
+
+        let mut mutated = data.to_vec();
+        for i in 0..data.len() {
+            for b in [0x00, 0x7F, 0x80, 0xFF].iter() {
+                let old = mutated[i];
+                mutated[i] = *b;
+                let _ = f(&mutated);
+                mutated[i] = old;
+            }
+        }
+    }
+
+    #[test]
+    fn test_fuzz_targets() -> Result<()> {
+        let data_dir = fixture_dir()?;
+        let data_dir = data_dir.to_str().unwrap();
+
+        parse_commands(&MAIN_CODE)?;
+        assert!(parse_commands(&MAIN_CODE[..2]).is_err());
+        check_mutations(&MAIN_CODE, parse_commands);
+
+        let packed = pack(&MAIN_CODE.repeat(4));
+        assert_eq!(unpack(&packed)?, MAIN_CODE.repeat(4));
+        check_mutations(&packed, |data| unpack(data).map(|_| ()));
+
+        let mem_list = std::fs::read(fixture_dir()?.join("memlist.bin"))?;
+        check_mutations(&mem_list[..0x100], load_mem_list);
+
+        let mut shape = vec![0x00, 0x40];
+        shape.extend_from_slice(&POLYGONS[0x0C..]);
+        shape.extend_from_slice(&POLYGONS);
+        draw_polygon(&[0x00, 0x40, 0xC5, 0x50, 0x3C, 0x02, 0x00, 0x00, 0x50, 0x3C])?;
+        check_mutations(&shape, draw_polygon);
+
+        let sys: SystemRef = Ref::new(Box::new(HeadlessSystem::default()));
+        let mut engine = Engine::new(sys, data_dir, data_dir);
+        engine.set_crash_dump_dir(None);
+        engine.init()?;
+        let state = engine.save_state()?;
+        load_state(data_dir, &state)?;
+        assert!(load_state(data_dir, &state[..40]).is_err());
+
+        // The bytecode resource index of the resource state closing the savestate, far out of
+        // the memlist.
+        let mut bad_code = state.clone();
+        let code_idx = state.len() - 12;
+        bad_code[code_idx..code_idx + 4].copy_from_slice(&[0xFF; 4]);
+        assert!(load_state(data_dir, &bad_code).is_err());
+
+        // Mutate the header, the top of the call stack and the video state before the screen
+        // pages, then the sound and resource state after them. The variables and the pixels are
+        // plain values, the list of loaded resources ends at the first zero.
+        let stack = STATE_HEADER_SIZE + 2 * VM_NUM_VARIABLES;
+        let pages = stack + 2 * VM_NUM_THREADS + 3;
+        let loaded_list = state.len() - RESOURCE_STATE_SIZE;
+        let fields = (0..8)
+            .chain(stack..stack + 4)
+            .chain(pages - 3..pages)
+            .chain(pages + 4 * VID_PAGE_SIZE..loaded_list + 8)
+            .chain(loaded_list + 64..state.len());
+
+        // A rejected state leaves the engine as it was.
+        let mut mutated = state.clone();
+        for i in fields {
+            for b in [0x00, 0xFF].iter() {
+                let old = mutated[i];
+                mutated[i] = *b;
+                match engine.load_state(&mutated) {
+                    Ok(()) => engine.load_state(&state)?,
+                    Err(_) => assert!(engine.save_state()? == state, "Mutation at {:#X}", i),
+                }
+                mutated[i] = old;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,511 @@
+// Identification and verification of the game data. At startup `memlist.bin` is hashed with
+// CRC32 and looked up in the known releases, and the sizes of the banks are checked against
+// its resources. Data missing from the table is recognized from the banks it ships: the DOS
+// demo has no password screen. `verify` also hashes the banks and unpacks every resource.
+
+use crate::{
+    bank,
+    error::AwbiError,
+    file::File,
+    memlist::{MemEntry, MemList},
+    parts::*,
+    vfs::Vfs,
+};
+use anyhow::{bail, Result};
+use std::fmt;
+
+const NUM_BANKS: u8 = 13;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Release {
+    DosRetail,
+    DosDemo,
+    #[default]
+    Unknown,
+}
+
+impl fmt::Display for Release {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Self::DosRetail => "DOS retail",
+            Self::DosDemo => "DOS demo",
+            Self::Unknown => "unknown",
+        })
+    }
+}
+
+pub struct KnownRelease {
+    pub name: &'static str,
+    pub release: Release,
+    /// CRC32 of `memlist.bin`.
+    pub memlist_crc: u32,
+    /// Size and CRC32 of every bank, `None` for the banks the release doesn't ship.
+    pub banks: [Option<(u64, u32)>; NUM_BANKS as usize],
+}
+
+// Releases whose files were checked, one line per release from the output of
+// `awbi-tools verify` on the original files. None could be checked yet, the releases are
+// recognized from the banks they ship instead.
+pub const KNOWN_RELEASES: [KnownRelease; 0] = [];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    MissingFile(String),
+    BadMemList(String),
+    /// File of a known release with another size or checksum.
+    BadFile {
+        name: String,
+        reason: String,
+    },
+    /// Resource out of its bank, failing to unpack or of the wrong size.
+    BadResource {
+        res_id: usize,
+        bank_id: u8,
+        reason: String,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingFile(name) => write!(
+                f,
+                "'{}' is missing, copy it from the original game into the data directory",
+                name
+            ),
+            Self::BadMemList(reason) => write!(
+                f,
+                "'memlist.bin' is damaged ({}), copy it again from the original game",
+                reason
+            ),
+            Self::BadFile { name, reason } => write!(
+                f,
+                "'{}' is damaged ({}), copy it again from the original game",
+                name, reason
+            ),
+            Self::BadResource {
+                res_id,
+                bank_id,
+                reason,
+            } => write!(
+                f,
+                "resource {:#04X} of 'bank{:02x}' is damaged ({}), copy the bank again from \
+                 the original game",
+                res_id, bank_id, reason
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DataFile {
+    pub name: String,
+    /// `None` if the file is missing.
+    pub size: Option<u64>,
+    /// CRC32, the banks are only hashed by `verify`.
+    pub crc: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct IntegrityReport {
+    pub release: Release,
+    /// Name of the matching known release.
+    pub known_as: Option<&'static str>,
+    /// `memlist.bin` followed by the banks.
+    pub files: Vec<DataFile>,
+    pub problems: Vec<Problem>,
+}
+
+impl IntegrityReport {
+    /// Fail with every problem found.
+    pub fn ensure_ok(&self) -> Result<()> {
+        if !self.problems.is_empty() {
+            let problems: Vec<_> = self.problems.iter().map(|p| format!("  {}", p)).collect();
+            bail!(AwbiError::bad_data(format!(
+                "The game data is incomplete or damaged:\n{}",
+                problems.join("\n")
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for IntegrityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.known_as {
+            Some(name) => writeln!(f, "Release: {} ({})", self.release, name)?,
+            None => writeln!(f, "Release: {} (checksum not in the table)", self.release)?,
+        }
+        for file in &self.files {
+            match (file.size, file.crc) {
+                (Some(size), Some(crc)) => {
+                    writeln!(f, "  {:12} {:8} bytes  {:08X}", file.name, size, crc)?
+                }
+                (Some(size), None) => writeln!(f, "  {:12} {:8} bytes", file.name, size)?,
+                (None, _) => writeln!(f, "  {:12} missing", file.name)?,
+            }
+        }
+        for problem in &self.problems {
+            writeln!(f, "Error: {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+/// Identify the data in `vfs` and look for missing files, for banks too short for the
+/// resources of the memlist and for files whose size differs from the known release. Only
+/// `memlist.bin` is read, this runs at startup.
+pub fn check(vfs: &dyn Vfs) -> Result<IntegrityReport> {
+    Ok(check_with(vfs, &KNOWN_RELEASES)?.0)
+}
+
+/// Run `check`, then read every bank to compare its checksum with the known release and
+/// unpack every resource to check its size.
+pub fn verify(vfs: &dyn Vfs) -> Result<IntegrityReport> {
+    verify_with(vfs, &KNOWN_RELEASES)
+}
+
+// The report and the memlist if it could be read.
+fn check_with(
+    vfs: &dyn Vfs,
+    known_releases: &[KnownRelease],
+) -> Result<(IntegrityReport, Option<MemList>)> {
+    let mut report = IntegrityReport::default();
+
+    let mem_list_data = match vfs.read("memlist.bin")? {
+        Some(data) => data,
+        None => {
+            report.files.push(DataFile {
+                name: "memlist.bin".into(),
+                size: None,
+                crc: None,
+            });
+            report
+                .problems
+                .push(Problem::MissingFile("memlist.bin".into()));
+            return Ok((report, None));
+        }
+    };
+    let memlist_crc = crc32fast::hash(&mem_list_data);
+    report.files.push(DataFile {
+        name: "memlist.bin".into(),
+        size: Some(mem_list_data.len() as u64),
+        crc: Some(memlist_crc),
+    });
+
+    let mut mem_list = MemList::default();
+    if let Err(err) = mem_list.read(&mut File::from_data(mem_list_data)) {
+        report.problems.push(Problem::BadMemList(err.to_string()));
+        return Ok((report, None));
+    }
+
+    for bank_id in 1..=NUM_BANKS {
+        let name = format!("bank{:02x}", bank_id);
+        let size = vfs.size(&name)?;
+        report.files.push(DataFile {
+            name,
+            size,
+            crc: None,
+        });
+    }
+    let sizes: Vec<_> = report.files[1..].iter().map(|file| file.size).collect();
+    let bank_size = |bank_id: u8| {
+        (bank_id as usize)
+            .checked_sub(1)
+            .and_then(|idx| sizes.get(idx).copied())
+    };
+
+    let known = known_releases
+        .iter()
+        .find(|known| known.memlist_crc == memlist_crc);
+    report.release = match known {
+        Some(known) => known.release,
+        None if is_demo(&mem_list, bank_size) => Release::DosDemo,
+        None => Release::Unknown,
+    };
+
+    let mut problems = Vec::new();
+    for (bank_id, file) in (1..=NUM_BANKS).zip(&report.files[1..]) {
+        let needed = mem_list.entries.iter().enumerate().any(|(res_id, me)| {
+            me.bank_id == bank_id && !(report.release == Release::DosDemo && not_in_demo(res_id))
+        });
+        let known_size = known.and_then(|known| known.banks[bank_id as usize - 1]);
+
+        match (file.size, known_size) {
+            (None, _) if needed => problems.push(Problem::MissingFile(file.name.clone())),
+            (Some(size), Some((known_size, _))) if size != known_size => {
+                problems.push(Problem::BadFile {
+                    name: file.name.clone(),
+                    reason: format!("{} bytes instead of {}", size, known_size),
+                })
+            }
+            _ => {}
+        }
+    }
+
+    for (res_id, me) in mem_list.entries.iter().enumerate() {
+        let size = match bank_size(me.bank_id) {
+            Some(Some(size)) => size,
+            Some(None) => continue,
+            None => {
+                problems.push(Problem::BadMemList(format!(
+                    "resource {:#04X} refers to unknown bank {}",
+                    res_id, me.bank_id
+                )));
+                continue;
+            }
+        };
+
+        if let Err(err) = in_bank(size, me) {
+            problems.push(Problem::BadResource {
+                res_id,
+                bank_id: me.bank_id,
+                reason: err.to_string(),
+            });
+        }
+    }
+
+    report.known_as = known.map(|known| known.name);
+    report.problems = problems;
+
+    Ok((report, Some(mem_list)))
+}
+
+fn verify_with(vfs: &dyn Vfs, known_releases: &[KnownRelease]) -> Result<IntegrityReport> {
+    let (mut report, mem_list) = check_with(vfs, known_releases)?;
+    let mem_list = match mem_list {
+        Some(mem_list) => mem_list,
+        None => return Ok(report),
+    };
+    let known = known_releases
+        .iter()
+        .find(|known| Some(known.name) == report.known_as);
+
+    let mut banks = Vec::new();
+    for (bank_id, file) in (1..=NUM_BANKS).zip(&mut report.files[1..]) {
+        let data = match file.size {
+            Some(_) => vfs.read(&file.name)?,
+            None => None,
+        };
+        file.crc = data.as_deref().map(crc32fast::hash);
+
+        let known_crc = known.and_then(|known| known.banks[bank_id as usize - 1]);
+        if let (Some(crc), Some((_, known_crc))) = (file.crc, known_crc) {
+            if crc != known_crc {
+                report.problems.push(Problem::BadFile {
+                    name: file.name.clone(),
+                    reason: format!("CRC32 {:08X} instead of {:08X}", crc, known_crc),
+                });
+            }
+        }
+        banks.push(data);
+    }
+
+    for (res_id, me) in mem_list.entries.iter().enumerate() {
+        let bank = match (me.bank_id as usize)
+            .checked_sub(1)
+            .and_then(|idx| banks.get(idx))
+        {
+            Some(Some(bank)) => bank,
+            // Reported by the check.
+            _ => continue,
+        };
+        if in_bank(bank.len() as u64, me).is_err() {
+            continue;
+        }
+
+        let result = bank::read_entry_data(bank, me).and_then(|data| {
+            if data.len() != me.size {
+                bail!("unpacked to {} bytes instead of {}", data.len(), me.size);
+            }
+            Ok(())
+        });
+        if let Err(err) = result {
+            report.problems.push(Problem::BadResource {
+                res_id,
+                bank_id: me.bank_id,
+                reason: err.to_string(),
+            });
+        }
+    }
+
+    Ok(report)
+}
+
+fn in_bank(bank_size: u64, me: &MemEntry) -> Result<()> {
+    let end = me.bank_offset + me.packed_size as u64;
+    if end > bank_size {
+        bail!("ends at {:#X} after the end of the bank", end);
+    }
+    Ok(())
+}
+
+// Resources of the retail parts the demo doesn't have.
+fn not_in_demo(res_id: usize) -> bool {
+    MEM_LIST_PARTS
+        .iter()
+        .zip(DEMO_MEM_LIST_PARTS.iter())
+        .filter(|(_, demo)| demo[MEMLIST_PART_CODE] as usize == MEMLIST_PART_NONE)
+        .flat_map(|(retail, _)| retail.iter())
+        .any(|idx| *idx as usize != MEMLIST_PART_NONE && *idx as usize == res_id)
+}
+
+// The DOS demo keeps the retail memlist but doesn't ship the bank of the password screen.
+fn is_demo(mem_list: &MemList, bank_size: impl Fn(u8) -> Option<Option<u64>>) -> bool {
+    let code_idx = MEM_LIST_PARTS[GAME_NUM_PARTS - 1][MEMLIST_PART_CODE] as usize;
+    mem_list
+        .entries
+        .get(code_idx)
+        .is_some_and(|me| bank_size(me.bank_id) == Some(None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        resource::Resource,
+        storage::Storage,
+        util::fixture_dir,
+        vfs::{self, DirVfs, MemVfs, OverlayVfs},
+    };
+    use std::{fs, path::Path};
+
+    // The fixtures, failing to read anything but the memlist.
+    #[derive(Debug)]
+    struct MemListOnly(DirVfs);
+
+    impl Vfs for MemListOnly {
+        fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+            assert_eq!(name, "memlist.bin");
+            self.0.read(name)
+        }
+
+        fn size(&self, name: &str) -> Result<Option<u64>> {
+            self.0.size(name)
+        }
+    }
+
+    // The fixtures with another memlist, without the files `missing`.
+    fn mem_vfs(data_dir: &Path, memlist: &[u8], missing: &[&str]) -> Result<MemVfs> {
+        let mut mem = MemVfs::default();
+        for entry in fs::read_dir(data_dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap().to_string_lossy();
+            if !missing.contains(&&*name) {
+                mem.insert(&name, fs::read(&path)?);
+            }
+        }
+        mem.insert("memlist.bin", memlist.to_vec());
+        Ok(mem)
+    }
+
+    #[test]
+    fn test_check() -> Result<()> {
+        let data_dir = fixture_dir()?;
+        let report = check(&MemListOnly(DirVfs::new(&data_dir)))?;
+        assert_eq!(report.problems, vec![]);
+        assert_eq!(report.release, Release::Unknown);
+        assert_eq!(report.known_as, None);
+        assert_eq!(report.files.len(), 1 + NUM_BANKS as usize);
+        assert!(report.files[1..].iter().all(|file| file.crc.is_none()));
+        report.ensure_ok()?;
+
+        let report = verify(&*vfs::open(&data_dir))?;
+        assert_eq!(report.problems, vec![]);
+        assert!(report.files.iter().all(|file| file.crc.is_some()));
+
+        // The fixtures as a known release.
+        let mut banks = [None; NUM_BANKS as usize];
+        for (bank, file) in banks.iter_mut().zip(&report.files[1..]) {
+            *bank = Some((file.size.unwrap(), file.crc.unwrap()));
+        }
+        let fixtures = [KnownRelease {
+            name: "fixtures",
+            release: Release::DosRetail,
+            memlist_crc: report.files[0].crc.unwrap(),
+            banks,
+        }];
+        let (report, _) = check_with(&*vfs::open(&data_dir), &fixtures)?;
+        assert_eq!(report.release, Release::DosRetail);
+        assert_eq!(report.known_as, Some("fixtures"));
+        assert_eq!(report.problems, vec![]);
+
+        // A damaged resource, another byte makes the bank bigger than the known one.
+        let mut bank02 = fs::read(data_dir.join("bank02"))?;
+        let last = bank02.len() - 5;
+        bank02[last] ^= 0x10;
+        let mut broken = MemVfs::default();
+        broken.insert("bank02", bank02.clone());
+        let broken = OverlayVfs::new(vec![Box::new(broken), vfs::open(&data_dir)]);
+        let report = verify_with(&broken, &fixtures)?;
+        assert_eq!(report.problems.len(), 2);
+        assert!(matches!(&report.problems[0], Problem::BadFile { name, .. } if name == "bank02"));
+        assert!(matches!(
+            report.problems[1],
+            Problem::BadResource { bank_id: 2, .. }
+        ));
+
+        bank02.push(0);
+        let mut longer = MemVfs::default();
+        longer.insert("bank02", bank02);
+        let longer = OverlayVfs::new(vec![Box::new(longer), vfs::open(&data_dir)]);
+        let (report, _) = check_with(&longer, &fixtures)?;
+        assert_eq!(
+            report.problems,
+            vec![Problem::BadFile {
+                name: "bank02".into(),
+                reason: format!("{} bytes instead of {}", last + 6, last + 5),
+            }]
+        );
+
+        let mut mem = MemVfs::default();
+        mem.insert("memlist.bin", fs::read(data_dir.join("memlist.bin"))?);
+        let report = check(&mem)?;
+        assert!(report
+            .problems
+            .contains(&Problem::MissingFile("bank01".into())));
+        let err = report.ensure_ok().unwrap_err().to_string();
+        assert!(err.contains("'bank02' is missing"), "{}", err);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_demo() -> Result<()> {
+        let data_dir = fixture_dir()?;
+
+        // The password screen moved to the third bank, which the demo doesn't ship.
+        let mut memlist = fs::read(data_dir.join("memlist.bin"))?;
+        for res_id in &MEM_LIST_PARTS[GAME_NUM_PARTS - 1][..MEMLIST_PART_VIDEO2] {
+            let me = &mut memlist[*res_id as usize * 20..];
+            me[7] = 3;
+            me[8..12].copy_from_slice(&[0; 4]);
+        }
+        assert_eq!(
+            check(&mem_vfs(&data_dir, &memlist, &[])?)?.release,
+            Release::Unknown
+        );
+
+        let report = check(&mem_vfs(&data_dir, &memlist, &["bank03"])?)?;
+        assert_eq!(report.release, Release::DosDemo);
+        assert_eq!(report.problems, vec![]);
+
+        // The engine picks the demo parts.
+        let demo = mem_vfs(&data_dir, &memlist, &["bank03"])?;
+        let mut res = Resource::new(Storage::new(Box::new(demo)));
+        res.init()?;
+        assert_eq!(res.variant().release, Release::DosDemo);
+        assert_eq!(
+            res.variant().part(GAME_PART8),
+            Some(&DEMO_MEM_LIST_PARTS[7])
+        );
+        assert_eq!(res.variant().part(GAME_PART10), None);
+
+        // Any other missing bank is still reported.
+        let report = check(&mem_vfs(&data_dir, &memlist, &["bank02", "bank03"])?)?;
+        assert_eq!(report.release, Release::DosDemo);
+        assert_eq!(report.problems, vec![Problem::MissingFile("bank02".into())]);
+
+        Ok(())
+    }
+}
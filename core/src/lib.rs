@@ -1,12 +1,25 @@
 #![allow(dead_code, incomplete_features, clippy::missing_const_for_fn)]
 
+#[macro_use]
+pub mod logging;
+
 mod bank;
 mod command;
+pub mod conformance;
+pub mod coverage;
+pub mod crash_dump;
+pub mod debugger;
 pub mod engine;
+pub mod error;
 mod file;
+pub mod fixtures;
+pub mod font;
+pub mod fuzz;
+pub mod integrity;
 mod memlist;
 mod mixer;
 mod parts;
+pub mod profiler;
 mod program;
 pub mod reference;
 mod resource;
@@ -15,8 +28,18 @@ mod sfxplayer;
 mod slice_reader;
 mod staticres;
 mod storage;
+pub mod strings;
+pub mod subtitles;
+pub mod symbols;
 pub mod system;
+pub mod trace;
 mod util;
+pub mod validator;
+pub mod variant;
+pub mod vfs;
+
 mod video;
-mod vm;
-mod vm_context;
+pub mod vm;
+pub mod vm_context;
+pub mod watchdog;
+pub mod xref;
@@ -0,0 +1,166 @@
+// Debug output is split in categories like in the original interpreter (see DBG_* masks).
+// Messages go through the `log` facade so the embedder can plug any logger, or call `init`
+// to get the built-in one printing to stderr.
+
+use anyhow::{anyhow, Result};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU16, Ordering};
+
+pub const DBG_VM: u16 = 1 << 0;
+pub const DBG_BANK: u16 = 1 << 1;
+pub const DBG_VIDEO: u16 = 1 << 2;
+pub const DBG_SND: u16 = 1 << 3;
+pub const DBG_SER: u16 = 1 << 4;
+pub const DBG_INFO: u16 = 1 << 5;
+pub const DBG_ALL: u16 = DBG_VM | DBG_BANK | DBG_VIDEO | DBG_SND | DBG_SER | DBG_INFO;
+
+const CATEGORIES: [(u16, &str); 6] = [
+    (DBG_VM, "vm"),
+    (DBG_BANK, "bank"),
+    (DBG_VIDEO, "video"),
+    (DBG_SND, "snd"),
+    (DBG_SER, "ser"),
+    (DBG_INFO, "info"),
+];
+
+static DEBUG_MASK: AtomicU16 = AtomicU16::new(DBG_INFO);
+
+pub fn debug_mask() -> u16 {
+    DEBUG_MASK.load(Ordering::Relaxed)
+}
+
+pub fn set_debug_mask(mask: u16) {
+    DEBUG_MASK.store(mask, Ordering::Relaxed);
+}
+
+pub fn set_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
+
+pub fn is_enabled(mask: u16, level: Level) -> bool {
+    debug_mask() & mask != 0 && level <= log::max_level()
+}
+
+/// Log target of the category, e.g. `awbi::vm`.
+pub fn target(mask: u16) -> &'static str {
+    match mask {
+        DBG_VM => "awbi::vm",
+        DBG_BANK => "awbi::bank",
+        DBG_VIDEO => "awbi::video",
+        DBG_SND => "awbi::snd",
+        DBG_SER => "awbi::ser",
+        DBG_INFO => "awbi::info",
+        _ => "awbi",
+    }
+}
+
+/// Parse comma separated category names (`vm,video`), `all` or `none`.
+pub fn parse_debug_mask(s: &str) -> Result<u16> {
+    let mut mask = 0;
+
+    for name in s.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        mask |= match name {
+            "all" => DBG_ALL,
+            "none" => 0,
+            _ => CATEGORIES
+                .iter()
+                .find(|(_, n)| *n == name)
+                .map(|(m, _)| *m)
+                .ok_or_else(|| anyhow!("Unknown debug category '{}'", name))?,
+        };
+    }
+
+    Ok(mask)
+}
+
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "[{}] {}: {}",
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// Install the stderr logger.
+pub fn init(level: LevelFilter, mask: u16) -> Result<()> {
+    log::set_logger(&LOGGER).map_err(|e| anyhow!("Cannot set logger: {}", e))?;
+    set_level(level);
+    set_debug_mask(mask);
+
+    Ok(())
+}
+
+/// Install the stderr logger configured by `AWBI_LOG` (level, `info` by default)
+/// and `AWBI_DEBUG` (categories, `info` by default) environment variables.
+pub fn init_from_env() -> Result<()> {
+    let level = match std::env::var("AWBI_LOG") {
+        Ok(level) => LevelFilter::from_str(&level)
+            .map_err(|_| anyhow!("Invalid AWBI_LOG level '{}'", level))?,
+        Err(_) => LevelFilter::Info,
+    };
+    let mask = match std::env::var("AWBI_DEBUG") {
+        Ok(mask) => parse_debug_mask(&mask)?,
+        Err(_) => DBG_INFO,
+    };
+
+    init(level, mask)
+}
+
+// Categorized debug message, e.g. `debug!(DBG_VM, "thr_id={}", thread_id)`.
+macro_rules! debug {
+    ($mask:expr, $($arg:tt)+) => {
+        if $crate::logging::is_enabled($mask, ::log::Level::Debug) {
+            ::log::debug!(target: $crate::logging::target($mask), $($arg)+);
+        }
+    };
+}
+
+// Categorized message for very verbose output, e.g. every executed instruction.
+macro_rules! trace {
+    ($mask:expr, $($arg:tt)+) => {
+        if $crate::logging::is_enabled($mask, ::log::Level::Trace) {
+            ::log::trace!(target: $crate::logging::target($mask), $($arg)+);
+        }
+    };
+}
+
+macro_rules! warning {
+    ($($arg:tt)+) => {
+        ::log::warn!(target: "awbi", $($arg)+)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_debug_mask() -> Result<()> {
+        assert_eq!(parse_debug_mask("vm")?, DBG_VM);
+        assert_eq!(
+            parse_debug_mask("vm, video,snd")?,
+            DBG_VM | DBG_VIDEO | DBG_SND
+        );
+        assert_eq!(parse_debug_mask("all")?, DBG_ALL);
+        assert_eq!(parse_debug_mask("")?, 0);
+        assert!(parse_debug_mask("vm,gfx").is_err());
+
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
+use crate::error::AwbiError;
 use crate::file::File;
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, Result};
 
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub(crate) enum MemEntryState {
@@ -101,33 +102,24 @@ impl MemEntry {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub(crate) struct MemList {
-    data_dir: String,
     pub entries: Vec<MemEntry>,
 }
 
 impl MemList {
-    pub fn new(data_dir: &str) -> Self {
-        Self {
-            data_dir: data_dir.into(),
-            entries: Vec::new(),
-        }
-    }
-
-    pub fn load(&mut self) -> Result<()> {
-        let mut f = File::open("memlist.bin", &self.data_dir, false).with_context(|| {
-            format!(
-                "MemList::load() unable to open '{:?}/memlist.bin' file",
-                self.data_dir
-            )
-        })?;
+    /// Parse the entries of `memlist.bin` up to the end marker.
+    pub fn read(&mut self, f: &mut File) -> Result<()> {
+        self.entries.clear();
 
         loop {
             let state = MemEntryState::new(f.read_u8()?);
 
             if let MemEntryState::Unknown(state) = state {
-                bail!("Unknown entry state {}", state);
+                bail!(AwbiError::bad_data(format!(
+                    "Unknown memlist entry state {}",
+                    state
+                )));
             }
 
             let entry = MemEntry {
@@ -155,6 +147,19 @@ impl MemList {
         Ok(())
     }
 
+    /// Entry `idx`, parts and savestates may refer to entries the memlist doesn't have.
+    pub fn entry_mut(&mut self, idx: usize) -> Result<&mut MemEntry> {
+        let len = self.entries.len();
+        let me = self.entries.get_mut(idx).ok_or_else(|| {
+            AwbiError::bad_data(format!(
+                "Resource {:#X} is not in the memlist of {} entries",
+                idx, len
+            ))
+        })?;
+
+        Ok(me)
+    }
+
     pub fn invalidate_res(&mut self) {
         self.entries
             .iter_mut()
@@ -1,4 +1,6 @@
 use crate::file::*;
+use crate::logging::DBG_SND;
+use crate::profiler::{Primitive, ProfilerRef};
 use crate::reference::*;
 use crate::serializer::*;
 use crate::system::*;
@@ -89,6 +91,7 @@ pub(crate) type MixerRef = Ref<Box<Mixer>>;
 
 pub(crate) struct Mixer {
     sys: SystemRef,
+    profiler: ProfilerRef,
     mutex: Vec<u8>,
 
     // Since the virtual machine and SDL are running simultaneously in two different threads
@@ -98,9 +101,10 @@ pub(crate) struct Mixer {
 }
 
 impl Mixer {
-    pub fn new(sys: SystemRef) -> Self {
+    pub fn new(sys: SystemRef, profiler: ProfilerRef) -> Self {
         Self {
             sys,
+            profiler,
             mutex: Vec::new(),
             channels: Default::default(),
         }
@@ -119,8 +123,13 @@ impl Mixer {
     }
 
     pub fn play_channel(&mut self, channel: u8, mc: MixerChunk, freq: u16, volume: u8) {
-        // debug(DBG_SND, "Mixer::playChannel(%d, %d, %d)", channel, freq, volume);
-        assert!((channel as usize) < AUDIO_NUM_CHANNELS);
+        debug!(
+            DBG_SND,
+            "Mixer::play_channel({}, {}, {})", channel, freq, volume
+        );
+        if !is_valid_channel(channel) {
+            return;
+        }
 
         // The mutex is acquired in the constructor
         let _ = MutexStack::new(self.sys.clone(), &self.mutex);
@@ -138,23 +147,30 @@ impl Mixer {
     }
 
     pub fn stop_channel(&mut self, channel: u8) {
-        // debug(DBG_SND, "Mixer::stopChannel(%d)", channel);
-        assert!((channel as usize) < AUDIO_NUM_CHANNELS);
+        debug!(DBG_SND, "Mixer::stop_channel({})", channel);
+        if !is_valid_channel(channel) {
+            return;
+        }
 
         let _ = MutexStack::new(self.sys.clone(), &self.mutex);
         self.channels[channel as usize].active = false;
     }
 
     pub fn set_channel_volume(&mut self, channel: u8, volume: u8) {
-        // debug(DBG_SND, "Mixer::setChannelVolume(%d, %d)", channel, volume);
-        assert!((channel as usize) < AUDIO_NUM_CHANNELS);
+        debug!(
+            DBG_SND,
+            "Mixer::set_channel_volume({}, {})", channel, volume
+        );
+        if !is_valid_channel(channel) {
+            return;
+        }
 
         let _ = MutexStack::new(self.sys.clone(), &self.mutex);
         self.channels[channel as usize].volume = volume;
     }
 
     pub fn stop_all(&mut self) {
-        // debug(DBG_SND, "Mixer::stopAll()");
+        debug!(DBG_SND, "Mixer::stop_all()");
 
         let _ = MutexStack::new(self.sys.clone(), &self.mutex);
         self.channels.iter_mut().for_each(|ch| ch.active = false);
@@ -168,10 +184,11 @@ impl Mixer {
     // of this method.
     pub fn mix(&mut self, len: usize) -> Vec<u8> {
         let _ = MutexStack::new(self.sys.clone(), &self.mutex);
+        let start = self.profiler.get().start();
 
         let mut buf = vec![0i8; len];
 
-        for ch in &mut self.channels {
+        for (i, ch) in self.channels.iter_mut().enumerate() {
             if !ch.active {
                 continue;
             }
@@ -183,14 +200,14 @@ impl Mixer {
 
                 let p2 = if ch.chunk.loop_len != 0 {
                     if p1 == (ch.chunk.loop_pos + ch.chunk.loop_len - 1) as usize {
-                        // debug(DBG_SND, "Looping sample on channel %d", i);
+                        debug!(DBG_SND, "Looping sample on channel {}", i);
                         ch.chunk_pos = ch.chunk.loop_pos as u32;
                         ch.chunk_pos as usize
                     } else {
                         p1 + 1
                     }
                 } else if p1 == ch.chunk.len as usize - 1 {
-                    // debug(DBG_SND, "Stopping sample on channel %d", i);
+                    debug!(DBG_SND, "Stopping sample on channel {}", i);
                     ch.active = false;
                     break;
                 } else {
@@ -211,7 +228,11 @@ impl Mixer {
         // Convert signed 8-bit PCM to unsigned 8-bit PCM. The
         // current version of SDL hangs when using signed 8-bit
         // PCM in combination with the PulseAudio driver.
-        buf.iter().map(|v| (*v as i16 + 128) as u8).collect()
+        let res = buf.iter().map(|v| (*v as i16 + 128) as u8).collect();
+
+        self.profiler.get_mut().finish(Primitive::Mix, start);
+
+        res
     }
 
     pub fn save_or_load(&mut self, ser: &mut Serializer) -> Result<()> {
@@ -227,6 +248,16 @@ impl Mixer {
     }
 }
 
+// Channel ids come from bytecode and music modules, ignore the bad ones like the sound is missing.
+fn is_valid_channel(channel: u8) -> bool {
+    if (channel as usize) < AUDIO_NUM_CHANNELS {
+        true
+    } else {
+        warning!("Mixer: invalid channel {}", channel);
+        false
+    }
+}
+
 fn get_byte(val: u32, idx: usize) -> u8 {
     val.to_ne_bytes()[idx]
 }
@@ -1,5 +1,5 @@
 //The game is divided in 10 parts.
-const GAME_NUM_PARTS: usize = 10;
+pub const GAME_NUM_PARTS: usize = 10;
 
 pub const GAME_PART_FIRST: u16 = 0x3E80;
 pub const GAME_PART1: u16 = 0x3E80;
@@ -45,3 +45,18 @@ pub const MEM_LIST_PARTS: [[u8; 4]; GAME_NUM_PARTS] = [
     [0x7D, 0x7E, 0x7F, 0x00],
     [0x7D, 0x7E, 0x7F, 0x00], // password screen
 ];
+
+// The DOS demo keeps the resource ids of the retail game but has no password screen.
+pub const DEMO_MEM_LIST_PARTS: [[u8; 4]; GAME_NUM_PARTS] = [
+    //MEMLIST_PART_PALETTE   MEMLIST_PART_CODE   MEMLIST_PART_VIDEO1   MEMLIST_PART_VIDEO2
+    [0x14, 0x15, 0x16, 0x00], // protection screens
+    [0x17, 0x18, 0x19, 0x00], // introduction cinematic
+    [0x1A, 0x1B, 0x1C, 0x11],
+    [0x1D, 0x1E, 0x1F, 0x11],
+    [0x20, 0x21, 0x22, 0x11],
+    [0x23, 0x24, 0x25, 0x00], // battlechar cinematic
+    [0x26, 0x27, 0x28, 0x11],
+    [0x29, 0x2A, 0x2B, 0x11],
+    [0x00, 0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x00, 0x00], // password screen
+];
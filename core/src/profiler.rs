@@ -0,0 +1,247 @@
+// Where the interpreter spends its frames: executed commands by kind, commands per thread and
+// time taken by the drawing and sound primitives. Counters of the current frame are added to
+// the session totals when the frame ends.
+
+use crate::{reference::Ref, vm_context::VM_NUM_THREADS};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+pub(crate) type ProfilerRef = Ref<Box<Profiler>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Primitive {
+    FillPolygon,
+    CopyPage,
+    DrawString,
+    Mix,
+}
+
+const PRIMITIVES: [Primitive; 4] = [
+    Primitive::FillPolygon,
+    Primitive::CopyPage,
+    Primitive::DrawString,
+    Primitive::Mix,
+];
+
+impl Primitive {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::FillPolygon => "fill_polygon",
+            Self::CopyPage => "copy_page",
+            Self::DrawString => "draw_string",
+            Self::Mix => "mix",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PrimitiveStats {
+    pub calls: u64,
+    pub time: Duration,
+}
+
+/// Counters of one frame or accumulated over a session.
+#[derive(Clone, Debug)]
+pub struct Profile {
+    pub frames: u64,
+    /// Executed commands by `Command` variant.
+    pub commands: BTreeMap<&'static str, u64>,
+    /// Executed commands by thread.
+    pub threads: [u64; VM_NUM_THREADS],
+    pub primitives: [PrimitiveStats; PRIMITIVES.len()],
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            frames: 0,
+            commands: BTreeMap::new(),
+            threads: [0; VM_NUM_THREADS],
+            primitives: Default::default(),
+        }
+    }
+}
+
+impl Profile {
+    pub fn total_commands(&self) -> u64 {
+        self.threads.iter().sum()
+    }
+
+    pub fn primitive(&self, primitive: Primitive) -> PrimitiveStats {
+        self.primitives[primitive as usize]
+    }
+
+    fn merge(&mut self, other: &Profile) {
+        self.frames += other.frames;
+        for (name, count) in &other.commands {
+            *self.commands.entry(name).or_default() += count;
+        }
+        for (total, count) in self.threads.iter_mut().zip(&other.threads) {
+            *total += count;
+        }
+        for (total, stats) in self.primitives.iter_mut().zip(&other.primitives) {
+            total.calls += stats.calls;
+            total.time += stats.time;
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frames = u64::max(self.frames, 1);
+
+        writeln!(
+            f,
+            "{} frames, {} commands ({} per frame)",
+            self.frames,
+            self.total_commands(),
+            self.total_commands() / frames
+        )?;
+
+        writeln!(f, "commands:")?;
+        let mut commands: Vec<_> = self.commands.iter().collect();
+        commands.sort_by(|a, b| b.1.cmp(a.1));
+        for (name, count) in commands {
+            writeln!(f, "  {:<16}{:>12}", name, count)?;
+        }
+
+        writeln!(f, "threads:")?;
+        for (thread_id, count) in self.threads.iter().enumerate() {
+            if *count > 0 {
+                writeln!(
+                    f,
+                    "  {:>2}{:>14}{:>10} per frame",
+                    thread_id,
+                    count,
+                    count / frames
+                )?;
+            }
+        }
+
+        writeln!(f, "primitives:")?;
+        for primitive in PRIMITIVES {
+            let stats = self.primitive(primitive);
+            writeln!(
+                f,
+                "  {:<16}{:>8} calls {:>10.3} ms",
+                primitive.name(),
+                stats.calls,
+                stats.time.as_secs_f64() * 1000.0
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Profiler {
+    enabled: bool,
+    frame: Profile,
+    last_frame: Profile,
+    session: Profile,
+}
+
+impl Profiler {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Counters of the last completed frame.
+    pub fn last_frame(&self) -> &Profile {
+        &self.last_frame
+    }
+
+    /// Counters of all frames since the profiler was enabled or reset.
+    pub fn session(&self) -> &Profile {
+        &self.session
+    }
+
+    pub fn reset(&mut self) {
+        self.frame = Profile::default();
+        self.last_frame = Profile::default();
+        self.session = Profile::default();
+    }
+
+    pub(crate) fn count_command(&mut self, thread_id: usize, name: &'static str) {
+        *self.frame.commands.entry(name).or_default() += 1;
+        self.frame.threads[thread_id] += 1;
+    }
+
+    /// Start time of a primitive, `None` when profiling is disabled.
+    pub(crate) fn start(&self) -> Option<Instant> {
+        if self.enabled {
+            Some(Instant::now())
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn finish(&mut self, primitive: Primitive, start: Option<Instant>) {
+        if let Some(start) = start {
+            let stats = &mut self.frame.primitives[primitive as usize];
+            stats.calls += 1;
+            stats.time += start.elapsed();
+        }
+    }
+
+    pub(crate) fn end_frame(&mut self) {
+        if !self.enabled {
+            return;
+        }
+
+        let mut frame = std::mem::take(&mut self.frame);
+        frame.frames = 1;
+        self.session.merge(&frame);
+        self.last_frame = frame;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiler() {
+        let mut profiler = Profiler::default();
+
+        // Nothing is timed while disabled.
+        assert!(profiler.start().is_none());
+
+        profiler.set_enabled(true);
+        for _ in 0..2 {
+            profiler.count_command(0, "MovConst");
+            profiler.count_command(3, "MovConst");
+            profiler.count_command(3, "PauseThread");
+            let start = profiler.start();
+            profiler.finish(Primitive::CopyPage, start);
+            profiler.end_frame();
+        }
+
+        let frame = profiler.last_frame();
+        assert_eq!(frame.frames, 1);
+        assert_eq!(frame.total_commands(), 3);
+        assert_eq!(frame.threads[3], 2);
+        assert_eq!(frame.primitive(Primitive::CopyPage).calls, 1);
+
+        let session = profiler.session();
+        assert_eq!(session.frames, 2);
+        assert_eq!(session.commands["MovConst"], 4);
+        assert_eq!(session.primitive(Primitive::CopyPage).calls, 2);
+        assert_eq!(session.primitive(Primitive::FillPolygon).calls, 0);
+
+        let report = session.to_string();
+        assert!(report.starts_with("2 frames, 6 commands (3 per frame)"));
+        assert!(report.contains("copy_page"));
+
+        profiler.reset();
+        assert_eq!(profiler.session().frames, 0);
+    }
+}
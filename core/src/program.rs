@@ -1,13 +1,15 @@
 use crate::{
     command::{Command, JmpType, OpType, ResetType},
-    parts::GAME_PART_FIRST,
+    error::{AwbiError, VmFault},
+    logging::DBG_VM,
     slice_reader::SliceReader,
     staticres::*,
+    symbols::Symbols,
     util::w_add_i16,
     video::Point,
-    vm_context::VmContext,
+    vm_context::{VmContext, VM_NUM_THREADS},
 };
-use anyhow::{anyhow, Result};
+use anyhow::{bail, Context, Result};
 use std::{collections::HashMap, fmt};
 
 const COLOR_BLACK: u8 = 0xFF;
@@ -25,24 +27,7 @@ pub(crate) struct Program {
 }
 
 impl Program {
-    pub fn new(id: usize, part_id: u16, mut code: Vec<u8>) -> Self {
-        //printf("Jump : %X \n",_scriptPtr.pc-res->segBytecode);
-        //FCS Whoever wrote this is patching the bytecode on the fly. This is ballzy !!
-        if part_id == GAME_PART_FIRST {
-            let ip = 0xCB9;
-            // (0x0CB8) condJmp(0x80, VAR(41), VAR(30), 0xCD3)
-            code[ip + 0x00] = 0x81;
-            code[ip + 0x03] = 0x0D;
-            code[ip + 0x04] = 0x24;
-            // (0x0D4E) condJmp(0x4, VAR(50), 6, 0xDBC)
-            code[ip + 0x99] = 0x0D;
-            code[ip + 0x9A] = 0x5A;
-            // printf("VirtualMachine::op_condJmp() bypassing protection");
-            // printf("bytecode has been patched/n");
-
-            //this->bypassProtection() ;
-        }
-
+    pub fn new(id: usize, part_id: u16, code: Vec<u8>) -> Self {
         Self {
             id,
             part_id,
@@ -65,6 +50,96 @@ impl Program {
         self.instructions[self.ip].0
     }
 
+    /// Get the part the program was loaded for.
+    pub fn part_id(&self) -> u16 {
+        self.part_id
+    }
+
+    /// Get the command at the current ip.
+    pub fn command(&self) -> Option<&Command> {
+        self.instructions.get(self.ip).map(|(_, cmd, _)| cmd)
+    }
+
+    /// Get the opcode byte of the current command.
+    pub fn opcode(&self) -> Option<u8> {
+        self.instructions
+            .get(self.ip)
+            .map(|(addr, _, _)| self.code.get_data()[*addr])
+    }
+
+    /// Return addresses of the pending calls, the innermost last.
+    pub fn call_stack(&self) -> Vec<u16> {
+        self.return_stack
+            .iter()
+            .map(|ip| self.ip_addr(*ip))
+            .collect()
+    }
+
+    // Address of the command at `ip`, the end of the code if it's past the last command.
+    fn ip_addr(&self, ip: usize) -> u16 {
+        match self.instructions.get(ip) {
+            Some((addr, _, _)) => *addr as u16,
+            None => self.code.get_data().len() as u16,
+        }
+    }
+
+    /// Disassemble up to `before` commands preceding `addr`, the command at `addr` and up to
+    /// `after` commands following it. If `addr` is not a command boundary the closest
+    /// preceding command is used instead.
+    pub fn disassemble(
+        &self,
+        addr: u16,
+        before: usize,
+        after: usize,
+        symbols: &Symbols,
+    ) -> Vec<(u16, String)> {
+        let ip = self
+            .instructions
+            .partition_point(|(a, _, _)| *a <= addr as usize)
+            .saturating_sub(1);
+        let first = ip.saturating_sub(before);
+        let last = usize::min(ip + after + 1, self.instructions.len());
+
+        self.instructions[first..last]
+            .iter()
+            .map(|(a, cmd, _)| (*a as u16, self.format(cmd, symbols)))
+            .collect()
+    }
+
+    /// Parsed commands with their addresses.
+    pub fn commands(&self) -> impl Iterator<Item = (u16, &Command)> {
+        self.instructions.iter().map(|(a, cmd, _)| (*a as u16, cmd))
+    }
+
+    /// Whether a command starts at `addr`.
+    pub fn is_command_addr(&self, addr: u16) -> bool {
+        self.addr_ip.contains_key(&addr)
+    }
+
+    /// Disassemble the whole bytecode.
+    pub fn listing(&self, symbols: &Symbols) -> Vec<(u16, String)> {
+        self.instructions
+            .iter()
+            .map(|(a, cmd, _)| (*a as u16, self.format(cmd, symbols)))
+            .collect()
+    }
+
+    fn format(&self, cmd: &Command, symbols: &Symbols) -> String {
+        cmd.display(Some(symbols), self.part_id).to_string()
+    }
+
+    fn check_thread_id(&self, addr: usize, thread_id: u8) -> Result<()> {
+        if thread_id as usize >= VM_NUM_THREADS {
+            return Err(VmFault::BadThread {
+                part_id: self.part_id,
+                addr: addr as u16,
+                thread_id: thread_id as u16,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
     pub fn goto_addr(&mut self, addr: u16) -> Result<()> {
         let oip = self.addr_ip.get(&addr);
 
@@ -73,25 +148,36 @@ impl Program {
             self.active = true;
             Ok(())
         } else {
-            let addresses = self
-                .instructions
-                .iter()
-                .map(|(addr, _, _)| format!("{}", addr))
-                .collect::<Vec<_>>()
-                .join(", ");
-
-            print!(
-                "Cannot find command at address {}/0x{:04X}\nAvailable addresses: {}",
-                addr, addr, addresses
-            );
-            Err(anyhow!(
-                "Cannot find command at address {}/0x{:04X}",
+            Err(VmFault::BadAddress {
+                part_id: self.part_id,
                 addr,
-                addr
-            ))
+            }
+            .into())
         }
     }
 
+    /// Continue from the command at `addr` with pending calls returning to `call_stack`, the
+    /// innermost last, as listed by `call_stack`.
+    pub fn resume_at(&mut self, addr: u16, call_stack: &[u16]) -> Result<()> {
+        let end = self.code.get_data().len();
+        let return_stack = call_stack
+            .iter()
+            .map(|ret_addr| match self.addr_ip.get(ret_addr) {
+                Some(ip) => Ok(*ip),
+                None if *ret_addr as usize == end => Ok(self.instructions.len()),
+                None => Err(VmFault::BadAddress {
+                    part_id: self.part_id,
+                    addr: *ret_addr,
+                }),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.goto_addr(addr)?;
+        self.return_stack = return_stack;
+
+        Ok(())
+    }
+
     pub fn is_active(&self) -> bool {
         self.active
     }
@@ -102,8 +188,9 @@ impl Program {
 
         while self.code.can_read() {
             let addr = self.code.pos();
-            let opcode = self.code.read_u8();
-            let cmd = Command::parse(opcode, &mut self.code)?;
+            let opcode = self.code.read_u8()?;
+            let cmd = Command::parse(opcode, &mut self.code)
+                .with_context(|| format!("Bad command at {:#06X}", addr))?;
 
             // println!("{:05X}: {:?}", ip, cmd);
 
@@ -120,221 +207,245 @@ impl Program {
         self.active = true;
     }
 
+    /// Deactivate the current thread as `KillThread` does.
+    pub fn kill(&mut self) {
+        self.active = false;
+    }
+
     pub fn exec(&mut self, ctx: &mut VmContext) -> Result<()> {
         // if !self.active {
         //     return Ok(());
         // }
 
         while !ctx.goto_next_thread {
-            let (addr, cmd, _) = &self.instructions[self.ip];
-            let mut ip_incr = 1;
-
-            // print!("{:04}/{:04X}: {:?}", self.ip, addr, cmd);
-            print!("{:04X}: {:?}", addr, cmd);
+            self.step(ctx)?;
+        }
+        Ok(())
+    }
 
-            match cmd {
-                Command::MovConst { var_id, val } => {
-                    ctx.variables[var_id.0 as usize] = *val as i16;
-                }
-                Command::Mov { dst_id, src_id } => {
-                    ctx.variables[dst_id.0 as usize] = ctx.variables[src_id.0 as usize];
-                    print!(" -> {}", ctx.variables[dst_id.0 as usize]);
-                }
-                Command::Add { dst_id, src_id } => {
-                    ctx.variables[dst_id.0 as usize] = w_add_i16(
-                        ctx.variables[dst_id.0 as usize],
-                        ctx.variables[src_id.0 as usize],
+    /// Execute the command at the current ip and move to the next one.
+    pub fn step(&mut self, ctx: &mut VmContext) -> Result<()> {
+        let (addr, cmd, _) = self.instructions.get(self.ip).ok_or(VmFault::BadAddress {
+            part_id: self.part_id,
+            addr: self.ip_addr(self.ip),
+        })?;
+        let mut ip_incr = 1;
+
+        trace!(
+            DBG_VM,
+            "{:04X}: {}",
+            addr,
+            cmd.display(Some(&ctx.symbols), self.part_id)
+        );
+
+        match cmd {
+            Command::MovConst { var_id, val } => {
+                ctx.variables[var_id.0 as usize] = *val as i16;
+            }
+            Command::Mov { dst_id, src_id } => {
+                ctx.variables[dst_id.0 as usize] = ctx.variables[src_id.0 as usize];
+                trace!(DBG_VM, "  -> {}", ctx.variables[dst_id.0 as usize]);
+            }
+            Command::Add { dst_id, src_id } => {
+                ctx.variables[dst_id.0 as usize] = w_add_i16(
+                    ctx.variables[dst_id.0 as usize],
+                    ctx.variables[src_id.0 as usize],
+                );
+                // ctx.variables[dst_id.0 as usize] += ctx.variables[src_id.0 as usize];
+
+                trace!(DBG_VM, "  -> {}", ctx.variables[dst_id.0 as usize]);
+            }
+            Command::AddConst { var_id, val } => {
+                if self.part_id == 0x3E86 && self.ip == 0x6D48 {
+                    warning!(
+                        "VirtualMachine::op_addConst() hack for non-stop looping gun sound bug"
                     );
-                    // ctx.variables[dst_id.0 as usize] += ctx.variables[src_id.0 as usize];
-
-                    print!(" -> {}", ctx.variables[dst_id.0 as usize]);
+                    // the script 0x27 slot 0x17 doesn't stop the gun sound from looping, I
+                    // don't really know why ; for now, let's play the 'stopping sound' like
+                    // the other scripts do
+                    //  (0x6D43) jmp(0x6CE5)
+                    //  (0x6D46) break
+                    //  (0x6D47) VAR(6) += -50
+                    ctx.play_sound(0x5B, 1, 64, 1)?;
                 }
-                Command::AddConst { var_id, val } => {
-                    if self.part_id == 0x3E86 && self.ip == 0x6D48 {
-                        // warning("VirtualMachine::op_addConst() hack for non-stop looping gun sound bug");
-                        // the script 0x27 slot 0x17 doesn't stop the gun sound from looping, I
-                        // don't really know why ; for now, let's play the 'stopping sound' like
-                        // the other scripts do
-                        //  (0x6D43) jmp(0x6CE5)
-                        //  (0x6D46) break
-                        //  (0x6D47) VAR(6) += -50
-                        ctx.play_sound(0x5B, 1, 64, 1);
-                    }
 
-                    // ctx.variables[var_id.0 as usize] += *val as i16;
-                    ctx.variables[var_id.0 as usize] =
-                        w_add_i16(ctx.variables[var_id.0 as usize], *val as _);
+                // ctx.variables[var_id.0 as usize] += *val as i16;
+                ctx.variables[var_id.0 as usize] =
+                    w_add_i16(ctx.variables[var_id.0 as usize], *val as _);
 
-                    print!(" -> {}", ctx.variables[var_id.0 as usize]);
-                }
-                Command::Call { offset } => {
-                    self.return_stack.push(self.ip + 1); // TODO: use ip instead
+                trace!(DBG_VM, "  -> {}", ctx.variables[var_id.0 as usize]);
+            }
+            Command::Call { offset } => {
+                self.return_stack.push(self.ip + 1); // TODO: use ip instead
+                let off = *offset;
+                self.goto_addr(off)?;
+                ip_incr = 0;
+            }
+            Command::Ret => {
+                self.ip = self.return_stack.pop().ok_or(VmFault::StackUnderflow {
+                    part_id: self.part_id,
+                    addr: *addr as u16,
+                })?; // TODO: use ip instead
+                ip_incr = 0;
+            }
+            Command::PauseThread => ctx.goto_next_thread = true, // TODO: do we need to increase ip or can just return?
+            Command::Jmp { offset } => {
+                let off = *offset;
+                self.goto_addr(off)?;
+                ip_incr = 0;
+            }
+            Command::SetVect { thr_id, offset } => {
+                self.check_thread_id(*addr, *thr_id)?;
+                ctx.threads_data[*thr_id as usize].requested_pc_offset = *offset
+            }
+            Command::Jnz { var_id, offset } => {
+                ctx.variables[var_id.0 as usize] = ctx.variables[var_id.0 as usize].wrapping_sub(1);
+                if ctx.variables[var_id.0 as usize] != 0 {
                     let off = *offset;
                     self.goto_addr(off)?;
                     ip_incr = 0;
+                    trace!(DBG_VM, "  jmp");
                 }
-                Command::Ret => {
-                    self.ip = self
-                        .return_stack
-                        .pop()
-                        .ok_or_else(|| anyhow!("Stack underflow"))?; // TODO: use ip instead
-                    ip_incr = 0;
-                }
-                Command::PauseThread => ctx.goto_next_thread = true, // TODO: do we need to increase ip or can just return?
-                Command::Jmp { offset } => {
+            }
+            Command::CondJmp {
+                jmp_type,
+                var_id,
+                op2,
+                offset,
+            } => {
+                let val1 = ctx.variables[var_id.0 as usize];
+                let val2 = match op2 {
+                    OpType::Var(var2_id) => ctx.variables[*var2_id as usize],
+                    OpType::Val1(val) => *val as i16,
+                    OpType::Val2(val) => *val as i16,
+                };
+                let cond = match jmp_type {
+                    JmpType::Je => val1 == val2,
+                    JmpType::Jne => val1 != val2,
+                    JmpType::Jg => val1 > val2,
+                    JmpType::Jge => val1 >= val2,
+                    JmpType::Jl => val1 < val2,
+                    JmpType::Jle => val1 <= val2,
+                    JmpType::Unknown(jt) => bail!(AwbiError::bad_data(format!(
+                        "Part {:#06X}: invalid jmp opcode {} at {:#06X}",
+                        self.part_id, jt, addr
+                    ))),
+                };
+
+                if cond {
+                    trace!(DBG_VM, "  -> {} ~ {} jmp", val1, val2);
                     let off = *offset;
                     self.goto_addr(off)?;
                     ip_incr = 0;
+                } else {
+                    trace!(DBG_VM, "  -> {} ~ {}", val1, val2);
                 }
-                Command::SetVect { thr_id, offset } => {
-                    ctx.threads_data[*thr_id as usize].requested_pc_offset = *offset
-                }
-                Command::Jnz { var_id, offset } => {
-                    ctx.variables[var_id.0 as usize] -= 1;
-                    if ctx.variables[var_id.0 as usize] != 0 {
-                        let off = *offset;
-                        self.goto_addr(off)?;
-                        ip_incr = 0;
-                        print!(" jmp");
+            }
+            Command::SetPalette { pal_id } => ctx.video.palette_id_requested = (*pal_id >> 8) as u8,
+            Command::ResetThread {
+                reset_type,
+                first,
+                last,
+            } => {
+                self.check_thread_id(*addr, *last)?;
+                if *reset_type == ResetType::Delete {
+                    for i in *first..=*last {
+                        // TODO: fix magic numbers
+                        ctx.threads_data[i as usize].requested_pc_offset = 0xFFFE;
                     }
-                }
-                Command::CondJmp {
-                    jmp_type,
-                    var_id,
-                    op2,
-                    offset,
-                } => {
-                    let val1 = ctx.variables[var_id.0 as usize];
-                    let val2 = match op2 {
-                        OpType::Var(var2_id) => ctx.variables[*var2_id as usize],
-                        OpType::Val1(val) => *val as i16,
-                        OpType::Val2(val) => *val as i16,
-                    };
-                    let cond = match jmp_type {
-                        JmpType::Je => val1 == val2,
-                        JmpType::Jne => val1 != val2,
-                        JmpType::Jg => val1 > val2,
-                        JmpType::Jge => val1 >= val2,
-                        JmpType::Jl => val1 < val2,
-                        JmpType::Jle => val1 <= val2,
-                        JmpType::Unknown(_) => false, // NOTE: we should not come here
-                    };
-
-                    if cond {
-                        print!(" -> {} ~ {} jmp", val1, val2);
-                        let off = *offset;
-                        self.goto_addr(off)?;
-                        ip_incr = 0;
-                    } else {
-                        print!(" -> {} ~ {}", val1, val2);
+                } else {
+                    let state_active = *reset_type == ResetType::Unfreeze;
+                    for i in *first..=*last {
+                        ctx.threads_data[i as usize].requested_state_active = state_active;
                     }
                 }
-                Command::SetPalette { pal_id } => {
-                    ctx.video.palette_id_requested = (*pal_id >> 8) as u8
-                }
-                Command::ResetThread {
-                    reset_type,
-                    first,
-                    last,
-                } => {
-                    if *reset_type == ResetType::Delete {
-                        for i in *first..=*last {
-                            // TODO: fix magic numbers
-                            ctx.threads_data[i as usize].requested_pc_offset = 0xFFFE;
-                        }
-                    } else {
-                        let state_active = *reset_type == ResetType::Unfreeze;
-                        for i in *first..=*last {
-                            ctx.threads_data[i as usize].requested_state_active = state_active;
-                        }
-                    }
-                }
-                Command::SelectVideoPage { page_id } => {
-                    ctx.video.change_page_off1(*page_id as usize)
-                }
-                Command::FillVideoPage { page_id, color } => {
-                    ctx.video.fill_page(*page_id as usize, *color)
-                }
-                Command::CopyVideoPage {
-                    src_page_id,
-                    dst_page_id,
-                } => ctx.video.copy_page(
-                    *src_page_id as usize,
-                    *dst_page_id as usize,
-                    ctx.variables[VM_VARIABLE_SCROLL_Y],
-                ),
-                Command::BlitFramebuffer { page_id } => ctx.blit_framebuffer(*page_id as usize)?,
-                Command::KillThread => {
-                    self.active = false;
-                    ctx.goto_next_thread = true;
-                }
-                Command::DrawString {
-                    str_id,
-                    x,
-                    y,
-                    color,
-                } => ctx.video.draw_string(*color, *x as u16, *y as u16, *str_id),
-                Command::Sub { dst_id, src_id } => {
-                    ctx.variables[dst_id.0 as usize] -= ctx.variables[src_id.0 as usize]
-                }
-                Command::And { var_id, val } => {
-                    ctx.variables[var_id.0 as usize] &= *val as i16;
-                    print!(" -> {}", ctx.variables[var_id.0 as usize]);
-                }
-                Command::Or { var_id, val } => ctx.variables[var_id.0 as usize] |= *val as i16,
-                Command::Shl { var_id, val } => ctx.variables[var_id.0 as usize] <<= *val,
-                Command::Shr { var_id, val } => ctx.variables[var_id.0 as usize] >>= *val,
-                Command::PlaySound {
-                    res_id,
-                    freq,
-                    vol,
-                    channel,
-                } => ctx.play_sound(*res_id, *freq, *vol, *channel),
-                Command::UpdateMemList { res_id } => ctx.update_mem_list(*res_id)?,
-                Command::PlayMusic { res_id, delay, pos } => {
-                    ctx.play_music(*res_id, *delay, *pos)?
-                }
-                Command::Video1 { offset, x, y } => {
-                    ctx.video.set_data_page(true, *offset);
-                    ctx.video.read_and_draw_polygon(
-                        COLOR_BLACK,
-                        DEFAULT_ZOOM,
-                        Point::new(*x as i16, *y as i16),
-                    );
-                }
-                Command::Video2 {
-                    cinematic,
-                    offset,
-                    x,
-                    y,
-                    zoom,
-                } => {
-                    let x_val = match x {
-                        OpType::Var(var_id) => ctx.variables[*var_id as usize],
-                        OpType::Val1(val) => *val as i16,
-                        OpType::Val2(val) => *val as i16,
-                    };
-                    let y_val = match y {
-                        OpType::Var(var_id) => ctx.variables[*var_id as usize],
-                        OpType::Val1(val) => *val as i16,
-                        OpType::Val2(val) => *val as i16,
-                    };
-                    let zoom_val = match zoom {
-                        OpType::Var(var_id) => ctx.variables[*var_id as usize] as u16,
-                        OpType::Val1(val) => *val as u16,
-                        OpType::Val2(val) => *val,
-                    };
-
-                    ctx.video.set_data_page(*cinematic, *offset);
-                    ctx.video
-                        .read_and_draw_polygon(0xFF, zoom_val, Point::new(x_val, y_val));
-                }
             }
-
-            println!("");
-
-            self.ip += ip_incr;
+            Command::SelectVideoPage { page_id } => ctx.video.change_page_off1(*page_id as usize),
+            Command::FillVideoPage { page_id, color } => {
+                ctx.video.fill_page(*page_id as usize, *color)
+            }
+            Command::CopyVideoPage {
+                src_page_id,
+                dst_page_id,
+            } => ctx.video.copy_page(
+                *src_page_id as usize,
+                *dst_page_id as usize,
+                ctx.variables[VM_VARIABLE_SCROLL_Y],
+            ),
+            Command::BlitFramebuffer { page_id } => ctx.blit_framebuffer(*page_id as usize)?,
+            Command::KillThread => {
+                self.active = false;
+                ctx.goto_next_thread = true;
+            }
+            Command::DrawString {
+                str_id,
+                x,
+                y,
+                color,
+            } => ctx.video.draw_string(*color, *x as u16, *y as u16, *str_id),
+            Command::Sub { dst_id, src_id } => {
+                ctx.variables[dst_id.0 as usize] =
+                    ctx.variables[dst_id.0 as usize].wrapping_sub(ctx.variables[src_id.0 as usize])
+            }
+            Command::And { var_id, val } => {
+                ctx.variables[var_id.0 as usize] &= *val as i16;
+                trace!(DBG_VM, "  -> {}", ctx.variables[var_id.0 as usize]);
+            }
+            Command::Or { var_id, val } => ctx.variables[var_id.0 as usize] |= *val as i16,
+            Command::Shl { var_id, val } => {
+                let var = &mut ctx.variables[var_id.0 as usize];
+                *var = var.wrapping_shl(*val as u32)
+            }
+            Command::Shr { var_id, val } => {
+                let var = &mut ctx.variables[var_id.0 as usize];
+                *var = var.wrapping_shr(*val as u32)
+            }
+            Command::PlaySound {
+                res_id,
+                freq,
+                vol,
+                channel,
+            } => ctx.play_sound(*res_id, *freq, *vol, *channel)?,
+            Command::UpdateMemList { res_id } => ctx.update_mem_list(*res_id)?,
+            Command::PlayMusic { res_id, delay, pos } => ctx.play_music(*res_id, *delay, *pos)?,
+            Command::Video1 { offset, x, y } => {
+                ctx.video.set_data_page(true, *offset)?;
+                ctx.video.read_and_draw_polygon(
+                    COLOR_BLACK,
+                    DEFAULT_ZOOM,
+                    Point::new(*x as i16, *y as i16),
+                )?;
+            }
+            Command::Video2 {
+                cinematic,
+                offset,
+                x,
+                y,
+                zoom,
+            } => {
+                let x_val = match x {
+                    OpType::Var(var_id) => ctx.variables[*var_id as usize],
+                    OpType::Val1(val) => *val as i16,
+                    OpType::Val2(val) => *val as i16,
+                };
+                let y_val = match y {
+                    OpType::Var(var_id) => ctx.variables[*var_id as usize],
+                    OpType::Val1(val) => *val as i16,
+                    OpType::Val2(val) => *val as i16,
+                };
+                let zoom_val = match zoom {
+                    OpType::Var(var_id) => ctx.variables[*var_id as usize] as u16,
+                    OpType::Val1(val) => *val as u16,
+                    OpType::Val2(val) => *val,
+                };
+
+                ctx.video.set_data_page(*cinematic, *offset)?;
+                ctx.video
+                    .read_and_draw_polygon(0xFF, zoom_val, Point::new(x_val, y_val))?;
+            }
         }
+
+        self.ip += ip_incr;
         Ok(())
     }
 }
@@ -358,21 +469,83 @@ impl fmt::Debug for Program {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{memlist::ResType, resource::Resource, storage::Storage, util::*};
+    use crate::{
+        memlist::ResType,
+        profiler::Profiler,
+        reference::Ref,
+        resource::Resource,
+        storage::Storage,
+        system::HeadlessSystem,
+        util::*,
+        vfs::{self, MemVfs},
+    };
     use anyhow::Result;
     use std::fs::File;
     use std::io::prelude::*;
 
+    // Execute the first command of `code` with the variables `vars` set, return the variables
+    // and the ip reached.
+    fn step(code: &[u8], vars: &[(usize, i16)]) -> Result<([i16; 256], usize)> {
+        let res = Resource::new(Storage::new(Box::new(MemVfs::default())));
+        let mut ctx = VmContext::new(
+            Ref::new(Box::new(HeadlessSystem::default())),
+            Ref::new(Box::new(res)),
+            Ref::new(Box::new(Profiler::default())),
+        );
+        for (var_id, val) in vars {
+            ctx.variables[*var_id] = *val;
+        }
+
+        let mut prog = Program::new(0, 0x3E80, code.to_vec());
+        prog.parse()?;
+        prog.start();
+        prog.step(&mut ctx)?;
+
+        Ok((ctx.variables, prog.ip))
+    }
+
+    #[test]
+    fn test_jnz_wraps() -> Result<()> {
+        // jnz v1, 0x0000
+        let (vars, ip) = step(&[0x09, 0x01, 0x00, 0x00], &[(1, i16::MIN)])?;
+        assert_eq!(vars[1], i16::MAX);
+        assert_eq!(ip, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sub_wraps() -> Result<()> {
+        // sub v2, v3
+        let (vars, _) = step(&[0x13, 0x02, 0x03], &[(2, i16::MIN), (3, 1)])?;
+        assert_eq!(vars[2], i16::MAX);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_shifts_wrap() -> Result<()> {
+        // shl v4, 17
+        let (vars, _) = step(&[0x16, 0x04, 0x00, 0x11], &[(4, 1)])?;
+        assert_eq!(vars[4], 2);
+
+        // shr v5, 16
+        let (vars, _) = step(&[0x17, 0x05, 0x00, 0x10], &[(5, -4)])?;
+        assert_eq!(vars[5], -4);
+
+        Ok(())
+    }
+
     // cargo test test_all_progs -- --nocapture
 
     #[test]
     fn test_all_progs() -> Result<()> {
-        let proj_dir = proj_dir()?;
-        let data_dir: String = data_dir()?.to_str().unwrap().into();
-        let storage = Storage::new(&data_dir);
+        let data_dir = fixture_dir()?;
+        let storage = Storage::new(vfs::open(&data_dir));
         let mut res = Resource::new(storage);
 
         res.init()?;
+        res.storage.load_all()?;
 
         let mut program_id = 0;
         for (i, me) in res
@@ -394,7 +567,7 @@ mod tests {
             prog.parse()?;
 
             let file_name = format!("resource-0x{:02x}.asm", i);
-            let mut file = File::create(proj_dir.join(file_name))?;
+            let mut file = File::create(fixture_dir()?.join(file_name))?;
             file.write_all(format!("{:?}", prog).as_bytes())?;
 
             program_id += 1;
@@ -1,11 +1,15 @@
+use crate::error::AwbiError;
 use crate::file::File;
+use crate::font::Font;
+use crate::integrity;
+use crate::logging::{DBG_BANK, DBG_INFO};
 use crate::memlist::*;
 use crate::parts::*;
 use crate::reference::*;
+use crate::strings::StringTable;
+use crate::variant::Variant;
 use crate::{serializer::*, storage::Storage};
-use anyhow::{ensure, Result};
-
-trace::init_depth_var!();
+use anyhow::{bail, ensure, Result};
 
 const MEM_BLOCK_SIZE: usize = 600 * 1024; //600kb total memory consumed (not taking into account stack and static heap)
 
@@ -113,6 +117,10 @@ pub(crate) struct Resource {
     pub requested_next_part: Option<u16>,
     pub mem_buf: [u8; MEM_BLOCK_SIZE],
     data: ResourceData,
+    variant: Variant,
+    bypass_protection: bool,
+    strings: Option<StringTable>,
+    font: Font,
 }
 
 impl Resource {
@@ -122,6 +130,10 @@ impl Resource {
             requested_next_part: None,
             mem_buf: [0; MEM_BLOCK_SIZE],
             data: Default::default(),
+            variant: Variant::default(),
+            bypass_protection: false,
+            strings: None,
+            font: Font::default(),
         }
     }
 
@@ -163,13 +175,11 @@ impl Resource {
         &self.mem_buf[offset..offset + size]
     }
 
-    // #[trace]
     pub fn from_mem_u8(&self, _page_idx: usize, offset: usize) -> u8 {
         // self.mem_entries[page_idx].from_buf_u8(offset)
         self.mem_buf[offset]
     }
 
-    // #[trace]
     pub fn from_mem_be_u16(&self, _page_idx: usize, offset: usize) -> u16 {
         // self.mem_entries[page_idx].from_buf_be_u16(offset)
         let b1 = self.mem_buf[offset];
@@ -178,68 +188,139 @@ impl Resource {
         u16::from_be_bytes([b1, b2])
     }
 
-    pub fn get_entry_data(&self, id: usize) -> &[u8] {
-        &self.storage.mem_list.entries[id].buffer
+    /// Data of a loaded entry.
+    pub fn get_entry_data(&self, id: usize) -> Result<&[u8]> {
+        let me = self.storage.mem_list.entries.get(id).ok_or_else(|| {
+            AwbiError::bad_data(format!("Resource {:#X} is not in the memlist", id))
+        })?;
+
+        Ok(&me.buffer)
+    }
+
+    /// Data of any entry, unpacked first if needed.
+    pub fn entry_data(&mut self, id: usize) -> Result<&[u8]> {
+        self.storage.load_entry(id)?;
+        Ok(&self.storage.mem_list.entries[id].buffer)
+    }
+
+    pub fn read_palette(&self, offset: usize, size: usize) -> Result<&[u8]> {
+        let data = self.get_entry_data(self.data.seg_palette_idx)?;
+        let palette = data.get(offset..offset + size).ok_or_else(|| {
+            AwbiError::bad_data(format!(
+                "Palette at {:#X} is out of the {} bytes of resource {:#X}",
+                offset,
+                data.len(),
+                self.data.seg_palette_idx
+            ))
+        })?;
+
+        Ok(palette)
+    }
+
+    /// Variant of the game data, known after `init`.
+    pub fn variant(&self) -> &Variant {
+        &self.variant
+    }
+
+    /// Patch the bytecode of the retail data to skip the protection screen, to set before
+    /// `init`.
+    pub fn set_bypass_protection(&mut self, bypass: bool) {
+        self.bypass_protection = bypass;
+    }
+
+    /// Translation drawn instead of the strings of the release, `None` for the original ones.
+    pub fn set_strings(&mut self, strings: Option<StringTable>) {
+        self.strings = strings;
+    }
+
+    /// Text of the string `str_id`, from the translation if it has it.
+    pub fn string(&self, str_id: u16) -> Option<&str> {
+        self.strings
+            .as_ref()
+            .and_then(|strings| strings.get(str_id))
+            .or_else(|| self.variant.strings.get(&str_id).copied())
+    }
+
+    pub fn font(&self) -> &Font {
+        &self.font
     }
 
-    // #[trace]
-    pub fn read_palette(&self, offset: usize, size: usize) -> &[u8] {
-        self.mem_to_slice(self.data.seg_palette_idx + offset, size)
+    pub fn set_font(&mut self, font: Font) {
+        self.font = font;
     }
 
     // Read all entries from memlist.bin. Do not load anything in memory,
     // this is just a fast way to access the data later based on their id.
-    // #[trace]
+    // The banks are read when an entry is loaded.
     pub fn init(&mut self) -> Result<()> {
-        self.storage.load()
+        let report = integrity::check(self.storage.vfs())?;
+        report.ensure_ok()?;
+        debug!(DBG_INFO, "Game data release: {}", report.release);
+
+        self.storage.load()?;
+        self.variant = Variant::new(report.release, self.bypass_protection);
+
+        Ok(())
     }
 
-    // #[trace]
     fn load_marked_as_needed(&mut self) -> Result<()> {
-        loop {
-            if let Some(me) = &mut self.storage.get_max_rank_entry_to_load() {
-                if me.bank_id == 0 {
-                    // warning("Resource::load() ec=0x%X (me->bankId == 0)", 0xF00);
-                    me.state = MemEntryState::NotNeeded;
-                } else {
-                    // debug(DBG_BANK, "Resource::load() bufPos=%X size=%X type=%X pos=%X bankId=%X", loadDestination - _memPtrStart, me->packedSize, me->type, me->bankOffset, me->bankId);
-                    let data = me.read_bank();
-                    if me.res_type == ResType::PolyAnim {
-                        // self.mem_entries[self.storage.seg_video2_idx]
-                        //     .from_slice(&data, self.storage.vid_cur_off);
-                        // self.video.copy_page_data(&data); // TODO: uncomment
-                        me.state = MemEntryState::NotNeeded;
-                        todo!(); // TODO:
-                    } else {
-                        if me.size as usize > self.data.vid_bak_off - self.data.script_cur_off {
-                            // warning("Resource::load() not enough memory");
-                            me.state = MemEntryState::NotNeeded;
-                            continue;
-                        }
-                        // self.mem_entries[self.storage.seg_code_idx]
-                        //     .from_slice(&data, self.storage.script_cur_off);
-                        let off = me.buf_offset as usize;
-                        self.mem_buf[off..off + data.len()].copy_from_slice(&data);
-                        me.buffer = data.into();
-                        me.state = MemEntryState::Loaded;
-                        self.data.script_cur_off += me.size as usize;
-                    }
-                }
-            } else {
-                break; // no entry found
+        while let Some(idx) = self.storage.max_rank_entry_to_load() {
+            let me = self.storage.mem_list.entry_mut(idx)?;
+            if me.bank_id == 0 {
+                warning!("Resource::load() ec={:#X} (me.bank_id == 0)", 0xF00);
+                me.state = MemEntryState::NotNeeded;
+                continue;
             }
+
+            debug!(
+                DBG_BANK,
+                "Resource::load() buf_pos={:X} size={:X} type={:?} pos={:X} bank_id={:X}",
+                me.buf_offset,
+                me.packed_size,
+                me.res_type,
+                me.bank_offset,
+                me.bank_id
+            );
+            if me.res_type == ResType::PolyAnim {
+                // self.mem_entries[self.storage.seg_video2_idx]
+                //     .from_slice(&data, self.storage.vid_cur_off);
+                // self.video.copy_page_data(&data); // TODO: uncomment
+                me.state = MemEntryState::NotNeeded;
+                bail!(AwbiError::unsupported("Resource::load() bitmap resources"));
+            }
+            if me.size as usize > self.data.vid_bak_off - self.data.script_cur_off {
+                warning!("Resource::load() not enough memory");
+                me.state = MemEntryState::NotNeeded;
+                continue;
+            }
+
+            self.storage.load_entry(idx)?;
+
+            // self.mem_entries[self.storage.seg_code_idx]
+            //     .from_slice(&data, self.storage.script_cur_off);
+            let me = self.storage.mem_list.entry_mut(idx)?;
+            let data = me.read_bank();
+            let off = me.buf_offset as usize;
+            ensure!(
+                off + data.len() <= MEM_BLOCK_SIZE,
+                AwbiError::bad_data(format!(
+                    "Resource::load() entry at {:#X} doesn't fit in memory",
+                    off
+                ))
+            );
+            self.mem_buf[off..off + data.len()].copy_from_slice(data);
+            me.state = MemEntryState::Loaded;
+            self.data.script_cur_off += me.size as usize;
         }
 
         Ok(())
     }
 
-    // #[trace]
     pub fn invalidate_res(&mut self) {
         self.storage.mem_list.invalidate_res();
         self.data.script_cur_off = self.data.script_bak_off;
     }
 
-    // #[trace]
     fn invalidate_all(&mut self) {
         self.storage.mem_list.invalidate_all();
         self.data.script_cur_off = 0;
@@ -252,12 +333,11 @@ impl Resource {
 
     // This is decided based on the resourceId. If it does not match a mementry id it is supposed to
     // be a part id.
-    // #[trace]
     pub fn load_parts_or_mem_entry(&mut self, resource_id: u16) -> Result<()> {
-        if resource_id as usize > self.storage.mem_list.entries.len() {
+        if resource_id as usize >= self.storage.mem_list.entries.len() {
             self.requested_next_part = Some(resource_id);
         } else {
-            let mut me = &mut self.storage.mem_list.entries[resource_id as usize];
+            let me = &mut self.storage.mem_list.entries[resource_id as usize];
 
             if me.state == MemEntryState::NotNeeded {
                 me.state = MemEntryState::LoadMe;
@@ -271,35 +351,42 @@ impl Resource {
     // so _memList[video2Index] is never loaded for those parts of the game. When
     // needed (for action phrases) _memList[video2Index] is always loaded with 0x11
     // (as seen in memListParts).
-    // #[trace]
     pub fn setup_part(&mut self, part_id: u16) -> Result<()> {
         if part_id == self.data.current_part_id {
             return Ok(());
         }
 
-        ensure!(
-            part_id >= GAME_PART_FIRST && part_id <= GAME_PART_LAST,
-            "Resource::setupPart() ec={} invalid partId",
-            part_id
-        );
+        let part = *self.variant.part(part_id).ok_or_else(|| {
+            AwbiError::bad_data(format!(
+                "Resource::setup_part() ec={} invalid part_id",
+                part_id
+            ))
+        })?;
 
         let part_idx = (part_id - GAME_PART_FIRST) as usize;
-        let palette_idx = MEM_LIST_PARTS[part_idx][MEMLIST_PART_PALETTE] as usize;
-        let code_idx = MEM_LIST_PARTS[part_idx][MEMLIST_PART_CODE] as usize;
-        let video_cinematic_idx = MEM_LIST_PARTS[part_idx][MEMLIST_PART_POLY_CINEMATIC] as usize;
-        let video2_idx = MEM_LIST_PARTS[part_idx][MEMLIST_PART_VIDEO2] as usize;
+        let palette_idx = part[MEMLIST_PART_PALETTE] as usize;
+        let code_idx = part[MEMLIST_PART_CODE] as usize;
+        let video_cinematic_idx = part[MEMLIST_PART_POLY_CINEMATIC] as usize;
+        let video2_idx = part[MEMLIST_PART_VIDEO2] as usize;
+
+        // A part referring to entries the memlist doesn't have is bad data, check them all
+        // before anything is invalidated.
+        let res_types = [palette_idx, code_idx, video_cinematic_idx, video2_idx]
+            .iter()
+            .map(|&idx| Ok(self.storage.mem_list.entry_mut(idx)?.res_type))
+            .collect::<Result<Vec<_>>>()?;
 
         // Mark all resources as located on hard drive.
         self.invalidate_all();
 
-        self.storage.mem_list.entries[palette_idx].state = MemEntryState::LoadMe;
-        self.storage.mem_list.entries[code_idx].state = MemEntryState::LoadMe;
-        self.storage.mem_list.entries[video_cinematic_idx].state = MemEntryState::LoadMe;
+        self.storage.mem_list.entry_mut(palette_idx)?.state = MemEntryState::LoadMe;
+        self.storage.mem_list.entry_mut(code_idx)?.state = MemEntryState::LoadMe;
+        self.storage.mem_list.entry_mut(video_cinematic_idx)?.state = MemEntryState::LoadMe;
 
         // This is probably a cinematic or a non interactive part of the game.
         // Player and enemy polygons are not needed.
         if video2_idx != MEMLIST_PART_NONE {
-            self.storage.mem_list.entries[video2_idx].state = MemEntryState::LoadMe;
+            self.storage.mem_list.entry_mut(video2_idx)?.state = MemEntryState::LoadMe;
         }
 
         self.load_marked_as_needed()?;
@@ -314,25 +401,15 @@ impl Resource {
             self.data.seg_video2_idx = video2_idx;
         }
 
-        println!("\tpart_idx={}", part_idx);
-        println!(
-            "\tpalette_idx={} {:?}",
-            palette_idx, self.storage.mem_list.entries[palette_idx].res_type
-        );
-        println!(
-            "\tcode_idx={} {:?}",
-            code_idx, self.storage.mem_list.entries[code_idx].res_type
+        debug!(DBG_BANK, "Resource::setup_part() part_idx={}", part_idx);
+        debug!(DBG_BANK, "\tpalette_idx={} {:?}", palette_idx, res_types[0]);
+        debug!(DBG_BANK, "\tcode_idx={} {:?}", code_idx, res_types[1]);
+        debug!(
+            DBG_BANK,
+            "\tvideo_cinematic_idx={} {:?}", video_cinematic_idx, res_types[2]
         );
-        println!(
-            "\tvideo_cinematic_idx={} {:?}",
-            video_cinematic_idx, self.storage.mem_list.entries[video_cinematic_idx].res_type
-        );
-
         if video2_idx != MEMLIST_PART_NONE {
-            println!(
-                "\tvideo2_idx={} {:?}",
-                video2_idx, self.storage.mem_list.entries[video2_idx].res_type
-            );
+            debug!(DBG_BANK, "\tvideo2_idx={} {:?}", video2_idx, res_types[3]);
         }
 
         self.data.current_part_id = part_id;
@@ -343,7 +420,6 @@ impl Resource {
         Ok(())
     }
 
-    // #[trace]
     pub fn reset_mem_block(&mut self) {
         self.mem_buf = [0; MEM_BLOCK_SIZE]; // TODO: faster cleanup?
         self.data.script_bak_off = 0;
@@ -352,6 +428,47 @@ impl Resource {
         self.data.vid_cur_off = self.data.vid_bak_off;
     }
 
+    // Part, resource indices and memory offsets restored from a savestate must match the game
+    // data and the memory block.
+    fn check_data(&self) -> Result<()> {
+        let data = &self.data;
+        let num_entries = self.storage.mem_list.entries.len();
+
+        ensure!(
+            self.variant.part(data.current_part_id).is_some(),
+            AwbiError::bad_data(format!(
+                "Savestate part {:#X} is unknown",
+                data.current_part_id
+            ))
+        );
+
+        let segments = [
+            ("palette", data.seg_palette_idx),
+            ("code", data.seg_code_idx),
+            ("cinematic", data.seg_cinematic_idx),
+            ("video2", data.seg_video2_idx),
+        ];
+        for (name, idx) in segments.iter() {
+            ensure!(
+                *idx < num_entries,
+                AwbiError::bad_data(format!(
+                    "Savestate {} resource {:#X} is not in the memlist",
+                    name, idx
+                ))
+            );
+        }
+
+        ensure!(
+            data.script_bak_off <= data.script_cur_off
+                && data.script_cur_off <= data.vid_bak_off
+                && data.vid_bak_off <= MEM_BLOCK_SIZE
+                && data.vid_cur_off <= MEM_BLOCK_SIZE,
+            AwbiError::bad_data("Savestate memory offsets are out of the memory block")
+        );
+
+        Ok(())
+    }
+
     pub fn save_or_load(&mut self, ser: &mut Serializer) -> Result<()> {
         if ser.mode() == Mode::Save {
             let mut ll_idx = 0;
@@ -375,15 +492,28 @@ impl Resource {
         ser.save_or_load_entries(&mut self.data, Ver(1))?;
 
         if ser.mode() == Mode::Load {
+            self.check_data()?;
+
             let mut mem_buf_idx = 0;
 
-            for me in &mut self.storage.mem_list.entries {
+            for &i in self.data.loaded_list.iter().take_while(|&&i| i != 0) {
+                ensure!(
+                    (i as usize) < self.storage.mem_list.entries.len(),
+                    AwbiError::bad_data(format!("Savestate refers to unknown resource {}", i))
+                );
+                self.storage.load_entry(i as usize)?;
+
+                let me = self.storage.mem_list.entry_mut(i as usize)?;
                 let buf = me.read_bank();
-                // me.buf_offset = self.mem_buf.len(); // TODO: uncomment
-                self.mem_buf[mem_buf_idx..mem_buf_idx + buf.len()].copy_from_slice(&buf); // TODO: optimize by reading in read_bank into the slice instead of returning vec
-                                                                                          // me.buffer = buf;
-                mem_buf_idx += me.size as usize;
-                todo!();
+
+                ensure!(
+                    mem_buf_idx + buf.len() <= MEM_BLOCK_SIZE,
+                    AwbiError::bad_data("Savestate resources don't fit in memory")
+                );
+                self.mem_buf[mem_buf_idx..mem_buf_idx + buf.len()].copy_from_slice(buf); // TODO: optimize by reading in read_bank into the slice instead of returning vec
+                me.buf_offset = mem_buf_idx;
+                me.state = MemEntryState::Loaded;
+                mem_buf_idx += me.size;
             }
         }
 
@@ -406,30 +536,60 @@ impl std::fmt::Debug for Resource {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::util::data_dir;
+    use crate::{
+        util::{data_dir, fixture_dir},
+        vfs,
+    };
 
     #[test]
     // #[ignore]
     fn test_read_entries() -> Result<()> {
-        let data_dir = data_dir()?;
-        let storage = Storage::new(data_dir.to_str().unwrap());
+        let data_dir = fixture_dir()?;
+        let storage = Storage::new(vfs::open(&data_dir));
         let mut res = Resource::new(storage);
 
         res.init()?;
 
         // println!("Entries:\n{:?}\nLen: {}", res.mem_entries, res.mem_entries.len());
 
+        // A translation falls back to the strings of the release.
+        let english = res.string(0x002).map(str::to_owned);
+        res.set_strings(Some(StringTable::from_toml(
+            "[strings]\n0x001 = \"C A C A H U E T E  3000\"",
+        )?));
+        assert_eq!(res.string(0x001), Some("C A C A H U E T E  3000"));
+        assert_eq!(res.string(0x002), english.as_deref());
+        assert_eq!(res.string(0xFFF), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_short_memlist() -> Result<()> {
+        let storage = Storage::new(vfs::open(fixture_dir()?));
+        let mut res = Resource::new(storage);
+
+        res.init()?;
+        res.storage.mem_list.entries.truncate(0x18);
+
+        // The code of the intro is missing.
+        let err = res.setup_part(GAME_PART2).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<AwbiError>(),
+            Some(AwbiError::BadData(_))
+        ));
+
         Ok(())
     }
 
     #[test]
     #[ignore]
     fn test_read_all_banks() -> Result<()> {
-        let data_dir: String = data_dir()?.to_str().unwrap().into();
-        let storage = Storage::new(&data_dir);
+        let storage = Storage::new(vfs::open(data_dir()?));
         let mut res = Resource::new(storage);
 
         res.init()?;
+        res.storage.load_all()?;
 
         for me in res.storage.mem_list.entries {
             println!(
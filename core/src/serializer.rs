@@ -1,4 +1,5 @@
 use crate::file::File;
+use crate::logging::DBG_SER;
 use anyhow::Result;
 
 #[derive(PartialEq, PartialOrd, Debug)]
@@ -195,6 +196,10 @@ impl Serializer {
         }
     }
 
+    pub fn into_stream(self) -> File {
+        self.stream
+    }
+
     pub fn mode(&self) -> Mode {
         self.mode
     }
@@ -204,12 +209,18 @@ impl Serializer {
         accessor: &mut impl AccessorWrap,
         min_ver: Ver,
     ) -> Result<()> {
-        // debug(DBG_SER, "Serializer::saveOrLoadEntries() _mode=%d", _mode);
+        debug!(
+            DBG_SER,
+            "Serializer::save_or_load_entries() mode={:?}", self.mode
+        );
         if self.mode == Mode::Save || self.save_ver >= min_ver && self.save_ver <= CUR_VER {
             accessor.access(self.mode, &mut self.stream)?;
             self.bytes_count = accessor.size() as u32;
         }
-        // debug(DBG_SER, "Serializer::saveOrLoadEntries() _bytesCount=%d", _bytesCount);
+        debug!(
+            DBG_SER,
+            "Serializer::save_or_load_entries() bytes_count={}", self.bytes_count
+        );
         Ok(())
     }
 }
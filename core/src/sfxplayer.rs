@@ -1,3 +1,5 @@
+use crate::error::AwbiError;
+use crate::logging::DBG_SND;
 use crate::memlist::*;
 use crate::mixer::*;
 use crate::reference::*;
@@ -5,7 +7,7 @@ use crate::resource::*;
 use crate::serializer::*;
 use crate::system::*;
 use crate::{file::*, slice_reader::SliceReader};
-use anyhow::{bail, Result};
+use anyhow::{bail, ensure, Result};
 
 #[derive(Clone, Default)]
 struct SfxInstrument {
@@ -78,7 +80,8 @@ pub(crate) struct SfxPlayer {
     delay: u16,
     res_id: u16,
     sfx_mod: SfxModule,
-    pub mark_var: Vec<i16>,
+    /// Music mark reached, not yet copied to `VM_VARIABLE_MUS_MARK`.
+    pub mark: Option<i16>,
 }
 
 impl TimerHandler for SfxPlayer {
@@ -99,7 +102,7 @@ impl SfxPlayer {
             delay: 0,
             res_id: 0,
             sfx_mod: Default::default(),
-            mark_var: Vec::new(),
+            mark: None,
         }
     }
 
@@ -113,25 +116,44 @@ impl SfxPlayer {
     }
 
     pub fn set_events_delay(&mut self, delay: u16) {
-        // debug(DBG_SND, "SfxPlayer::setEventsDelay(%d)", delay);
+        debug!(DBG_SND, "SfxPlayer::set_events_delay({})", delay);
         let _ = MutexStack::new(self.sys.clone(), &self.mutex);
         self.delay = delay * 60 / 7050;
     }
 
     pub fn load_sfx_module(&mut self, res_id: u16, delay: u16, pos: u8) -> Result<()> {
-        // debug(DBG_SND, "SfxPlayer::loadSfxModule(0x%X, %d, %d)", resNum, delay, pos);
+        debug!(
+            DBG_SND,
+            "SfxPlayer::load_sfx_module({:#X}, {}, {})", res_id, delay, pos
+        );
         let _ = MutexStack::new(self.sys.clone(), &self.mutex);
 
         // to avoid borrow checker complain
-        let src_me = &self.res.get().storage.mem_list.entries[res_id as usize];
+        let res = self.res.get();
+        let src_me = res
+            .storage
+            .mem_list
+            .entries
+            .get(res_id as usize)
+            .ok_or_else(|| AwbiError::bad_data(format!("Unknown music module {:#X}", res_id)))?;
         let mut me_offset = src_me.buf_offset as usize;
 
         if src_me.state == MemEntryState::Loaded && src_me.res_type == ResType::Music {
+            ensure!(
+                src_me.buffer.len() >= me_offset + 0xC0,
+                AwbiError::bad_data(format!("Music module {:#X} is too short", res_id))
+            );
+
             self.res_id = res_id;
             self.sfx_mod = Default::default();
             self.sfx_mod.cur_order = pos;
             self.sfx_mod.num_order = src_me.from_buf_be_u16(me_offset + 0x3E) as u8;
-            // debug(DBG_SND, "SfxPlayer::loadSfxModule() curOrder = 0x%X numOrder = 0x%X", self.sfx_mod.curOrder, self.sfx_mod.numOrder);
+            debug!(
+                DBG_SND,
+                "SfxPlayer::load_sfx_module() cur_order = {:#X} num_order = {:#X}",
+                self.sfx_mod.cur_order,
+                self.sfx_mod.num_order
+            );
 
             // for i in 0..0x80 {
             //     self.sfx_mod.order_table[i] = me.from_buf_u8(me_offset + 0x40 + i);
@@ -145,19 +167,27 @@ impl SfxPlayer {
             }
             self.delay *= 60 / 7050;
             self.sfx_mod.data = src_me.to_slice_end(me_offset + 0xC0).into();
-            // debug(DBG_SND, "SfxPlayer::loadSfxModule() eventDelay = %d ms", _delay);
+            debug!(
+                DBG_SND,
+                "SfxPlayer::load_sfx_module() event_delay = {} ms", self.delay
+            );
 
             // self.prepare_instruments(&src_me, me_offset + 2)?;
             // prepare instruments
-            for ins in &mut self.sfx_mod.samples {
+            me_offset += 2; // skip delay
+            for (i, ins) in self.sfx_mod.samples.iter_mut().enumerate() {
                 let res_id = src_me.from_buf_be_u16(me_offset as usize) as usize;
                 me_offset += 2;
 
                 if res_id != 0 {
                     ins.volume = src_me.from_buf_be_u16(me_offset as usize);
-                    let me = &self.res.get().storage.mem_list.entries[res_id];
+                    let me = res.storage.mem_list.entries.get(res_id);
 
-                    if me.state == MemEntryState::Loaded && me.res_type == ResType::Sound {
+                    if let Some(me) = me.filter(|me| {
+                        me.state == MemEntryState::Loaded
+                            && me.res_type == ResType::Sound
+                            && me.buffer.len() >= 12
+                    }) {
                         let mut buf = me.buffer.clone();
 
                         // TODO: do it in idiomatic way
@@ -166,16 +196,22 @@ impl SfxPlayer {
                         }
 
                         ins.data = buf.into();
-                    //         debug(DBG_SND, "Loaded instrument 0x%X n=%d volume=%d", resNum, i, ins->volume);
+                        debug!(
+                            DBG_SND,
+                            "Loaded instrument {:#X} n={} volume={}", res_id, i, ins.volume
+                        );
                     } else {
-                        bail!("Error loading instrument {}", res_id);
+                        bail!(AwbiError::bad_data(format!(
+                            "Error loading instrument {}",
+                            res_id
+                        )));
                     }
                 }
 
                 me_offset += 2; // skip volume
             }
         } else {
-            //     warning("SfxPlayer::loadSfxModule() ec=0x%X", 0xF8);
+            warning!("SfxPlayer::load_sfx_module() ec={:#X}", 0xF8);
         }
 
         Ok(())
@@ -206,7 +242,7 @@ impl SfxPlayer {
     // }
 
     pub fn start(&mut self) {
-        // debug(DBG_SND, "SfxPlayer::start()");
+        debug!(DBG_SND, "SfxPlayer::start()");
         let _ = MutexStack::new(self.sys.clone(), &self.mutex);
         self.sfx_mod.cur_pos = 0;
         self.timer_id = self
@@ -219,7 +255,7 @@ impl SfxPlayer {
     }
 
     pub fn stop(&mut self) {
-        // debug(DBG_SND, "SfxPlayer::stop()");
+        debug!(DBG_SND, "SfxPlayer::stop()");
         let _ = MutexStack::new(self.sys.clone(), &self.mutex);
         if self.res_id != 0 {
             self.res_id = 0;
@@ -230,21 +266,44 @@ impl SfxPlayer {
     fn handle_events(&mut self) {
         // todo!(); // TODO: implement
         let _ = MutexStack::new(self.sys.clone(), &self.mutex);
-        let mut order = self.sfx_mod.order_table[self.sfx_mod.cur_order as usize] as u16;
-        let mut pattern_data_idx: u16 = self.sfx_mod.cur_pos + order * 1024;
+        let mut order = match self
+            .sfx_mod
+            .order_table
+            .get(self.sfx_mod.cur_order as usize)
+        {
+            Some(order) => *order as u16,
+            None => {
+                warning!(
+                    "SfxPlayer::handle_events() invalid order {}",
+                    self.sfx_mod.cur_order
+                );
+                self.stop();
+                return;
+            }
+        };
+        let mut pattern_data_idx: u16 = self.sfx_mod.cur_pos.wrapping_add(order.wrapping_mul(1024));
 
         for ch in 0..4 {
-            self.handle_pattern(ch, pattern_data_idx);
-            pattern_data_idx += 4;
+            if let Err(err) = self.handle_pattern(ch, pattern_data_idx) {
+                // A truncated module ends the music.
+                warning!("SfxPlayer::handle_events() {}", err);
+                self.stop();
+                self.mixer.get_mut().stop_all();
+                return;
+            }
+            pattern_data_idx = pattern_data_idx.wrapping_add(4);
         }
 
         self.sfx_mod.cur_pos += 4 * 4;
-        // debug(DBG_SND, "SfxPlayer::handleEvents() order = 0x%X curPos = 0x%X", order, self.sfx_mod.curPos);
+        debug!(
+            DBG_SND,
+            "SfxPlayer::handle_events() order = {:#X} cur_pos = {:#X}", order, self.sfx_mod.cur_pos
+        );
         if self.sfx_mod.cur_pos >= 1024 {
             self.sfx_mod.cur_pos = 0;
             order = self.sfx_mod.cur_pos + 1;
 
-            if order == self.sfx_mod.num_order as _ {
+            if order == self.sfx_mod.num_order as u16 {
                 self.res_id = 0;
                 self.sys.get_mut().remove_timer(self.timer_id);
                 self.mixer.get_mut().stop_all();
@@ -254,11 +313,11 @@ impl SfxPlayer {
         }
     }
 
-    fn handle_pattern(&mut self, channel: u8, _pattern_data_idx: u16) {
+    fn handle_pattern(&mut self, channel: u8, _pattern_data_idx: u16) -> Result<()> {
         let mut pat = SfxPattern::default();
 
-        pat.note_1 = self.sfx_mod.data.read_u16();
-        pat.note_2 = self.sfx_mod.data.read_u16();
+        pat.note_1 = self.sfx_mod.data.read_u16()?;
+        pat.note_2 = self.sfx_mod.data.read_u16()?;
 
         if pat.note_1 != 0xFFFD {
             let sample = ((pat.note_2 & 0xF000) >> 12) as usize;
@@ -267,12 +326,15 @@ impl SfxPlayer {
                 let slice_reader = &mut instrument.data;
 
                 if !slice_reader.is_empty() {
-                    // debug(DBG_SND, "SfxPlayer::handlePattern() preparing sample %d", sample);
+                    debug!(
+                        DBG_SND,
+                        "SfxPlayer::handle_pattern() preparing sample {}", sample
+                    );
                     pat.sample_volume = instrument.volume;
                     pat.sample_start = 8;
                     pat.sample_buffer = slice_reader.get_data().into();
-                    pat.sample_len = slice_reader.read_u16() * 2;
-                    let loop_len = slice_reader.read_u16() * 2;
+                    pat.sample_len = slice_reader.read_u16()?.wrapping_mul(2);
+                    let loop_len = slice_reader.read_u16()?.wrapping_mul(2);
 
                     if loop_len != 0 {
                         pat.loop_pos = pat.sample_len;
@@ -309,11 +371,16 @@ impl SfxPlayer {
         }
 
         if pat.note_1 == 0xFFFD {
-            // debug(DBG_SND, "SfxPlayer::handlePattern() _scriptVars[0xF4] = 0x%X", pat.note_2);
-            self.mark_var[0] = pat.note_2 as i16;
+            debug!(
+                DBG_SND,
+                "SfxPlayer::handle_pattern() variables[0xF4] = {:#X}", pat.note_2
+            );
+            self.mark = Some(pat.note_2 as i16);
         } else if pat.note_1 != 0 {
             if pat.note_1 == 0xFFFE {
                 self.mixer.get_mut().stop_channel(channel);
+            } else if !(0x37..0x1000).contains(&pat.note_1) {
+                warning!("SfxPlayer::handle_pattern() invalid note {:#X}", pat.note_1);
             } else if !pat.sample_buffer.is_empty() {
                 let mut mc = MixerChunk::default();
 
@@ -321,10 +388,12 @@ impl SfxPlayer {
                 mc.len = pat.sample_len;
                 mc.loop_pos = pat.loop_pos;
                 mc.loop_len = pat.loop_len;
-                assert!(pat.note_1 >= 0x37 && pat.note_1 < 0x1000);
                 // convert amiga period value to hz
                 let freq = 7159092 / (pat.note_1 as u32 * 2);
-                // debug(DBG_SND, "SfxPlayer::handlePattern() adding sample freq = 0x%X", freq);
+                debug!(
+                    DBG_SND,
+                    "SfxPlayer::handle_pattern() adding sample freq = {:#X}", freq
+                );
                 self.mixer.get_mut().play_channel(
                     channel,
                     mc,
@@ -333,6 +402,8 @@ impl SfxPlayer {
                 );
             }
         }
+
+        Ok(())
     }
 
     pub fn save_or_load(&mut self, ser: &mut Serializer) -> Result<()> {
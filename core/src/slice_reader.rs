@@ -1,5 +1,8 @@
 // TODO: creeate a trait and Vec and slice implementations
 
+use crate::error::AwbiError;
+use anyhow::{bail, Result};
+
 #[derive(Clone, Default)]
 pub struct SliceReader {
     pos: usize,
@@ -32,17 +35,35 @@ impl SliceReader {
     }
 
     #[inline]
-    pub fn read_u8(&mut self) -> u8 {
+    pub fn read_u8(&mut self) -> Result<u8> {
         let addr = self.pos;
-        self.pos += 1;
-        self.data[addr]
+        match self.data.get(addr) {
+            Some(b) => {
+                self.pos += 1;
+                Ok(*b)
+            }
+            None => bail!(self.out_of_data(addr)),
+        }
     }
 
     #[inline]
-    pub fn read_u16(&mut self) -> u16 {
+    pub fn read_u16(&mut self) -> Result<u16> {
         let addr = self.pos;
-        self.pos += 2;
-        u16::from_be_bytes([self.data[addr], self.data[addr + 1]])
+        match self.data.get(addr..addr.saturating_add(2)) {
+            Some(bytes) => {
+                self.pos += 2;
+                Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+            }
+            None => bail!(self.out_of_data(addr)),
+        }
+    }
+
+    fn out_of_data(&self, addr: usize) -> AwbiError {
+        AwbiError::bad_data(format!(
+            "Read past the end of data at {:#06X}, size {:#06X}",
+            addr,
+            self.data.len()
+        ))
     }
 }
 
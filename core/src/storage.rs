@@ -1,55 +1,200 @@
-use crate::{bank::Bank, memlist::*};
-use anyhow::Result;
-use std::io::Cursor;
+use crate::{
+    bank,
+    error::AwbiError,
+    file::File,
+    memlist::*,
+    vfs::{self, Vfs},
+};
+use anyhow::{ensure, Result};
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
 
+const NUM_BANKS: u8 = 13;
+
+// Bank files and entries are read and unpacked the first time they are needed, then kept in
+// memory. With a memory limit the cache is trimmed after each load: the bank files go first,
+// then the entries the current part doesn't use.
 #[derive(Debug)]
 pub(crate) struct Storage {
-    data_dir: String,
+    vfs: Box<dyn Vfs>,
     pub mem_list: MemList,
-    banks: Vec<Vec<u8>>,
+    banks: Vec<Option<Vec<u8>>>,
+    memory_limit: Option<usize>,
 }
 
 impl Storage {
-    pub fn new(data_dir: &str) -> Self {
+    pub fn new(vfs: Box<dyn Vfs>) -> Self {
         Self {
-            data_dir: data_dir.into(),
-            mem_list: MemList::new(data_dir),
-            banks: Vec::new(),
+            vfs,
+            mem_list: MemList::default(),
+            banks: vec![None; NUM_BANKS as usize],
+            memory_limit: None,
         }
     }
 
-    pub fn bank_data(&self, bank_id: usize) -> &[u8] {
-        &self.banks[bank_id]
+    pub fn vfs(&self) -> &dyn Vfs {
+        &*self.vfs
+    }
+
+    /// Limit in bytes of the cached bank files and unpacked entries, `None` keeps everything.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.memory_limit = limit;
+        self.trim_cache(None);
     }
 
+    /// Bytes held by the cached bank files and unpacked entries.
+    pub fn cached_size(&self) -> usize {
+        let banks: usize = self.banks.iter().flatten().map(Vec::len).sum();
+        let entries: usize = self.mem_list.entries.iter().map(|me| me.buffer.len()).sum();
+
+        banks + entries
+    }
+
+    /// Read and parse `memlist.bin`, the entries are loaded by `load_entry`.
     pub fn load(&mut self) -> Result<()> {
-        let mut bank = Bank::default();
-        for bank_id in 1..=13 {
-            let mut f = bank.read_bank(&self.data_dir, bank_id)?;
-            let data = f.read_all()?;
-            self.banks.push(data);
+        let mem_list = vfs::read_file(&*self.vfs, "memlist.bin")?;
+        self.mem_list.read(&mut File::from_data(mem_list))?;
+        self.banks = vec![None; NUM_BANKS as usize];
+
+        for (i, me) in self.mem_list.entries.iter().enumerate() {
+            ensure!(
+                (1..=NUM_BANKS).contains(&me.bank_id),
+                AwbiError::bad_data(format!(
+                    "Memlist entry {:#X} refers to unknown bank {}",
+                    i, me.bank_id
+                ))
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Unpack the entry `idx` into its buffer unless it is already there.
+    pub fn load_entry(&mut self, idx: usize) -> Result<()> {
+        let me = self
+            .mem_list
+            .entries
+            .get(idx)
+            .ok_or_else(|| AwbiError::bad_data(format!("Unknown memlist entry {:#X}", idx)))?;
+        if me.size == 0 || !me.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let bank_id = me.bank_id;
+        self.read_bank(bank_id)?;
+        let bank_data = self.banks[bank_id as usize - 1]
+            .as_deref()
+            .unwrap_or_default();
+        let data = bank::read_entry_data(bank_data, &self.mem_list.entries[idx])?;
+        self.mem_list.entries[idx].buffer = data;
+
+        self.trim_cache(Some(idx));
+
+        Ok(())
+    }
+
+    /// Unpack every entry, spreading the work over the available cores.
+    pub fn load_all(&mut self) -> Result<()> {
+        let pending: Vec<usize> = (0..self.mem_list.entries.len())
+            .filter(|&idx| {
+                let me = &self.mem_list.entries[idx];
+                me.size != 0 && me.buffer.is_empty()
+            })
+            .collect();
+        for &idx in &pending {
+            self.read_bank(self.mem_list.entries[idx].bank_id)?;
+        }
+
+        let banks = &self.banks;
+        let entries = &self.mem_list.entries;
+        let next = AtomicUsize::new(0);
+        let num_threads = thread::available_parallelism()
+            .map_or(1, |n| n.get())
+            .min(pending.len());
+
+        // Workers take the next pending entry until there are none left, so big resources
+        // don't hold up the others.
+        let mut unpacked: Vec<(usize, Result<Vec<u8>>)> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..num_threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut done = Vec::new();
+                        while let Some(&idx) = pending.get(next.fetch_add(1, Ordering::Relaxed)) {
+                            let me = &entries[idx];
+                            let bank_data = banks[me.bank_id as usize - 1]
+                                .as_deref()
+                                .unwrap_or_default();
+                            done.push((idx, bank::read_entry_data(bank_data, me)));
+                        }
+                        done
+                    })
+                })
+                .collect();
+
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().expect("Unpack thread panicked"))
+                .collect()
+        });
+
+        // Report the error of the first entry whatever thread hit it.
+        unpacked.sort_by_key(|(idx, _)| *idx);
+        for (idx, data) in unpacked {
+            self.mem_list.entries[idx].buffer = data?;
         }
 
-        // load and parse memlist.bin
-        self.mem_list.load()?;
+        self.trim_cache(None);
+
+        Ok(())
+    }
 
-        for me in &mut self.mem_list.entries {
-            let mut cursor = Cursor::new(&self.banks[me.bank_id as usize - 1]);
-            let data = bank.read_entry_data(&mut cursor, &me)?;
-            me.buffer = data;
+    // Read the bank file `bank_id` unless it is cached.
+    fn read_bank(&mut self, bank_id: u8) -> Result<()> {
+        let bank = &mut self.banks[bank_id as usize - 1];
+        if bank.is_none() {
+            let bank_name = format!("bank{:02x}", bank_id);
+            *bank = Some(vfs::read_file(&*self.vfs, &bank_name)?);
         }
 
         Ok(())
     }
 
-    pub fn get_max_rank_entry_to_load(&mut self) -> Option<&mut MemEntry> {
+    // Evict cached data until the limit is met, keeping the entry `keep` that was just loaded.
+    fn trim_cache(&mut self, keep: Option<usize>) {
+        let limit = match self.memory_limit {
+            Some(limit) => limit,
+            None => return,
+        };
+
+        let mut size = self.cached_size();
+        for bank in &mut self.banks {
+            if size <= limit {
+                return;
+            }
+            size -= bank.take().map_or(0, |data| data.len());
+        }
+
+        for (i, me) in self.mem_list.entries.iter_mut().enumerate() {
+            if size <= limit {
+                return;
+            }
+            if me.state == MemEntryState::NotNeeded && keep != Some(i) {
+                size -= std::mem::take(&mut me.buffer).len();
+            }
+        }
+    }
+
+    /// Entry with the highest rank among those waiting to be loaded.
+    pub fn max_rank_entry_to_load(&self) -> Option<usize> {
         let mut mem_entry = None;
         let mut max_num = 0;
 
-        for me in &mut self.mem_list.entries {
+        for (i, me) in self.mem_list.entries.iter().enumerate() {
             if me.state == MemEntryState::LoadMe && max_num <= me.rank_num {
                 max_num = me.rank_num;
-                mem_entry = Some(me);
+                mem_entry = Some(i);
             }
         }
 
@@ -73,17 +218,85 @@ impl Storage {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::util::data_dir;
+    use crate::{
+        parts::*,
+        util::fixture_dir,
+        vfs::{MemVfs, OverlayVfs},
+    };
 
     #[test]
     fn test_storage_load() -> Result<()> {
-        let data_dir = data_dir()?;
-        let mut storage = Storage::new(&data_dir.to_str().unwrap());
+        let data_dir = fixture_dir()?;
+        let mut storage = Storage::new(vfs::open(&data_dir));
 
         storage.load()?;
 
         // println!("Storage:\n{:?}", storage);
 
+        // Nothing is read before an entry is needed.
+        assert_eq!(storage.cached_size(), 0);
+
+        let palette_idx = MEM_LIST_PARTS[0][MEMLIST_PART_PALETTE] as usize;
+        let code_idx = MEM_LIST_PARTS[0][MEMLIST_PART_CODE] as usize;
+        storage.load_entry(palette_idx)?;
+        let palette_size = storage.mem_list.entries[palette_idx].size;
+        let bank_size = std::fs::read(data_dir.join("bank01"))?.len();
+        assert_eq!(storage.cached_size(), bank_size + palette_size);
+
+        // The bank goes first, then the entries the part doesn't need.
+        storage.mem_list.entries[palette_idx].state = MemEntryState::Loaded;
+        storage.set_memory_limit(Some(palette_size));
+        assert_eq!(storage.cached_size(), palette_size);
+
+        storage.load_entry(code_idx)?;
+        let code_size = storage.mem_list.entries[code_idx].size;
+        assert_eq!(storage.cached_size(), palette_size + code_size);
+
+        storage.set_memory_limit(Some(0));
+        assert_eq!(storage.cached_size(), palette_size);
+
+        // Evicted entries are loaded again.
+        storage.load_entry(code_idx)?;
+        assert_eq!(storage.mem_list.entries[code_idx].buffer.len(), code_size);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_all() -> Result<()> {
+        let data_dir = fixture_dir()?;
+
+        let mut one_by_one = Storage::new(vfs::open(&data_dir));
+        one_by_one.load()?;
+        for idx in 0..one_by_one.mem_list.entries.len() {
+            one_by_one.load_entry(idx)?;
+        }
+
+        let mut parallel = Storage::new(vfs::open(&data_dir));
+        parallel.load()?;
+        parallel.load_all()?;
+        assert_eq!(parallel.cached_size(), one_by_one.cached_size());
+        for (a, b) in parallel
+            .mem_list
+            .entries
+            .iter()
+            .zip(&one_by_one.mem_list.entries)
+        {
+            assert_eq!(a.buffer, b.buffer);
+        }
+
+        // A truncated bank fails the whole load.
+        let mut bank01 = std::fs::read(data_dir.join("bank01"))?;
+        bank01.truncate(bank01.len() / 2);
+        let mut broken = MemVfs::default();
+        broken.insert("bank01", bank01);
+        let mut storage = Storage::new(Box::new(OverlayVfs::new(vec![
+            Box::new(broken),
+            vfs::open(&data_dir),
+        ])));
+        storage.load()?;
+        assert!(storage.load_all().is_err());
+
         Ok(())
     }
 }
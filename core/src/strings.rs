@@ -0,0 +1,135 @@
+// Translations of the strings drawn by the bytecode. Files are TOML or JSON keyed by string id,
+// ids missing from a translation are drawn from the built-in table of the release:
+//
+//     language = "Français"
+//
+//     [strings]
+//     0x001 = "P E A N U T  3000"
+//     0x193 = "Bonne chance..."
+
+use crate::{staticres::STRINGS_TABLE_ENG, symbols::convert};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::Path};
+
+#[derive(Deserialize, Serialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawStrings {
+    language: String,
+    strings: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct StringTable {
+    language: String,
+    strings: BTreeMap<u16, String>,
+}
+
+impl StringTable {
+    /// Load a `.toml` or `.json` string table.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read string table {:?}", path))?;
+
+        let res = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Self::from_toml(&text),
+            Some("json") => Self::from_json(&text),
+            _ => bail!("String table {:?} is neither .toml nor .json", path),
+        };
+        res.with_context(|| format!("Bad string table {:?}", path))
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self> {
+        Self::from_raw(toml::from_str(text)?)
+    }
+
+    pub fn from_json(text: &str) -> Result<Self> {
+        Self::from_raw(serde_json::from_str(text)?)
+    }
+
+    fn from_raw(raw: RawStrings) -> Result<Self> {
+        Ok(Self {
+            language: raw.language,
+            strings: convert(raw.strings, "string")?,
+        })
+    }
+
+    /// The built-in English strings, to start a translation from.
+    pub fn english() -> Self {
+        Self {
+            language: "English".into(),
+            strings: STRINGS_TABLE_ENG
+                .iter()
+                .map(|(str_id, text)| (*str_id, text.to_string()))
+                .collect(),
+        }
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        let raw = RawStrings {
+            language: self.language.clone(),
+            strings: self
+                .strings
+                .iter()
+                .map(|(str_id, text)| (format!("{:#05X}", str_id), text.clone()))
+                .collect(),
+        };
+
+        Ok(toml::to_string(&raw)?)
+    }
+
+    pub fn language(&self) -> &str {
+        &self.language
+    }
+
+    pub fn get(&self, str_id: u16) -> Option<&str> {
+        self.strings.get(&str_id).map(|text| text.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_string_table() -> Result<()> {
+        let table = StringTable::from_toml(
+            r#"
+            language = "Français"
+
+            [strings]
+            0x001 = "P E A N U T  3000"
+            404 = "Bonne chance...\nA bientot"
+            "#,
+        )?;
+        assert_eq!(table.language(), "Français");
+        assert_eq!(table.get(0x001), Some("P E A N U T  3000"));
+        assert_eq!(table.get(0x194), Some("Bonne chance...\nA bientot"));
+        assert_eq!(table.get(0x002), None);
+
+        let json = StringTable::from_json(r#"{ "strings": { "0x002": "Copyright" } }"#)?;
+        assert_eq!(json.get(0x002), Some("Copyright"));
+        assert_eq!(json.language(), "");
+
+        // Ids are 16 bits.
+        assert!(StringTable::from_toml("[strings]\n0x10000 = \"x\"").is_err());
+        assert!(StringTable::from_toml("[text]\n0x001 = \"x\"").is_err());
+
+        // The English table exported for translators reads back the same.
+        let english = StringTable::english();
+        let exported = StringTable::from_toml(&english.to_toml()?)?;
+        assert_eq!(exported.len(), STRINGS_TABLE_ENG.len());
+        assert_eq!(exported.get(0x002), STRINGS_TABLE_ENG.get(&0x002).copied());
+
+        Ok(())
+    }
+}
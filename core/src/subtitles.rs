@@ -0,0 +1,228 @@
+// Captions drawn over the screen, for the cinematics and the alien dialogue that have no text in
+// the game. Files are TOML or JSON, a caption starts at a frame of a part, counted in screen
+// updates from the start of the part, or when its music reaches a mark:
+//
+//     [[captions]]
+//     part = "0x3E81"
+//     frame = 120
+//     frames = 100
+//     text = "A storm breaks over the laboratory."
+//
+//     [[captions]]
+//     part = "0x3E83"
+//     music_mark = 2
+//     frames = 50
+//     text = "[Alien shouting]"
+
+use crate::symbols::parse_id;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::{fs, path::Path};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawCaption {
+    part: String,
+    frame: Option<u32>,
+    music_mark: Option<i16>,
+    frames: u32,
+    text: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawSubtitles {
+    captions: Vec<RawCaption>,
+}
+
+/// When a caption starts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cue {
+    Frame(u32),
+    MusicMark(i16),
+}
+
+#[derive(Clone, Debug)]
+pub struct Caption {
+    pub part_id: u16,
+    pub cue: Cue,
+    /// Number of frames the caption stays on screen.
+    pub frames: u32,
+    pub text: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Subtitles {
+    captions: Vec<Caption>,
+    part_id: u16,
+    frame: u32,
+    music_mark: i16,
+    // Caption on screen and the frame it goes away.
+    shown: Option<(usize, u32)>,
+}
+
+impl Subtitles {
+    /// Load a `.toml` or `.json` subtitle file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read subtitle file {:?}", path))?;
+
+        let res = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Self::from_toml(&text),
+            Some("json") => Self::from_json(&text),
+            _ => bail!("Subtitle file {:?} is neither .toml nor .json", path),
+        };
+        res.with_context(|| format!("Bad subtitle file {:?}", path))
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self> {
+        Self::from_raw(toml::from_str(text)?)
+    }
+
+    pub fn from_json(text: &str) -> Result<Self> {
+        Self::from_raw(serde_json::from_str(text)?)
+    }
+
+    fn from_raw(raw: RawSubtitles) -> Result<Self> {
+        let mut captions = Vec::new();
+
+        for (i, caption) in raw.captions.into_iter().enumerate() {
+            let cue = match (caption.frame, caption.music_mark) {
+                (Some(frame), None) => Cue::Frame(frame),
+                (None, Some(mark)) => Cue::MusicMark(mark),
+                _ => bail!("Caption {} needs either a frame or a music_mark", i + 1),
+            };
+
+            captions.push(Caption {
+                part_id: parse_id(&caption.part)?,
+                cue,
+                frames: caption.frames,
+                text: caption.text,
+            });
+        }
+
+        Ok(Self {
+            captions,
+            ..Default::default()
+        })
+    }
+
+    pub fn captions(&self) -> &[Caption] {
+        &self.captions
+    }
+
+    /// Move to the next screen update of the part `part_id` with the music mark `music_mark`,
+    /// and return the caption to draw over it. A new caption replaces the one on screen.
+    pub(crate) fn next_frame(&mut self, part_id: u16, music_mark: i16) -> Option<&str> {
+        let mark_reached = music_mark != self.music_mark;
+
+        if part_id != self.part_id {
+            // The mark left by the music of the previous part is no cue.
+            self.part_id = part_id;
+            self.frame = 0;
+            self.shown = None;
+        } else {
+            self.frame += 1;
+            if mark_reached {
+                self.start(Cue::MusicMark(music_mark));
+            }
+        }
+        self.music_mark = music_mark;
+        self.start(Cue::Frame(self.frame));
+
+        match self.shown {
+            Some((idx, end)) if self.frame < end => Some(&self.captions[idx].text),
+            _ => None,
+        }
+    }
+
+    fn start(&mut self, cue: Cue) {
+        let (part_id, frame) = (self.part_id, self.frame);
+
+        if let Some(idx) = self
+            .captions
+            .iter()
+            .position(|caption| caption.part_id == part_id && caption.cue == cue)
+        {
+            self.shown = Some((idx, frame.saturating_add(self.captions[idx].frames)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parts::{GAME_PART2, GAME_PART3};
+
+    #[test]
+    fn test_subtitles() -> Result<()> {
+        let mut subtitles = Subtitles::from_toml(
+            r#"
+            [[captions]]
+            part = "0x3E81"
+            frame = 2
+            frames = 3
+            text = "Thunder"
+
+            [[captions]]
+            part = "0x3E81"
+            music_mark = 1
+            frames = 2
+            text = "Door opens"
+
+            [[captions]]
+            part = "16002"
+            frame = 0
+            frames = 1
+            text = "Splash"
+            "#,
+        )?;
+        assert_eq!(subtitles.captions().len(), 3);
+        assert_eq!(subtitles.captions()[2].part_id, GAME_PART3);
+
+        let mut play = |part_id, marks: &[i16]| -> Vec<Option<String>> {
+            marks
+                .iter()
+                .map(|mark| subtitles.next_frame(part_id, *mark).map(str::to_owned))
+                .collect()
+        };
+        let text = |s: &str| Some(s.to_string());
+
+        // Frames 0 to 6, the mark reached at frame 3 cuts the first caption short.
+        assert_eq!(
+            play(GAME_PART2, &[1, 1, 0, 1, 1, 1, 1]),
+            vec![
+                None,
+                None,
+                text("Thunder"),
+                text("Door opens"),
+                text("Door opens"),
+                None,
+                None,
+            ]
+        );
+        assert_eq!(play(GAME_PART3, &[1, 1]), vec![text("Splash"), None]);
+
+        let json = Subtitles::from_json(
+            r#"{ "captions": [ { "part": "0x3E85", "music_mark": 4, "frames": 9, "text": "Hi" } ] }"#,
+        )?;
+        assert_eq!(json.captions()[0].cue, Cue::MusicMark(4));
+
+        let both =
+            "[[captions]]\npart = \"0x3E81\"\nframe = 1\nmusic_mark = 1\nframes = 1\ntext = \"\"";
+        assert!(Subtitles::from_toml(both).is_err());
+        assert!(Subtitles::from_toml("[[captions]]\npart = \"0x3E81\"\nframe = 1").is_err());
+
+        // A caption staying for good.
+        let mut forever = Subtitles::from_toml(&format!(
+            "[[captions]]\npart = \"0x3E81\"\nframe = 1\nframes = {}\ntext = \"End\"",
+            u32::MAX
+        ))?;
+        forever.next_frame(GAME_PART2, 0);
+        assert_eq!(forever.next_frame(GAME_PART2, 0), Some("End"));
+        assert_eq!(forever.next_frame(GAME_PART2, 0), Some("End"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,286 @@
+// Names of variables, threads, code labels and resources collected while reverse engineering
+// the scripts. Files are TOML or JSON, names of a part override the global ones:
+//
+//     [variables]
+//     0x3C = "RANDOM_SEED"
+//
+//     [parts.0x3E80.labels]
+//     0x0010 = "check_code"
+
+use crate::staticres::VARIABLE_NAME_BY_INDEX;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use std::{collections::BTreeMap, convert::TryFrom, fs, path::Path};
+
+type RawNames = BTreeMap<String, String>;
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawPart {
+    variables: RawNames,
+    threads: RawNames,
+    labels: RawNames,
+    resources: RawNames,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawSymbols {
+    variables: RawNames,
+    threads: RawNames,
+    resources: RawNames,
+    parts: BTreeMap<String, RawPart>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Names {
+    variables: BTreeMap<u8, String>,
+    threads: BTreeMap<u8, String>,
+    labels: BTreeMap<u16, String>,
+    resources: BTreeMap<u16, String>,
+}
+
+impl Names {
+    fn merge(&mut self, other: Names) {
+        self.variables.extend(other.variables);
+        self.threads.extend(other.threads);
+        self.labels.extend(other.labels);
+        self.resources.extend(other.resources);
+    }
+}
+
+pub(crate) fn parse_id(s: &str) -> Result<u16> {
+    let res = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    res.map_err(|_| anyhow!("Invalid id '{}'", s))
+}
+
+pub(crate) fn convert<K: TryFrom<u16> + Ord>(
+    raw: RawNames,
+    what: &str,
+) -> Result<BTreeMap<K, String>> {
+    raw.into_iter()
+        .map(|(id, name)| {
+            let key = parse_id(&id)
+                .ok()
+                .and_then(|id| K::try_from(id).ok())
+                .ok_or_else(|| anyhow!("Invalid {} id '{}'", what, id))?;
+            Ok((key, name))
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug)]
+pub struct Symbols {
+    global: Names,
+    parts: BTreeMap<u16, Names>,
+}
+
+impl Default for Symbols {
+    /// Names of the variables used by the engine itself.
+    fn default() -> Self {
+        let variables = VARIABLE_NAME_BY_INDEX
+            .iter()
+            .map(|(id, name)| (*id as u8, name.to_string()))
+            .collect();
+
+        Self {
+            global: Names {
+                variables,
+                ..Default::default()
+            },
+            parts: BTreeMap::new(),
+        }
+    }
+}
+
+impl Symbols {
+    /// Load a `.toml` or `.json` symbol file on top of the built-in names.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read symbol file {:?}", path))?;
+
+        let mut symbols = Self::default();
+        let res = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => symbols.add_toml(&text),
+            Some("json") => symbols.add_json(&text),
+            _ => bail!("Symbol file {:?} is neither .toml nor .json", path),
+        };
+        res.with_context(|| format!("Bad symbol file {:?}", path))?;
+
+        Ok(symbols)
+    }
+
+    pub fn add_toml(&mut self, text: &str) -> Result<()> {
+        self.add(toml::from_str(text)?)
+    }
+
+    pub fn add_json(&mut self, text: &str) -> Result<()> {
+        self.add(serde_json::from_str(text)?)
+    }
+
+    fn add(&mut self, raw: RawSymbols) -> Result<()> {
+        self.global.merge(Names {
+            variables: convert(raw.variables, "variable")?,
+            threads: convert(raw.threads, "thread")?,
+            labels: BTreeMap::new(),
+            resources: convert(raw.resources, "resource")?,
+        });
+
+        for (part_id, part) in raw.parts {
+            let part_id = parse_id(&part_id)?;
+            self.parts.entry(part_id).or_default().merge(Names {
+                variables: convert(part.variables, "variable")?,
+                threads: convert(part.threads, "thread")?,
+                labels: convert(part.labels, "label")?,
+                resources: convert(part.resources, "resource")?,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn lookup<'a, K: Ord + 'static, F>(&'a self, part_id: u16, key: &K, table: F) -> Option<&'a str>
+    where
+        F: Fn(&'a Names) -> &'a BTreeMap<K, String>,
+    {
+        self.parts
+            .get(&part_id)
+            .and_then(|names| table(names).get(key))
+            .or_else(|| table(&self.global).get(key))
+            .map(|name| name.as_str())
+    }
+
+    fn find<'a, K: Copy + Ord + 'static, F>(
+        &'a self,
+        part_id: u16,
+        name: &str,
+        table: F,
+    ) -> Option<K>
+    where
+        F: Fn(&'a Names) -> &'a BTreeMap<K, String>,
+    {
+        let find = |names| {
+            table(names)
+                .iter()
+                .find(|(_, n)| n.as_str() == name)
+                .map(|(k, _)| *k)
+        };
+        self.parts
+            .get(&part_id)
+            .and_then(find)
+            .or_else(|| find(&self.global))
+    }
+
+    pub fn variable(&self, part_id: u16, var_id: u8) -> Option<&str> {
+        self.lookup(part_id, &var_id, |n| &n.variables)
+    }
+
+    pub fn thread(&self, part_id: u16, thread_id: u8) -> Option<&str> {
+        self.lookup(part_id, &thread_id, |n| &n.threads)
+    }
+
+    pub fn label(&self, part_id: u16, addr: u16) -> Option<&str> {
+        self.lookup(part_id, &addr, |n| &n.labels)
+    }
+
+    pub fn resource(&self, part_id: u16, res_id: u16) -> Option<&str> {
+        self.lookup(part_id, &res_id, |n| &n.resources)
+    }
+
+    pub fn find_variable(&self, part_id: u16, name: &str) -> Option<u8> {
+        self.find(part_id, name, |n| &n.variables)
+    }
+
+    pub fn find_label(&self, part_id: u16, name: &str) -> Option<u16> {
+        self.find(part_id, name, |n| &n.labels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::Program;
+
+    #[test]
+    fn test_symbols() -> Result<()> {
+        let mut symbols = Symbols::default();
+        assert_eq!(symbols.variable(0x3E80, 0x3C), Some("RANDOM_SEED"));
+
+        symbols.add_toml(
+            r#"
+            [variables]
+            0x10 = "counter"
+
+            [threads]
+            0 = "main"
+
+            [parts.0x3E80.variables]
+            0x10 = "code_digit"
+
+            [parts.0x3E80.labels]
+            0x0010 = "check_code"
+            "#,
+        )?;
+        symbols.add_json(r#"{ "parts": { "0x3E81": { "resources": { "0x12": "intro" } } } }"#)?;
+
+        assert_eq!(symbols.variable(0x3E80, 0x10), Some("code_digit"));
+        assert_eq!(symbols.variable(0x3E81, 0x10), Some("counter"));
+        assert_eq!(symbols.thread(0x3E81, 0), Some("main"));
+        assert_eq!(symbols.label(0x3E80, 0x10), Some("check_code"));
+        assert_eq!(symbols.label(0x3E81, 0x10), None);
+        assert_eq!(symbols.resource(0x3E81, 0x12), Some("intro"));
+        assert_eq!(symbols.find_variable(0x3E81, "counter"), Some(0x10));
+        assert_eq!(symbols.find_label(0x3E80, "check_code"), Some(0x10));
+
+        assert!(symbols
+            .add_toml("[variables]\n0x100 = \"too_big\"")
+            .is_err());
+        assert!(symbols.add_toml("[labels]\n0x10 = \"no_part\"").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_symbols_disassembly() -> Result<()> {
+        #[rustfmt::skip]
+        let code = vec![
+            0x00, 0x10, 0x00, 0x05, // 0000: mov [0x10], 5
+            0x08, 0x01, 0x00, 0x00, // 0004: setvec 1, 0x0000
+            0x02, 0x3C, 0x11,       // 0008: add [0x3C], [0x11]
+        ];
+        let mut program = Program::new(0, 0x3E81, code);
+        program.parse()?;
+
+        let mut symbols = Symbols::default();
+        symbols.add_toml(
+            r#"
+            [parts.0x3E81.variables]
+            0x10 = "lives"
+            [parts.0x3E81.threads]
+            1 = "enemy"
+            [parts.0x3E81.labels]
+            0x0000 = "start"
+            "#,
+        )?;
+
+        let listing: Vec<_> = program
+            .listing(&symbols)
+            .into_iter()
+            .map(|(_, cmd)| cmd)
+            .collect();
+        assert_eq!(
+            listing,
+            vec![
+                "mov [lives], 5",
+                "setvec channel:1 <enemy>, address:0x0000 <start>",
+                "add [RANDOM_SEED], [0x11]",
+            ]
+        );
+
+        Ok(())
+    }
+}
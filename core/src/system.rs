@@ -72,9 +72,70 @@ pub trait System {
     fn get_offscreen_framebuffer(&mut self) -> Vec<u8>;
 }
 
+/*
+    System without any video, audio or input. Used to run the engine from tools and tests.
+*/
+#[derive(Default)]
+pub struct HeadlessSystem {
+    input: PlayerInput,
+}
+
+impl System for HeadlessSystem {
+    fn input(&self) -> &PlayerInput {
+        &self.input
+    }
+
+    fn input_mut(&mut self) -> &mut PlayerInput {
+        &mut self.input
+    }
+
+    fn init(&mut self, _title: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn destroy(&mut self) {}
+    fn set_palette(&mut self, _s: u8, _n: u8, _buf: &[u8]) {}
+    fn copy_rect(&mut self, _x: u16, _y: u16, _w: u16, _h: u16, _buf: &[u8], _pitch: u32) {}
+
+    fn process_events(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn sleep(&self, _duration: u32) {}
+
+    fn get_timestamp(&self) -> u32 {
+        0
+    }
+
+    fn start_audio(&mut self, _callback: &AudioCallback) {}
+    fn stop_audio(&mut self) {}
+
+    fn get_output_sample_rate(&mut self) -> u32 {
+        22050 // sound sample rate
+    }
+
+    fn add_timer(&mut self, _delay: u32, _callback: &TimerCallback) -> TimerId {
+        TimerId::default()
+    }
+
+    fn remove_timer(&mut self, _timer_id: TimerId) {}
+
+    fn create_mutex(&mut self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn destroy_mutex(&mut self, _mutex: &[u8]) {}
+    fn lock_mutex(&mut self, _mutex: &[u8]) {}
+    fn unlock_mutex(&mut self, _mutex: &[u8]) {}
+
+    fn get_offscreen_framebuffer(&mut self) -> Vec<u8> {
+        vec![]
+    }
+}
+
 impl fmt::Debug for dyn System {
-    fn fmt(&self, _f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        todo!()
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("System")
     }
 }
 
@@ -0,0 +1,303 @@
+// Instruction level execution trace, one line per executed command:
+//
+//     FRAME THREAD PART ADDR OPCODE [VAR=VALUE ...]
+//
+// The frame is decimal, everything else is hexadecimal. The format is simple enough to be
+// written by the C++ reference implementation, so both traces can be compared with `diff`.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    collections::VecDeque,
+    convert::TryFrom,
+    fmt,
+    fs::File,
+    io::{BufRead, BufWriter, Write},
+    path::Path,
+    str::FromStr,
+};
+
+const HEADER: &str = "# awbi trace";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub frame: u64,
+    pub thread_id: u8,
+    pub part_id: u16,
+    pub addr: u16,
+    pub opcode: u8,
+    /// Variables changed by the command with their new values.
+    pub writes: Vec<(u8, i16)>,
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut line = format!(
+            "{} {:02X} {:04X} {:04X} {:02X}",
+            self.frame, self.thread_id, self.part_id, self.addr, self.opcode
+        );
+        for (var_id, val) in &self.writes {
+            line.push_str(&format!(" {:02X}={:04X}", var_id, *val as u16));
+        }
+        f.pad(&line)
+    }
+}
+
+fn parse_hex<T: TryFrom<u32>>(s: &str, what: &str) -> Result<T> {
+    u32::from_str_radix(s, 16)
+        .ok()
+        .and_then(|n| T::try_from(n).ok())
+        .ok_or_else(|| anyhow!("Invalid {} '{}'", what, s))
+}
+
+impl FromStr for TraceRecord {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let fields: Vec<_> = s.split_whitespace().collect();
+        if fields.len() < 5 {
+            bail!("Expected 'frame thread part addr opcode [var=value ...]'");
+        }
+
+        let writes = fields[5..]
+            .iter()
+            .map(|write| match write.split_once('=') {
+                Some((var_id, val)) => Ok((
+                    parse_hex(var_id, "variable")?,
+                    parse_hex::<u16>(val, "value")? as i16,
+                )),
+                None => bail!("Expected 'var=value' instead of '{}'", write),
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            frame: fields[0]
+                .parse()
+                .map_err(|_| anyhow!("Invalid frame '{}'", fields[0]))?,
+            thread_id: parse_hex(fields[1], "thread")?,
+            part_id: parse_hex(fields[2], "part")?,
+            addr: parse_hex(fields[3], "address")?,
+            opcode: parse_hex(fields[4], "opcode")?,
+            writes,
+        })
+    }
+}
+
+pub struct TraceWriter {
+    out: Box<dyn Write>,
+    frame: u64,
+}
+
+impl TraceWriter {
+    pub fn new(mut out: Box<dyn Write>) -> Result<Self> {
+        writeln!(out, "{}", HEADER)?;
+        writeln!(out, "# frame thread part addr opcode [var=value ...]")?;
+
+        Ok(Self { out, frame: 0 })
+    }
+
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file =
+            File::create(path).with_context(|| format!("Unable to create trace {:?}", path))?;
+
+        Self::new(Box::new(BufWriter::new(file)))
+    }
+
+    /// Number of completed frames.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        Ok(self.out.flush()?)
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        thread_id: usize,
+        part_id: u16,
+        addr: u16,
+        opcode: u8,
+        old_vars: &[i16],
+        new_vars: &[i16],
+    ) -> Result<()> {
+        let writes = old_vars
+            .iter()
+            .zip(new_vars)
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(var_id, (_, new))| (var_id as u8, *new))
+            .collect();
+
+        let record = TraceRecord {
+            frame: self.frame,
+            thread_id: thread_id as u8,
+            part_id,
+            addr,
+            opcode,
+            writes,
+        };
+
+        Ok(writeln!(self.out, "{}", record)?)
+    }
+
+    pub(crate) fn end_frame(&mut self) {
+        self.frame += 1;
+    }
+}
+
+impl fmt::Debug for TraceWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TraceWriter")
+            .field("frame", &self.frame)
+            .finish()
+    }
+}
+
+/// Records of a trace, comments and empty lines are skipped.
+pub fn read_trace<R: BufRead>(input: R) -> impl Iterator<Item = Result<(usize, TraceRecord)>> {
+    input
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| match line {
+            Ok(line) => {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    None
+                } else {
+                    Some(
+                        line.parse()
+                            .map(|record| (i + 1, record))
+                            .with_context(|| format!("Line {}", i + 1)),
+                    )
+                }
+            }
+            Err(err) => Some(Err(err.into())),
+        })
+}
+
+/// First difference of two traces.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    /// Number of matching records before the divergence.
+    pub index: usize,
+    /// Line and record of both traces, `None` when the trace ended.
+    pub left: Option<(usize, TraceRecord)>,
+    pub right: Option<(usize, TraceRecord)>,
+    /// Matching records preceding the divergence.
+    pub context: Vec<TraceRecord>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Traces diverge after {} records", self.index)?;
+        for record in &self.context {
+            writeln!(f, "  {}", record)?;
+        }
+
+        for (side, record) in [("<", &self.left), (">", &self.right)].iter() {
+            match record {
+                Some((line, record)) => writeln!(f, "{} {} (line {})", side, record, line)?,
+                None => writeln!(f, "{} end of trace", side)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Compare two traces record by record, `context` matching records before the first
+/// difference are kept for the report.
+pub fn diff<A: BufRead, B: BufRead>(
+    left: A,
+    right: B,
+    context: usize,
+) -> Result<Option<Divergence>> {
+    let mut left = read_trace(left);
+    let mut right = read_trace(right);
+    let mut previous = VecDeque::with_capacity(context + 1);
+    let mut index = 0;
+
+    loop {
+        let l = left.next().transpose().context("Left trace")?;
+        let r = right.next().transpose().context("Right trace")?;
+
+        match (l, r) {
+            (None, None) => return Ok(None),
+            (Some((_, l)), Some((_, r))) if l == r => {
+                previous.push_back(l);
+                if previous.len() > context {
+                    previous.pop_front();
+                }
+                index += 1;
+            }
+            (left, right) => {
+                return Ok(Some(Divergence {
+                    index,
+                    left,
+                    right,
+                    context: previous.into_iter().collect(),
+                }))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    // Writer the test can read back after the trace writer is done.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trace() -> Result<()> {
+        let out = Shared::default();
+        let mut writer = TraceWriter::new(Box::new(out.clone()))?;
+
+        let old = [0i16; 4];
+        writer.record(1, 0x3E80, 0x0000, 0x00, &old, &[0, 5, 0, -1])?;
+        writer.end_frame();
+        writer.record(2, 0x3E80, 0x0004, 0x06, &old, &old)?;
+
+        let left = String::from_utf8(out.0.borrow().clone())?;
+        let lines: Vec<_> = left.lines().skip(2).collect();
+        assert_eq!(
+            lines,
+            vec!["0 01 3E80 0000 00 01=0005 03=FFFF", "1 02 3E80 0004 06"]
+        );
+
+        let record: TraceRecord = lines[0].parse()?;
+        assert_eq!(record.writes, vec![(1, 5), (3, -1)]);
+
+        assert_eq!(diff(left.as_bytes(), left.as_bytes(), 4)?, None);
+
+        let right = left.replace("03=FFFF", "03=FFFE");
+        let divergence = diff(left.as_bytes(), right.as_bytes(), 4)?.expect("Traces differ");
+        assert_eq!(divergence.index, 0);
+        assert_eq!(divergence.left.map(|(line, _)| line), Some(3));
+
+        let shorter = lines[0];
+        let divergence = diff(left.as_bytes(), shorter.as_bytes(), 4)?.expect("Traces differ");
+        assert_eq!(divergence.index, 1);
+        assert_eq!(divergence.context, vec![record]);
+        assert_eq!(divergence.right, None);
+        assert!(divergence.to_string().contains("> end of trace"));
+
+        assert!(diff(left.as_bytes(), "0 01".as_bytes(), 4).is_err());
+
+        Ok(())
+    }
+}
@@ -21,6 +21,24 @@ pub(crate) fn data_dir() -> Result<PathBuf> {
     Ok(dir)
 }
 
+// Synthetic game data shared by the tests, generated once per test run.
+#[cfg(test)]
+pub(crate) fn fixture_dir() -> Result<PathBuf> {
+    lazy_static::lazy_static! {
+        static ref GENERATED: std::sync::Mutex<bool> = std::sync::Mutex::new(false);
+    }
+
+    let dir = proj_dir()?.join("target").join("fixtures");
+
+    let mut generated = GENERATED.lock().unwrap_or_else(|err| err.into_inner());
+    if !*generated {
+        crate::fixtures::write_fixtures(&dir)?;
+        *generated = true;
+    }
+
+    Ok(dir)
+}
+
 #[inline]
 pub(crate) fn w_add_i16(v1: i16, v2: i16) -> i16 {
     (Wrapping(v1) + Wrapping(v2)).0
@@ -0,0 +1,302 @@
+// Static checks of the parsed bytecode. Everything is checked without running the scripts, so
+// modded data can be rejected before it faults in the middle of a play session.
+
+use crate::{
+    command::{Command, JmpType, ResetType},
+    memlist::ResType,
+    parts::{GAME_PART_FIRST, GAME_PART_LAST},
+    program::Program,
+    staticres::*,
+    vm_context::{VM_NUM_THREADS, VM_NUM_VARIABLES},
+};
+use std::{collections::BTreeMap, fmt};
+
+// Variables set by the engine itself: initial values, input and music synchronization.
+const ENGINE_VARIABLES: [usize; 14] = [
+    0x54,
+    0xDC,
+    0xE4,
+    0xF7,
+    VM_VARIABLE_RANDOM_SEED,
+    VM_VARIABLE_LAST_KEYCHAR,
+    VM_VARIABLE_HERO_POS_UP_DOWN,
+    VM_VARIABLE_MUS_MARK,
+    VM_VARIABLE_SCROLL_Y,
+    VM_VARIABLE_HERO_ACTION,
+    VM_VARIABLE_HERO_POS_JUMP_DOWN,
+    VM_VARIABLE_HERO_POS_LEFT_RIGHT,
+    VM_VARIABLE_HERO_POS_MASK,
+    VM_VARIABLE_HERO_ACTION_POS_MASK,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IssueKind {
+    /// Jump, call or `SetVect` target that is not a command boundary.
+    BadTarget {
+        target: u16,
+    },
+    UnknownJmpType(u8),
+    UnknownResetType(u8),
+    /// `ResetThread` range that is reversed or goes beyond `VM_NUM_THREADS`.
+    BadThreadRange {
+        first: u8,
+        last: u8,
+    },
+    /// Resource id missing from the memlist or of another type than `expected`.
+    BadResource {
+        res_id: u16,
+        expected: &'static str,
+    },
+    /// Variable read by the scripts but written nowhere, neither by a command of any part nor
+    /// by the engine. Reads that may happen before a write are not detected.
+    NeverWritten {
+        var_id: u8,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Issue {
+    pub part_id: u16,
+    pub addr: u16,
+    pub kind: IssueKind,
+}
+
+impl Issue {
+    pub fn severity(&self) -> Severity {
+        match self.kind {
+            // All variables start at 0, reading them is legal even if suspicious.
+            IssueKind::NeverWritten { .. } => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity() {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(
+            f,
+            "Part 0x{:04X} at 0x{:04X}: {}: ",
+            self.part_id, self.addr, severity
+        )?;
+
+        match &self.kind {
+            IssueKind::BadTarget { target } => {
+                write!(f, "target 0x{:04X} is not a command boundary", target)
+            }
+            IssueKind::UnknownJmpType(jt) => write!(f, "unknown conditional jump type {}", jt),
+            IssueKind::UnknownResetType(rt) => write!(f, "unknown thread reset type {}", rt),
+            IssueKind::BadThreadRange { first, last } => write!(
+                f,
+                "thread range {}..={} is out of 0..{}",
+                first, last, VM_NUM_THREADS
+            ),
+            IssueKind::BadResource { res_id, expected } => {
+                write!(f, "resource 0x{:02X} is not {}", res_id, expected)
+            }
+            IssueKind::NeverWritten { var_id } => {
+                write!(f, "variable 0x{:02X} is read but written nowhere", var_id)
+            }
+        }
+    }
+}
+
+pub(crate) struct Validator<'a> {
+    // Types of the memlist entries by resource id.
+    res_types: &'a [ResType],
+    issues: Vec<Issue>,
+    written: [bool; VM_NUM_VARIABLES],
+    // First read of every variable.
+    reads: BTreeMap<u8, (u16, u16)>,
+}
+
+impl<'a> Validator<'a> {
+    pub fn new(res_types: &'a [ResType]) -> Self {
+        let mut written = [false; VM_NUM_VARIABLES];
+        for var_id in ENGINE_VARIABLES {
+            written[var_id] = true;
+        }
+
+        Self {
+            res_types,
+            issues: Vec::new(),
+            written,
+            reads: BTreeMap::new(),
+        }
+    }
+
+    pub fn check_program(&mut self, program: &Program) {
+        let part_id = program.part_id();
+
+        for (addr, cmd) in program.commands() {
+            let mut kinds = Vec::new();
+
+            match cmd {
+                Command::Call { offset }
+                | Command::Jmp { offset }
+                | Command::SetVect { offset, .. }
+                | Command::Jnz { offset, .. }
+                | Command::CondJmp { offset, .. }
+                    if !program.is_command_addr(*offset) =>
+                {
+                    kinds.push(IssueKind::BadTarget { target: *offset })
+                }
+                _ => {}
+            }
+
+            match cmd {
+                Command::CondJmp {
+                    jmp_type: JmpType::Unknown(jt),
+                    ..
+                } => kinds.push(IssueKind::UnknownJmpType(*jt)),
+                Command::ResetThread {
+                    reset_type,
+                    first,
+                    last,
+                } => {
+                    if let ResetType::Unknown(rt) = reset_type {
+                        kinds.push(IssueKind::UnknownResetType(*rt));
+                    }
+                    if first > last || *last as usize >= VM_NUM_THREADS {
+                        kinds.push(IssueKind::BadThreadRange {
+                            first: *first,
+                            last: *last,
+                        });
+                    }
+                }
+                Command::UpdateMemList { res_id } => {
+                    let is_part = (GAME_PART_FIRST..=GAME_PART_LAST).contains(res_id);
+                    let is_entry = matches!(
+                        self.res_types.get(*res_id as usize),
+                        Some(rt) if !matches!(rt, ResType::Unknown(_))
+                    );
+                    if *res_id != 0 && !is_part && !is_entry {
+                        kinds.push(IssueKind::BadResource {
+                            res_id: *res_id,
+                            expected: "a resource or a game part",
+                        });
+                    }
+                }
+                Command::PlaySound { res_id, .. }
+                    if self.res_types.get(*res_id as usize) != Some(&ResType::Sound) =>
+                {
+                    kinds.push(IssueKind::BadResource {
+                        res_id: *res_id,
+                        expected: "a sound",
+                    })
+                }
+                Command::PlayMusic { res_id, .. }
+                    if *res_id != 0
+                        && self.res_types.get(*res_id as usize) != Some(&ResType::Music) =>
+                {
+                    kinds.push(IssueKind::BadResource {
+                        res_id: *res_id,
+                        expected: "a music",
+                    })
+                }
+                _ => {}
+            }
+
+            self.issues.extend(kinds.into_iter().map(|kind| Issue {
+                part_id,
+                addr,
+                kind,
+            }));
+
+            for var_id in cmd.src_vars() {
+                self.reads.entry(var_id).or_insert((part_id, addr));
+            }
+            if let Some(var_id) = cmd.dst_var() {
+                self.written[var_id as usize] = true;
+            }
+        }
+    }
+
+    /// Issues of all checked programs. Variables are global, so the reads are matched with
+    /// the writes of every part.
+    pub fn finish(mut self) -> Vec<Issue> {
+        for (var_id, (part_id, addr)) in &self.reads {
+            if !self.written[*var_id as usize] {
+                self.issues.push(Issue {
+                    part_id: *part_id,
+                    addr: *addr,
+                    kind: IssueKind::NeverWritten { var_id: *var_id },
+                });
+            }
+        }
+
+        self.issues.sort_by_key(|issue| (issue.part_id, issue.addr));
+
+        self.issues
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_validator() -> Result<()> {
+        #[rustfmt::skip]
+        let code = vec![
+            0x00, 0x10, 0x00, 0x05,             // 0000: mov [0x10], 5
+            0x07, 0x00, 0x05,                   // 0004: jmp 0x0005
+            0x0A, 0x06, 0x20, 0x01, 0x00, 0x00, // 0007: unknown_jmp(6) [0x20], 1, 0x0000
+            0x0C, 0x3F, 0x40, 0x02,             // 000D: deleteChannels 63..=64
+            0x18, 0x00, 0x01, 0x00, 0x3F, 0x00, // 0011: play_sound 1
+            0x19, 0x3E, 0x81,                   // 0017: update_mem_list 0x3E81
+            0x1A, 0x00, 0x00, 0x00, 0x00, 0x00, // 001A: play_music 0
+            0x02, 0x10, 0x11,                   // 0020: add [0x10], [0x11]
+            0x11,                               // 0023: kill
+        ];
+        let mut program = Program::new(0, 0x3E81, code);
+        program.parse()?;
+
+        let res_types = [ResType::Sound, ResType::Music];
+        let mut validator = Validator::new(&res_types);
+        validator.check_program(&program);
+        let issues = validator.finish();
+
+        let kinds: Vec<_> = issues.iter().map(|i| (i.addr, i.kind.clone())).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (0x04, IssueKind::BadTarget { target: 0x05 }),
+                (0x07, IssueKind::UnknownJmpType(6)),
+                (0x07, IssueKind::NeverWritten { var_id: 0x20 }),
+                (
+                    0x0D,
+                    IssueKind::BadThreadRange {
+                        first: 63,
+                        last: 64
+                    }
+                ),
+                (
+                    0x11,
+                    IssueKind::BadResource {
+                        res_id: 1,
+                        expected: "a sound"
+                    }
+                ),
+                (0x20, IssueKind::NeverWritten { var_id: 0x11 }),
+            ]
+        );
+        assert_eq!(issues[2].severity(), Severity::Warning);
+        assert_eq!(
+            issues[0].to_string(),
+            "Part 0x3E81 at 0x0004: error: target 0x0005 is not a command boundary"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+// What differs between the releases of the game: the strings drawn by the bytecode, the
+// resources of every part and the patches that can be applied to the bytecode when it is
+// loaded.
+
+use crate::{
+    integrity::Release,
+    parts::*,
+    staticres::{STRINGS_TABLE_DEMO, STRINGS_TABLE_ENG},
+};
+use std::collections::HashMap;
+
+/// Bytes replaced in the bytecode of a part, where it holds the `original` ones.
+pub struct Patch {
+    pub part_id: u16,
+    pub offset: usize,
+    pub original: &'static [u8],
+    pub bytes: &'static [u8],
+}
+
+// The protection screen accepts any symbols, only applied when asked for.
+const PROTECTION_PATCHES: [Patch; 3] = [
+    // (0x0CB8) condJmp(0x80, VAR(41), VAR(30), 0xCD3)
+    Patch {
+        part_id: GAME_PART1,
+        offset: 0xCB9,
+        original: &[0x80],
+        bytes: &[0x81],
+    },
+    Patch {
+        part_id: GAME_PART1,
+        offset: 0xCBC,
+        original: &[0x0C, 0xD3],
+        bytes: &[0x0D, 0x24],
+    },
+    // (0x0D4E) condJmp(0x4, VAR(50), 6, 0xDBC)
+    Patch {
+        part_id: GAME_PART1,
+        offset: 0xD52,
+        original: &[0x0D, 0xBC],
+        bytes: &[0x0D, 0x5A],
+    },
+];
+
+pub struct Variant {
+    pub release: Release,
+    pub strings: &'static HashMap<u16, &'static str>,
+    /// Palette, bytecode, cinematic and character polygons of every part.
+    pub parts: [[u8; 4]; GAME_NUM_PARTS],
+    pub patches: &'static [Patch],
+}
+
+impl Variant {
+    /// Variant of `release`, the bytecode of the retail data is patched to skip the protection
+    /// screen with `bypass_protection`.
+    pub(crate) fn new(release: Release, bypass_protection: bool) -> Self {
+        match release {
+            Release::DosDemo => Self {
+                release,
+                strings: &STRINGS_TABLE_DEMO,
+                parts: DEMO_MEM_LIST_PARTS,
+                patches: &[],
+            },
+            Release::DosRetail | Release::Unknown => Self {
+                release,
+                strings: &STRINGS_TABLE_ENG,
+                parts: MEM_LIST_PARTS,
+                patches: if bypass_protection {
+                    &PROTECTION_PATCHES
+                } else {
+                    &[]
+                },
+            },
+        }
+    }
+
+    /// Resources of the part, `None` if the release doesn't have it.
+    pub fn part(&self, part_id: u16) -> Option<&[u8; 4]> {
+        let part_idx = part_id.checked_sub(GAME_PART_FIRST)? as usize;
+        self.parts
+            .get(part_idx)
+            .filter(|part| part[MEMLIST_PART_CODE] as usize != MEMLIST_PART_NONE)
+    }
+
+    /// Apply the patches of the part, unless the bytecode doesn't hold the original bytes of
+    /// all of them: it is from another release or modded.
+    pub(crate) fn patch(&self, part_id: u16, code: &mut [u8]) {
+        let patches = || self.patches.iter().filter(|p| p.part_id == part_id);
+
+        let differs =
+            |p: &&Patch| code.get(p.offset..p.offset + p.original.len()) != Some(p.original);
+        if let Some(p) = patches().find(differs) {
+            warning!(
+                "Bytecode of part 0x{:04X} differs at 0x{:04X}, not patching it",
+                part_id,
+                p.offset
+            );
+            return;
+        }
+
+        for p in patches() {
+            code[p.offset..p.offset + p.bytes.len()].copy_from_slice(p.bytes);
+        }
+    }
+}
+
+impl Default for Variant {
+    fn default() -> Self {
+        Self::new(Release::default(), false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_variant() {
+        let retail = Variant::new(Release::DosRetail, false);
+        assert_eq!(retail.part(GAME_PART1), Some(&MEM_LIST_PARTS[0]));
+        assert_eq!(retail.part(GAME_PART10), Some(&MEM_LIST_PARTS[9]));
+        assert_eq!(retail.part(GAME_PART_LAST + 1), None);
+        assert!(retail.patches.is_empty());
+
+        // The demo has every part of the retail game but the password screen.
+        let demo = Variant::new(Release::DosDemo, true);
+        for part_id in GAME_PART_FIRST..=GAME_PART8 {
+            assert_eq!(demo.part(part_id), retail.part(part_id));
+        }
+        assert_eq!(demo.part(GAME_PART9), None);
+        assert_eq!(demo.part(GAME_PART10), None);
+        assert_eq!(demo.strings.get(&0x1F9), Some(&"And sends you ..."));
+        assert!(demo.patches.is_empty());
+    }
+
+    #[test]
+    fn test_patch() {
+        let retail = Variant::new(Release::DosRetail, true);
+        let mut code = vec![0; 0xD54];
+        for p in retail.patches {
+            assert_eq!(p.original.len(), p.bytes.len());
+            code[p.offset..p.offset + p.original.len()].copy_from_slice(p.original);
+        }
+
+        let mut patched = code.clone();
+        retail.patch(GAME_PART1, &mut patched);
+        assert_eq!(&patched[0xCB9..0xCBE], &[0x81, 0, 0, 0x0D, 0x24]);
+        assert_eq!(&patched[0xD52..], &[0x0D, 0x5A]);
+
+        // Other bytes at one of the offsets, or a shorter bytecode, leave it unpatched.
+        let mut modded = code.clone();
+        modded[0xD53] = 0xBD;
+        let expected = modded.clone();
+        retail.patch(GAME_PART1, &mut modded);
+        assert_eq!(modded, expected);
+
+        let mut short = code[..0xD53].to_vec();
+        retail.patch(GAME_PART1, &mut short);
+        assert_eq!(short, &code[..0xD53]);
+
+        let mut other_part = code.clone();
+        retail.patch(GAME_PART2, &mut other_part);
+        assert_eq!(other_part, code);
+    }
+}
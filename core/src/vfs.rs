@@ -0,0 +1,373 @@
+// Virtual file system the game data is read from. The engine only needs to read whole files by
+// name, so a backend can be a directory, a zip archive, files held in memory or a stack of
+// those where a mod overrides some files of the original data.
+
+use crate::error::AwbiError;
+use anyhow::{Context, Result};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt, fs,
+    io::{ErrorKind, Read},
+    path::{Path, PathBuf},
+};
+
+pub trait Vfs: fmt::Debug {
+    /// Content of the file `name`, `None` if there is no such file. Names are matched ignoring
+    /// case as the original data uses DOS names.
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Size of the file `name`, `None` if there is no such file. Backends that can tell it
+    /// without reading the file should.
+    fn size(&self, name: &str) -> Result<Option<u64>> {
+        Ok(self.read(name)?.map(|data| data.len() as u64))
+    }
+}
+
+/// Read the file `name`, failing if it doesn't exist.
+pub(crate) fn read_file(vfs: &dyn Vfs, name: &str) -> Result<Vec<u8>> {
+    vfs.read(name)?.ok_or_else(|| {
+        std::io::Error::new(
+            ErrorKind::NotFound,
+            format!("No '{}' file in {:?}", name, vfs),
+        )
+        .into()
+    })
+}
+
+/// Whether `path` is a zip archive rather than a directory.
+pub(crate) fn is_zip<P: AsRef<Path>>(path: P) -> bool {
+    let path = path.as_ref();
+    path.is_file()
+        && matches!(path.extension().and_then(|ext| ext.to_str()), Some(ext) if ext.eq_ignore_ascii_case("zip"))
+}
+
+/// Directory or zip archive at `path`.
+pub fn open<P: AsRef<Path>>(path: P) -> Box<dyn Vfs> {
+    if is_zip(&path) {
+        Box::new(ZipVfs::new(path))
+    } else {
+        Box::new(DirVfs::new(path))
+    }
+}
+
+#[derive(Debug)]
+pub struct DirVfs {
+    dir: PathBuf,
+}
+
+impl DirVfs {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().into(),
+        }
+    }
+
+    // The exact name first, scanning the directory only when the case differs.
+    fn find(&self, name: &str) -> Result<Option<PathBuf>> {
+        let path = self.dir.join(name);
+        if path.is_file() {
+            return Ok(Some(path));
+        }
+
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).with_context(|| format!("Unable to list {:?}", self.dir)),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            let matches = path
+                .file_name()
+                .and_then(|file_name| file_name.to_str())
+                .is_some_and(|file_name| file_name.eq_ignore_ascii_case(name));
+            if matches && path.is_file() {
+                return Ok(Some(path));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl Vfs for DirVfs {
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        match self.find(name)? {
+            Some(path) => {
+                let data = fs::read(&path).with_context(|| format!("Unable to read {:?}", path))?;
+                Ok(Some(data))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn size(&self, name: &str) -> Result<Option<u64>> {
+        match self.find(name)? {
+            Some(path) => {
+                let metadata =
+                    fs::metadata(&path).with_context(|| format!("Unable to stat {:?}", path))?;
+                Ok(Some(metadata.len()))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Zip archive, opened on the first read.
+pub struct ZipVfs {
+    path: PathBuf,
+    archive: RefCell<Option<zip::ZipArchive<fs::File>>>,
+}
+
+impl ZipVfs {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().into(),
+            archive: RefCell::new(None),
+        }
+    }
+
+    fn bad_zip(&self, err: zip::result::ZipError) -> AwbiError {
+        AwbiError::bad_data(format!("Bad zip archive {:?}: {}", self.path, err))
+    }
+
+    // Call `f` with the entry matching `name`, opening the archive first if needed.
+    fn with_entry<T>(
+        &self,
+        name: &str,
+        f: impl FnOnce(&mut zip::read::ZipFile, &str) -> Result<T>,
+    ) -> Result<Option<T>> {
+        let mut archive = self.archive.borrow_mut();
+        if archive.is_none() {
+            let file = fs::File::open(&self.path)
+                .with_context(|| format!("Unable to open zip archive {:?}", self.path))?;
+            *archive = Some(zip::ZipArchive::new(file).map_err(|err| self.bad_zip(err))?);
+        }
+        let archive = archive.as_mut().unwrap();
+
+        let entry_name = match find_entry(archive.file_names(), name) {
+            Some(entry_name) => entry_name,
+            None => return Ok(None),
+        };
+        let mut entry = archive
+            .by_name(&entry_name)
+            .map_err(|err| self.bad_zip(err))?;
+
+        f(&mut entry, &entry_name).map(Some)
+    }
+}
+
+impl Vfs for ZipVfs {
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        self.with_entry(name, |entry, entry_name| {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).with_context(|| {
+                format!("Unable to unzip '{}' from {:?}", entry_name, self.path)
+            })?;
+            Ok(data)
+        })
+    }
+
+    fn size(&self, name: &str) -> Result<Option<u64>> {
+        self.with_entry(name, |entry, _| Ok(entry.size()))
+    }
+}
+
+impl fmt::Debug for ZipVfs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ZipVfs").field("path", &self.path).finish()
+    }
+}
+
+// Entry with the path `name`, or the file name `name` in any folder of the archive as the data
+// is often zipped in its own folder.
+fn find_entry<'a, I: Iterator<Item = &'a str>>(names: I, name: &str) -> Option<String> {
+    let mut in_folder: Vec<&str> = Vec::new();
+
+    for entry in names {
+        if entry.eq_ignore_ascii_case(name) {
+            return Some(entry.into());
+        }
+
+        let file_name = entry.rsplit('/').next().unwrap_or(entry);
+        if file_name.eq_ignore_ascii_case(name) {
+            in_folder.push(entry);
+        }
+    }
+
+    // The archive order is lost, prefer the shortest path.
+    in_folder.sort_by_key(|entry| (entry.len(), *entry));
+    in_folder.first().map(|entry| entry.to_string())
+}
+
+/// Files held in memory, for tests and embedders shipping the data themselves.
+#[derive(Default)]
+pub struct MemVfs {
+    files: HashMap<String, Vec<u8>>,
+}
+
+impl MemVfs {
+    /// Add or replace the file `name`.
+    pub fn insert(&mut self, name: &str, data: Vec<u8>) {
+        self.files.insert(name.to_ascii_lowercase(), data);
+    }
+}
+
+impl Vfs for MemVfs {
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.files.get(&name.to_ascii_lowercase()).cloned())
+    }
+
+    fn size(&self, name: &str) -> Result<Option<u64>> {
+        let data = self.files.get(&name.to_ascii_lowercase());
+        Ok(data.map(|data| data.len() as u64))
+    }
+}
+
+impl fmt::Debug for MemVfs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<_> = self.files.keys().collect();
+        names.sort();
+        f.debug_struct("MemVfs").field("files", &names).finish()
+    }
+}
+
+/// Layers searched in order, a file of an earlier layer hides the same file of the later ones.
+#[derive(Debug)]
+pub struct OverlayVfs {
+    layers: Vec<Box<dyn Vfs>>,
+}
+
+impl OverlayVfs {
+    pub fn new(layers: Vec<Box<dyn Vfs>>) -> Self {
+        Self { layers }
+    }
+}
+
+impl Vfs for OverlayVfs {
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        for layer in &self.layers {
+            if let Some(data) = layer.read(name)? {
+                return Ok(Some(data));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn size(&self, name: &str) -> Result<Option<u64>> {
+        for layer in &self.layers {
+            if let Some(size) = layer.size(name)? {
+                return Ok(Some(size));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::Storage, util::fixture_dir};
+    use std::io::Write;
+    use zip::{write::FileOptions, ZipWriter};
+
+    fn buffers(storage: &Storage) -> Vec<Vec<u8>> {
+        let entries = &storage.mem_list.entries;
+        entries.iter().map(|me| me.buffer.clone()).collect()
+    }
+
+    fn load(vfs: Box<dyn Vfs>) -> Result<Storage> {
+        let mut storage = Storage::new(vfs);
+        storage.load()?;
+        storage.load_all()?;
+        Ok(storage)
+    }
+
+    #[test]
+    fn test_zip() -> Result<()> {
+        let names = [
+            "AW/readme.txt",
+            "AW/MEMLIST.BIN",
+            "memlist.bin.bak",
+            "Bank01",
+        ];
+        assert_eq!(
+            find_entry(names.iter().copied(), "memlist.bin"),
+            Some("AW/MEMLIST.BIN".into())
+        );
+        assert_eq!(
+            find_entry(names.iter().copied(), "bank01"),
+            Some("Bank01".into())
+        );
+        assert_eq!(find_entry(names.iter().copied(), "bank02"), None);
+
+        // The fixtures zipped in a folder with DOS names.
+        let data_dir = fixture_dir()?;
+        let zip_path = data_dir.with_file_name("fixtures.zip");
+        let mut zip = ZipWriter::new(fs::File::create(&zip_path)?);
+        for entry in fs::read_dir(&data_dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap().to_string_lossy().to_uppercase();
+            zip.start_file(format!("DATA/{}", name), FileOptions::default())?;
+            zip.write_all(&fs::read(&path)?)?;
+        }
+        zip.finish()?;
+
+        assert!(is_zip(&zip_path));
+        assert!(!is_zip(&data_dir));
+
+        let vfs = open(&zip_path);
+        assert_eq!(
+            vfs.read("bank01")?,
+            Some(fs::read(data_dir.join("bank01"))?)
+        );
+        assert_eq!(vfs.read("bank0e")?, None);
+        assert_eq!(
+            vfs.size("BANK01")?,
+            Some(fs::metadata(data_dir.join("bank01"))?.len())
+        );
+        assert_eq!(vfs.size("bank0e")?, None);
+        assert!(read_file(&*vfs, "bank0e").is_err());
+        assert!(open(data_dir.join("missing.zip")).read("bank01")?.is_none());
+
+        let from_dir = load(open(&data_dir))?;
+        assert_eq!(buffers(&load(vfs)?), buffers(&from_dir));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mem_and_overlay() -> Result<()> {
+        let data_dir = fixture_dir()?;
+        let from_dir = load(open(&data_dir))?;
+
+        let mut mem = MemVfs::default();
+        for entry in fs::read_dir(&data_dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap().to_string_lossy().to_uppercase();
+            mem.insert(&name, fs::read(&path)?);
+        }
+        assert_eq!(mem.read("memlist.bin")?, mem.read("MEMLIST.BIN")?);
+        assert_eq!(buffers(&load(Box::new(mem))?), buffers(&from_dir));
+
+        // A mod replacing the second bank with a copy of the first one.
+        let mut patch = MemVfs::default();
+        patch.insert("bank02", fs::read(data_dir.join("bank01"))?);
+        patch.insert("readme.txt", b"mod".to_vec());
+        let overlay = OverlayVfs::new(vec![Box::new(patch), Box::new(DirVfs::new(&data_dir))]);
+        assert_eq!(overlay.read("README.TXT")?, Some(b"mod".to_vec()));
+        assert_eq!(overlay.read("bank02")?, overlay.read("bank01")?);
+        assert_eq!(
+            overlay.read("bank03")?,
+            Some(fs::read(data_dir.join("bank03"))?)
+        );
+        assert_eq!(overlay.read("bank0e")?, None);
+        assert_eq!(overlay.size("bank02")?, overlay.size("bank01")?);
+        assert_eq!(overlay.size("readme.txt")?, Some(3));
+        assert_eq!(overlay.size("bank0e")?, None);
+
+        Ok(())
+    }
+}
@@ -1,9 +1,13 @@
+use crate::error::AwbiError;
+use crate::font::Glyph;
+use crate::logging::DBG_VIDEO;
+use crate::profiler::{Primitive, ProfilerRef};
 use crate::resource::*;
 use crate::system::*;
+use crate::util::w_mul_i16;
 use crate::{file::File, slice_reader::SliceReader};
 use crate::{serializer::*, util::w_add_u32};
-use crate::{staticres::*, util::w_mul_i16};
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use std::cmp::Ordering;
 
 struct StrEntry {
@@ -24,6 +28,44 @@ impl Point {
 }
 
 const MAX_POINTS: usize = 50;
+// Nesting of polygon hierarchies, deeper ones are loops in corrupt data.
+const MAX_HIERARCHY_DEPTH: usize = 16;
+
+// Lines of a subtitle drawn at the bottom of the screen, the first ones are left out.
+const MAX_CAPTION_LINES: usize = 4;
+
+// Draw the glyph at the column `x` of 8 pixels and the line `y`, clipped out if it doesn't fit.
+fn draw_glyph(page: &mut [u8], glyph: &Glyph, x: u16, y: u16, color: u8) {
+    if x <= 39 && y <= 192 {
+        let mut buf_off = (x * 4 + y * 160) as usize;
+
+        for &row in glyph.iter() {
+            let mut ch = row;
+            for i in 0..4 {
+                let b = page[buf_off + i];
+                let mut cmask = 0xFF;
+                let mut colb = 0;
+                if ch & 0x80 != 0 {
+                    colb |= color << 4;
+                    cmask &= 0x0F;
+                }
+                ch <<= 1;
+                if ch & 0x80 != 0 {
+                    colb |= color;
+                    cmask &= 0xF0;
+                }
+                ch <<= 1;
+                page[buf_off + i] = (b & cmask) | colb;
+            }
+            buf_off += 160;
+        }
+    }
+}
+
+// Coordinates and sizes are scaled with the int promotion of the original engine.
+fn zoomed(v: u8, zoom: u16) -> u16 {
+    (v as u32 * zoom as u32 / 64) as u16
+}
 
 struct Polygon {
     bbw: u16,
@@ -42,17 +84,27 @@ impl Polygon {
         }
     }
 
-    fn read_vertices(&mut self, buf: &mut SliceReader, zoom: u16) {
-        self.bbw = buf.read_u8() as u16 * zoom / 64;
-        self.bbh = buf.read_u8() as u16 * zoom / 64;
-        self.num_points = buf.read_u8();
-        assert!((self.num_points & 1) == 0 && (self.num_points as usize) < MAX_POINTS);
+    fn read_vertices(&mut self, buf: &mut SliceReader, zoom: u16) -> Result<()> {
+        self.bbw = zoomed(buf.read_u8()?, zoom);
+        self.bbh = zoomed(buf.read_u8()?, zoom);
+        self.num_points = buf.read_u8()?;
+        ensure!(
+            (self.num_points & 1) == 0
+                && self.num_points != 0
+                && (self.num_points as usize) < MAX_POINTS,
+            AwbiError::bad_data(format!(
+                "Polygon::read_vertices() invalid number of points {}",
+                self.num_points
+            ))
+        );
 
         //Read all points, directly from bytecode segment
         for pt in &mut self.points[0..self.num_points as usize] {
-            pt.x = (buf.read_u8() as u16 * zoom / 64) as i16;
-            pt.y = (buf.read_u8() as u16 * zoom / 64) as i16;
+            pt.x = zoomed(buf.read_u8()?, zoom) as i16;
+            pt.y = zoomed(buf.read_u8()?, zoom) as i16;
         }
+
+        Ok(())
     }
 }
 
@@ -62,15 +114,17 @@ pub const END_OF_STRING_DICTIONARY: u16 = 0xFFFF;
 // Special value when no palette change is necessary
 const NO_PALETTE_CHANGE_REQUESTED: u8 = 0xFF;
 
-const VID_PAGE_SIZE: usize = 320 * 200 / 2;
+pub(crate) const VID_PAGE_SIZE: usize = 320 * 200 / 2;
 
 pub(crate) struct Video {
     // typedef void (Video::*drawLine)(int16_t x1, int16_t x2, uint8_t col);
     res: ResourceRef,
     sys: SystemRef,
+    profiler: ProfilerRef,
 
     pub palette_id_requested: u8,
     current_palette_id: u8,
+    palette: [u8; NUM_COLORS * BYTE_PER_PIXEL],
     // page_offsets: [usize; 4];
 
     // I am almost sure that:
@@ -90,17 +144,20 @@ pub(crate) struct Video {
     data_page_idx: usize,
     data: SliceReader,
 
-    pages_buf: [[u8; VID_PAGE_SIZE]; 4],
+    // On the heap, the engine holding the video is moved around by value.
+    pages_buf: Vec<[u8; VID_PAGE_SIZE]>,
     mask: u8,
 }
 
 impl Video {
-    pub fn new(res: ResourceRef, sys: SystemRef) -> Self {
+    pub fn new(res: ResourceRef, sys: SystemRef, profiler: ProfilerRef) -> Self {
         Self {
             res,
             sys,
+            profiler,
             palette_id_requested: 0,
             current_palette_id: 0,
+            palette: [0; NUM_COLORS * BYTE_PER_PIXEL],
             // page_offsets: [],
             cur_page_idx1: 0,
             cur_page_idx2: 0,
@@ -110,7 +167,7 @@ impl Video {
             interp_table: [0; 0x400],
             data_page_idx: 0,
             data: Default::default(),
-            pages_buf: [[0; VID_PAGE_SIZE]; 4],
+            pages_buf: vec![[0; VID_PAGE_SIZE]; 4],
             mask: 0,
         }
     }
@@ -142,15 +199,17 @@ impl Video {
         }
     }
 
-    pub(crate) fn set_data_page(&mut self, cinematic: bool, offset: usize) {
+    pub(crate) fn set_data_page(&mut self, cinematic: bool, offset: usize) -> Result<()> {
         self.data_page_idx = if cinematic {
             self.res.get().seg_cinematic_idx()
         } else {
             self.res.get().seg_video2_idx()
         };
 
-        self.data = self.res.get().get_entry_data(self.data_page_idx).into();
+        self.data = self.res.get().get_entry_data(self.data_page_idx)?.into();
         self.data.set_pos(offset);
+
+        Ok(())
     }
 
     // A shape can be given in two different ways:
@@ -158,8 +217,32 @@ impl Video {
     // 	- A list of object space vertices, based on a delta from the first vertex.
 
     // 	This is a recursive function.
-    pub(crate) fn read_and_draw_polygon(&mut self, mut color: u8, zoom: u16, pt: Point) {
-        let mut i = self.data.read_u8();
+    pub(crate) fn read_and_draw_polygon(&mut self, color: u8, zoom: u16, pt: Point) -> Result<()> {
+        self.read_and_draw_shape(color, zoom, pt, 0)
+    }
+
+    /// Draw the shape at `offset` of `data` instead of a resource.
+    pub(crate) fn draw_shape_data(
+        &mut self,
+        data: &[u8],
+        offset: usize,
+        color: u8,
+        zoom: u16,
+        pt: Point,
+    ) -> Result<()> {
+        self.data = data.into();
+        self.data.set_pos(offset);
+        self.read_and_draw_polygon(color, zoom, pt)
+    }
+
+    fn read_and_draw_shape(
+        &mut self,
+        mut color: u8,
+        zoom: u16,
+        pt: Point,
+        depth: usize,
+    ) -> Result<()> {
+        let mut i = self.data.read_u8()?;
 
         // 0xc0 = 192
         if i >= 0xC0 {
@@ -173,37 +256,48 @@ impl Video {
             // vertices information.
             let old_pos = self.data.pos();
 
-            self.polygon.read_vertices(&mut self.data, zoom);
+            self.polygon.read_vertices(&mut self.data, zoom)?;
 
             self.data.set_pos(old_pos);
 
-            self.fill_polygon(color, zoom, pt);
+            let start = self.profiler.get().start();
+            let res = self.fill_polygon(color, zoom, pt);
+            self.profiler
+                .get_mut()
+                .finish(Primitive::FillPolygon, start);
+            res?;
         } else {
             i &= 0x3F; //0x3F = 63
             if i == 1 {
-                println!("Video::read_and_draw_polygon() ec=0xF80 (i != 2)");
+                warning!("Video::read_and_draw_polygon() ec=0xF80 (i != 2)");
             } else if i == 2 {
-                self.read_and_draw_polygon_hierarchy(zoom, pt);
+                ensure!(
+                    depth < MAX_HIERARCHY_DEPTH,
+                    AwbiError::bad_data("Video::read_and_draw_polygon() hierarchy is too deep")
+                );
+                self.read_and_draw_polygon_hierarchy(zoom, pt, depth + 1)?;
             } else {
-                println!("Video::read_and_draw_polygon() ec=0xFBB (i != 2)");
+                warning!("Video::read_and_draw_polygon() ec=0xFBB (i != 2)");
             }
         }
+
+        Ok(())
     }
 
-    fn fill_polygon(&mut self, color: u8, _zoom: u16, pt: Point) {
+    fn fill_polygon(&mut self, color: u8, _zoom: u16, pt: Point) -> Result<()> {
         if self.polygon.bbw == 0 && self.polygon.bbh == 1 && self.polygon.num_points == 4 {
             self.draw_point(color, pt.x, pt.y);
 
-            return;
+            return Ok(());
         }
 
-        let mut x1 = pt.x - (self.polygon.bbw / 2) as i16;
-        let mut x2 = pt.x + (self.polygon.bbw / 2) as i16;
-        let y1 = pt.y - (self.polygon.bbh / 2) as i16;
-        let y2 = pt.y + (self.polygon.bbh / 2) as i16;
+        let mut x1 = pt.x.wrapping_sub((self.polygon.bbw / 2) as i16);
+        let mut x2 = pt.x.wrapping_add((self.polygon.bbw / 2) as i16);
+        let y1 = pt.y.wrapping_sub((self.polygon.bbh / 2) as i16);
+        let y2 = pt.y.wrapping_add((self.polygon.bbh / 2) as i16);
 
         if x1 > 319 || x2 < 0 || y1 > 199 || y2 < 0 {
-            return;
+            return Ok(());
         }
 
         self.hliney = y1;
@@ -212,8 +306,8 @@ impl Video {
         // let mut j = w_sub(self.polygon.num_points, 1) as usize;
         let mut j = (self.polygon.num_points - 1) as usize;
 
-        x2 = self.polygon.points[i].x + x1;
-        x1 = self.polygon.points[j].x + x1;
+        x2 = self.polygon.points[i].x.wrapping_add(x1);
+        x1 = self.polygon.points[j].x.wrapping_add(x1);
 
         i += 1;
         j -= 1;
@@ -241,8 +335,8 @@ impl Video {
                 break;
             }
 
-            let (step1, _) = self.calc_step(self.polygon.points[j + 1], self.polygon.points[j]);
-            let (step2, h) = self.calc_step(self.polygon.points[i - 1], self.polygon.points[i]);
+            let (step1, _) = self.calc_step(self.polygon.points[j + 1], self.polygon.points[j])?;
+            let (step2, h) = self.calc_step(self.polygon.points[i - 1], self.polygon.points[i])?;
 
             i += 1;
             j -= 1;
@@ -251,8 +345,8 @@ impl Video {
             cpt2 = (cpt2 & 0xFFFF_0000) | 0x8000;
 
             if h == 0 {
-                cpt1 += step1 as u32;
-                cpt2 += step2 as u32;
+                cpt1 = w_add_u32(cpt1, step1 as _);
+                cpt2 = w_add_u32(cpt2, step2 as _);
             } else {
                 for _ in 0..h {
                     if self.hliney >= 0 {
@@ -281,7 +375,7 @@ impl Video {
                     // cpt2 += step2 as u32;
                     self.hliney += 1;
                     if self.hliney > 199 {
-                        return;
+                        return Ok(());
                     }
                 }
             }
@@ -294,99 +388,137 @@ impl Video {
             // 	dumpBackGroundBuffer();
             // #endif
         }
+
+        Ok(())
     }
 
     // What is read from the bytecode is not a pure screen space polygon but a polygon space polygon.
-    fn read_and_draw_polygon_hierarchy(&mut self, zoom: u16, pgc: Point) {
+    fn read_and_draw_polygon_hierarchy(
+        &mut self,
+        zoom: u16,
+        pgc: Point,
+        depth: usize,
+    ) -> Result<()> {
         let mut pt = pgc;
-        pt.x -= (self.data.read_u8() as u16 * zoom / 64) as i16;
-        pt.y -= (self.data.read_u8() as u16 * zoom / 64) as i16;
+        pt.x = pt.x.wrapping_sub(zoomed(self.data.read_u8()?, zoom) as i16);
+        pt.y = pt.y.wrapping_sub(zoomed(self.data.read_u8()?, zoom) as i16);
 
-        let children = self.data.read_u8();
-        // debug(DBG_VIDEO, "Video::readAndDrawPolygonHierarchy childs=%d", childs);
+        let children = self.data.read_u8()?;
+        debug!(
+            DBG_VIDEO,
+            "Video::read_and_draw_polygon_hierarchy children={}", children
+        );
 
         for _ in 0..children {
-            let mut off = self.data.read_u16();
+            let mut off = self.data.read_u16()?;
             let mut po = pt;
 
-            po.x += (self.data.read_u8() as u16 * zoom / 64) as i16;
-            po.y += (self.data.read_u8() as u16 * zoom / 64) as i16;
+            po.x = po.x.wrapping_add(zoomed(self.data.read_u8()?, zoom) as i16);
+            po.y = po.y.wrapping_add(zoomed(self.data.read_u8()?, zoom) as i16);
 
             let mut color = 0xFF;
             let bp = off;
             off &= 0x7FFF;
 
             if bp & 0x8000 != 0 {
-                color = self.data.read_u8() & 0x7F;
-                self.data.read_u8();
+                color = self.data.read_u8()? & 0x7F;
+                self.data.read_u8()?;
             }
 
             let old_pos = self.data.pos();
             self.data.set_pos((off * 2) as usize);
 
-            self.read_and_draw_polygon(color, zoom, po);
+            self.read_and_draw_shape(color, zoom, po, depth)?;
 
             self.data.set_pos(old_pos);
         }
+
+        Ok(())
     }
 
-    fn calc_step(&self, p1: Point, p2: Point) -> (i16, usize) {
-        let dy = (p2.y - p1.y) as usize;
-        let dx = w_mul_i16(w_mul_i16(p2.x - p1.x, self.interp_table[dy] as i16), 4);
-        (dx, dy)
+    fn calc_step(&self, p1: Point, p2: Point) -> Result<(i16, usize)> {
+        let dy = p2.y.wrapping_sub(p1.y) as u16 as usize;
+        let interp = *self.interp_table.get(dy).ok_or_else(|| {
+            AwbiError::bad_data(format!(
+                "Video::calc_step() invalid polygon edge height {}",
+                dy
+            ))
+        })?;
+        let dx = w_mul_i16(w_mul_i16(p2.x.wrapping_sub(p1.x), interp as i16), 4);
+        Ok((dx, dy))
     }
 
     pub(crate) fn draw_string(&mut self, color: u8, mut x: u16, mut y: u16, string_id: u16) {
-        if let Some(se) = STRINGS_TABLE_ENG.get(&string_id) {
-            // debug(DBG_VIDEO, "drawString(%d, %d, %d, '%s')", color, x, y, se->str);
+        let start = self.profiler.get().start();
+
+        // The glyphs are looked up before drawing, the resources can't stay borrowed.
+        let res = self.res.get();
+        let text = res.string(string_id).map(|se| {
+            let glyphs: Vec<Glyph> = se.chars().map(|ch| *res.font().glyph(ch)).collect();
+            (se.to_owned(), glyphs)
+        });
+        drop(res);
+
+        if let Some((se, glyphs)) = text {
+            debug!(DBG_VIDEO, "draw_string({}, {}, {}, '{}')", color, x, y, se);
 
             //Used if the string contains a return carriage.
             let x_origin = x;
 
-            for ch in se.chars() {
+            for (ch, glyph) in se.chars().zip(glyphs.iter()) {
                 if ch == '\n' {
                     y += 8;
                     x = x_origin;
                     continue;
                 }
 
-                self.draw_char(ch, x, y, color, self.cur_page_idx1);
+                draw_glyph(&mut self.pages_buf[self.cur_page_idx1], glyph, x, y, color);
                 x += 1;
             }
         }
+
+        self.profiler.get_mut().finish(Primitive::DrawString, start);
     }
 
-    fn draw_char(&mut self, character: char, x: u16, y: u16, color: u8, idx: usize) {
-        if x <= 39 && y <= 192 {
-            let font_off = (character as u8 - b' ') as usize * 8;
-
-            let mut buf_off = (x * 4 + y * 160) as usize;
-
-            for j in 0..8 {
-                let mut ch = FONT[font_off + j];
-                for i in 0..4 {
-                    let b = self.pages_buf[idx][buf_off + i];
-                    let mut cmask = 0xFF;
-                    let mut colb = 0;
-                    if ch & 0x80 != 0 {
-                        colb |= color << 4;
-                        cmask &= 0x0F;
-                    }
-                    ch <<= 1;
-                    if ch & 0x80 != 0 {
-                        colb |= color;
-                        cmask &= 0xF0;
-                    }
-                    ch <<= 1;
-                    self.pages_buf[idx][buf_off + i] = (b & cmask) | colb;
-                }
-                buf_off += 160;
+    // Caption lines centered at the bottom of `page`, in the brightest color of the palette on
+    // a band of the darkest one.
+    fn draw_caption(&self, page: &mut [u8; VID_PAGE_SIZE], caption: &str) {
+        let brightness = |color: &usize| {
+            let rgb = &self.palette[color * BYTE_PER_PIXEL..][..BYTE_PER_PIXEL];
+            rgb.iter().map(|c| *c as u16).sum::<u16>()
+        };
+        let text_color = (0..NUM_COLORS).max_by_key(brightness).unwrap_or(0) as u8;
+        let band_color = (0..NUM_COLORS).min_by_key(brightness).unwrap_or(0) as u8;
+
+        let lines: Vec<&str> = caption.lines().rev().take(MAX_CAPTION_LINES).collect();
+        if lines.is_empty() {
+            return;
+        }
+        let top = 200 - 4 - lines.len() * 8;
+        page[(top - 2) * 160..198 * 160].fill(band_color << 4 | band_color);
+
+        let res = self.res.get();
+        for (i, line) in lines.iter().rev().enumerate() {
+            let glyphs: Vec<&Glyph> = line
+                .chars()
+                .take(40)
+                .map(|ch| res.font().glyph(ch))
+                .collect();
+            let x = (40 - glyphs.len()) / 2;
+            for (col, glyph) in glyphs.into_iter().enumerate() {
+                draw_glyph(
+                    page,
+                    glyph,
+                    (x + col) as u16,
+                    (top + i * 8) as u16,
+                    text_color,
+                );
             }
         }
     }
 
     fn draw_point(&mut self, color: u8, x: i16, y: i16) {
-        // debug(DBG_VIDEO, "drawPoint(%d, %d, %d)", color, x, y);
+        trace!(DBG_VIDEO, "draw_point({}, {}, {})", color, x, y);
         if x >= 0 && x <= 319 && y >= 0 && y <= 199 {
             let off = (y * 160 + x / 2) as usize;
 
@@ -412,8 +544,8 @@ impl Video {
     }
 
     // Blend a line in the current framebuffer (_curPagePtr1)
-    fn draw_line_blend(&mut self, x1: i16, x2: i16, _color: u8) {
-        // debug(DBG_VIDEO, "drawLineBlend(%d, %d, %d)", x1, x2, color);
+    fn draw_line_blend(&mut self, x1: i16, x2: i16, color: u8) {
+        trace!(DBG_VIDEO, "draw_line_blend({}, {}, {})", x1, x2, color);
         let xmax = std::cmp::max(x1, x2);
         let xmin = std::cmp::min(x1, x2);
         let mut off = (self.hliney * 160 + xmin / 2) as usize;
@@ -453,7 +585,7 @@ impl Video {
     }
 
     fn draw_line_n(&mut self, x1: i16, x2: i16, color: u8) {
-        // debug(DBG_VIDEO, "drawLineN(%d, %d, %d)", x1, x2, color);
+        trace!(DBG_VIDEO, "draw_line_n({}, {}, {})", x1, x2, color);
         let xmax = std::cmp::max(x1, x2);
         let xmin = std::cmp::min(x1, x2);
         let mut off = (self.hliney * 160 + xmin / 2) as usize;
@@ -490,8 +622,8 @@ impl Video {
         }
     }
 
-    fn draw_line_p(&mut self, x1: i16, x2: i16, _color: u8) {
-        // debug(DBG_VIDEO, "drawLineP(%d, %d, %d)", x1, x2, color);
+    fn draw_line_p(&mut self, x1: i16, x2: i16, color: u8) {
+        trace!(DBG_VIDEO, "draw_line_p({}, {}, {})", x1, x2, color);
         let xmax = std::cmp::max(x1, x2);
         let xmin = std::cmp::min(x1, x2);
         let mut off = (self.hliney * 160 + xmin / 2) as usize;
@@ -537,18 +669,21 @@ impl Video {
         } else if page_id == 0xFE {
             self.cur_page_idx2
         } else {
-            // warning("Video::getPagePtr() p != [0,1,2,3,0xFF,0xFE] == 0x%X", page);
+            warning!(
+                "Video::get_page_off() p != [0,1,2,3,0xFF,0xFE] == {:#X}",
+                page_id
+            );
             0 // XXX check
         }
     }
 
     pub(crate) fn change_page_off1(&mut self, page: usize) {
-        // debug(DBG_VIDEO, "Video::changePagePtr1(%d)", page);
+        debug!(DBG_VIDEO, "Video::change_page_off1({})", page);
         self.cur_page_idx1 = self.get_page_off(page);
     }
 
     pub(crate) fn fill_page(&mut self, page: usize, color: u8) {
-        // debug(DBG_VIDEO, "Video::fillPage(%d, %d)", page, color);
+        debug!(DBG_VIDEO, "Video::fill_page({}, {})", page, color);
         let page_off = self.get_page_off(page);
 
         // Since a palette indice is coded on 4 bits, we need to duplicate the
@@ -569,14 +704,18 @@ impl Video {
     // This opcode is used once the background of a scene has been drawn in one of the framebuffer:
     // it is copied in the current framebuffer at the start of a new frame in order to improve performances.
     pub(crate) fn copy_page(&mut self, src_page_id: usize, dst_page_id: usize, vscroll: i16) {
-        // debug(DBG_VIDEO, "Video::copyPage(%d, %d)", srcPageId, dstPageId);
+        debug!(
+            DBG_VIDEO,
+            "Video::copy_page({}, {})", src_page_id, dst_page_id
+        );
 
         if src_page_id == dst_page_id {
             return;
         }
 
+        let start = self.profiler.get().start();
         let src_mask = src_page_id & 0xBF;
-        let mut q = self.get_page_off(dst_page_id);
+        let q = self.get_page_off(dst_page_id);
 
         if src_page_id >= 0xFE {
             let p = self.get_page_off(src_page_id);
@@ -587,32 +726,40 @@ impl Video {
 
             self.pages_buf[q] = self.pages_buf[p];
         } else {
-            let mut p = self.get_page_off(src_mask & 3);
+            let p = self.get_page_off(src_mask & 3);
 
             if vscroll >= -199 && vscroll <= 199 {
                 let mut h = 200;
+                let mut src_off = 0;
+                let mut dst_off = 0;
 
                 if vscroll < 0 {
                     h += vscroll as usize;
-                    p += -vscroll as usize * 160;
+                    src_off = -vscroll as usize * 160;
                 } else {
                     h -= vscroll as usize;
-                    q += vscroll as usize * 160;
+                    dst_off = vscroll as usize * 160;
                 }
 
+                let len = h * 160;
+
                 if p < q {
                     let (p_arr, q_arr) = self.pages_buf.split_at_mut(q);
-                    q_arr[0][..h * 160].clone_from_slice(&p_arr[p][..h * 160]);
+                    q_arr[0][dst_off..dst_off + len]
+                        .copy_from_slice(&p_arr[p][src_off..src_off + len]);
                 } else if q < p {
                     let (q_arr, p_arr) = self.pages_buf.split_at_mut(p);
-                    q_arr[q][..h * 160].clone_from_slice(&p_arr[0][..h * 160]);
+                    q_arr[q][dst_off..dst_off + len]
+                        .copy_from_slice(&p_arr[0][src_off..src_off + len]);
                 } else {
-                    // TODO: error
-                    todo!();
+                    // Scrolling inside the same page, the regions may overlap.
+                    self.pages_buf[p].copy_within(src_off..src_off + len, dst_off);
                 }
             }
         }
 
+        self.profiler.get_mut().finish(Primitive::CopyPage, start);
+
         // #if TRACE_FRAMEBUFFER
         // char name[256];
         // memset(name,0,sizeof(name));
@@ -622,7 +769,7 @@ impl Video {
     }
 
     fn copy_page_data(&mut self, src: &mut [u8]) {
-        // debug(DBG_VIDEO, "Video::copyPagePtr()");
+        debug!(DBG_VIDEO, "Video::copy_page_data()");
         let mut src_idx = 0;
         let mut dst_idx = 0;
 
@@ -662,20 +809,25 @@ impl Video {
             return;
         }
 
-        let mut pal_idx = pal_num * 32; //colors are coded on 2bytes (565) for 16 colors = 32
-                                        // res->segPalettes
+        let pal_idx = pal_num * 32; //colors are coded on 2bytes (565) for 16 colors = 32
+                                    // res->segPalettes
 
         // Moved to the heap, legacy code used to allocate the palette
         // on the stack.
         let mut palette = [0u8; NUM_COLORS * BYTE_PER_PIXEL];
         let res = self.res.get();
 
-        for i in 0..NUM_COLORS {
-            let c = res.read_palette(pal_idx, 2);
-
-            pal_idx += 2;
+        // A short palette resource keeps the current palette.
+        let colors = match res.read_palette(pal_idx, NUM_COLORS * 2) {
+            Ok(colors) => colors,
+            Err(err) => {
+                warning!("{}", err);
+                return;
+            }
+        };
 
-            palette[i * 3 + 0] = ((c[0] & 0x0F) << 2) | ((c[0] & 0x0F) >> 2); // r
+        for (i, c) in colors.chunks_exact(2).enumerate() {
+            palette[i * 3] = ((c[0] & 0x0F) << 2) | ((c[0] & 0x0F) >> 2); // r
             palette[i * 3 + 1] = ((c[1] & 0xF0) >> 2) | ((c[1] & 0xF0) >> 6); // g
             palette[i * 3 + 2] = ((c[1] & 0x0F) >> 2) | ((c[1] & 0x0F) << 2); // b
         }
@@ -684,6 +836,7 @@ impl Video {
             .get_mut()
             .set_palette(0, NUM_COLORS as u8, &palette);
         self.current_palette_id = pal_num as u8;
+        self.palette = palette;
 
         // #if TRACE_PALETTE
         // printf("\nuint8_t dumpPalette[48] = {\n");
@@ -699,8 +852,9 @@ impl Video {
         // #endif
     }
 
-    pub(crate) fn update_display(&mut self, page: usize) {
-        // debug(DBG_VIDEO, "Video::updateDisplay(%d)", pageId);
+    /// Show the page `page`, with the subtitle `caption` over it.
+    pub(crate) fn update_display(&mut self, page: usize, caption: Option<&str>) {
+        debug!(DBG_VIDEO, "Video::update_display({})", page);
 
         if page != 0xFE {
             if page == 0xFF {
@@ -719,9 +873,24 @@ impl Video {
         //Q: Why 160 ?
         //A: Because one byte gives two palette indices so
         //   we only need to move 320/2 per line.
-        self.sys
-            .get_mut()
-            .copy_rect(0, 0, 320, 200, &self.pages_buf[self.cur_page_idx2][..], 160);
+        match caption {
+            // On a copy, the scripts draw over the pages they show.
+            Some(caption) => {
+                let mut page = self.pages_buf[self.cur_page_idx2];
+                self.draw_caption(&mut page, caption);
+                self.sys.get_mut().copy_rect(0, 0, 320, 200, &page, 160);
+            }
+            None => {
+                self.sys.get_mut().copy_rect(
+                    0,
+                    0,
+                    320,
+                    200,
+                    &self.pages_buf[self.cur_page_idx2][..],
+                    160,
+                );
+            }
+        }
 
         // #if TRACE_FRAMEBUFFER
         // 	  dumpFrameBuffer(_curPagePtr2,allFrameBuffers,320,200);
@@ -1,14 +1,30 @@
-use crate::{program::Program, resource::*, serializer::*, system::*, vm_context::*};
-use anyhow::Result;
+use crate::{
+    coverage::Coverage,
+    crash_dump::CrashDump,
+    debugger::*,
+    error::{AwbiError, VmFault},
+    logging::DBG_VM,
+    parts::*,
+    profiler::{Profiler, ProfilerRef},
+    program::Program,
+    reference::Ref,
+    resource::*,
+    serializer::*,
+    subtitles::Subtitles,
+    symbols::Symbols,
+    system::*,
+    trace::TraceWriter,
+    vm_context::*,
+    watchdog::{LoopPolicy, Watchdog},
+};
+use anyhow::{ensure, Result};
 
 use std::{collections::HashMap, fmt};
 
-trace::init_depth_var!();
-
 const VM_NO_SETVEC_REQUESTED: u16 = 0xFFFF;
 const VM_INACTIVE_THREAD: u16 = 0xFFFF;
 
-pub(crate) struct VirtualMachine {
+pub struct VirtualMachine {
     sys: SystemRef,
     res: ResourceRef,
 
@@ -19,12 +35,23 @@ pub(crate) struct VirtualMachine {
     ctx: VmContext,
     programs: HashMap<usize, Program>,
     program_id: usize,
+
+    debugger: Debugger,
+    // Thread to resume when the debugger stopped in the middle of a frame.
+    frame_thread: Option<usize>,
+    // Thread and address of the last failed command.
+    fault_location: Option<(usize, u16)>,
+    watchdog: Watchdog,
+    coverage: Option<Coverage>,
+    profiler: ProfilerRef,
+    trace: Option<TraceWriter>,
 }
 
 impl VirtualMachine {
-    pub fn new(res: ResourceRef, sys: SystemRef) -> Self {
+    pub(crate) fn new(res: ResourceRef, sys: SystemRef) -> Self {
         let code_idx = res.get().seg_code_idx();
-        let ctx = VmContext::new(sys.clone(), res.clone());
+        let profiler = Ref::new(Box::new(Profiler::default()));
+        let ctx = VmContext::new(sys.clone(), res.clone(), profiler.clone());
 
         Self {
             sys,
@@ -36,26 +63,35 @@ impl VirtualMachine {
             ctx,
             programs: HashMap::new(),
             program_id: 0,
+            debugger: Debugger::default(),
+            frame_thread: None,
+            fault_location: None,
+            watchdog: Watchdog::default(),
+            coverage: None,
+            profiler,
+            trace: None,
         }
     }
 
-    // #[trace]
-    pub fn init(&mut self) {
+    pub(crate) fn init(&mut self) {
         self.ctx.init();
     }
 
-    // #[trace]
-    pub fn init_for_part(&mut self, part_id: u16) -> Result<()> {
+    pub(crate) fn init_for_part(&mut self, part_id: u16) -> Result<()> {
         self.ctx.init_for_part(part_id)?;
 
+        self.load_program()
+    }
+
+    // Switch to the bytecode of the current part.
+    pub(crate) fn load_program(&mut self) -> Result<()> {
         self.program_id = self.res.get().seg_code_idx();
+        self.frame_thread = None;
 
-        if self.programs.get(&self.program_id).is_none() {
-            let mut program = Program::new(
-                self.program_id,
-                part_id,
-                self.res.get().get_entry_data(self.program_id).into(),
-            );
+        if !self.programs.contains_key(&self.program_id) {
+            let mut code = self.res.get().get_entry_data(self.program_id)?.to_vec();
+            self.res.get().variant().patch(self.part_id(), &mut code);
+            let mut program = Program::new(self.program_id, self.part_id(), code);
 
             program.parse()?;
             program.start();
@@ -66,19 +102,181 @@ impl VirtualMachine {
         Ok(())
     }
 
+    pub(crate) fn mix_audio(&mut self, len: usize) -> Vec<u8> {
+        self.ctx.mix_audio(len)
+    }
+
     pub fn toggle_fast_mode(&mut self) {
         self.ctx.toggle_fast_mode();
     }
 
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    pub fn debugger_mut(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    pub fn watchdog(&self) -> &Watchdog {
+        &self.watchdog
+    }
+
+    pub fn watchdog_mut(&mut self) -> &mut Watchdog {
+        &mut self.watchdog
+    }
+
+    /// Start recording executed commands into `coverage`, `None` stops recording.
+    pub fn set_coverage(&mut self, coverage: Option<Coverage>) {
+        self.coverage = coverage;
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    pub fn take_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.take()
+    }
+
+    /// Start writing every executed command into `trace`, `None` stops tracing.
+    pub fn set_trace(&mut self, trace: Option<TraceWriter>) {
+        self.trace = trace;
+    }
+
+    pub fn take_trace(&mut self) -> Option<TraceWriter> {
+        self.trace.take()
+    }
+
+    pub fn profiler(&self) -> std::cell::Ref<'_, Box<Profiler>> {
+        self.profiler.get()
+    }
+
+    pub fn profiler_mut(&mut self) -> std::cell::RefMut<'_, Box<Profiler>> {
+        self.profiler.get_mut()
+    }
+
+    /// Names used by the disassembly and the trace output.
+    pub fn symbols(&self) -> &Symbols {
+        &self.ctx.symbols
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.ctx.symbols = symbols;
+    }
+
+    /// Captions drawn over the screen, `None` for none.
+    pub fn set_subtitles(&mut self, subtitles: Option<Subtitles>) {
+        self.ctx.subtitles = subtitles;
+    }
+
+    /// Whether the debugger stopped in the middle of a frame.
+    pub fn in_frame(&self) -> bool {
+        self.frame_thread.is_some()
+    }
+
+    pub fn part_id(&self) -> u16 {
+        self.res.get().current_part_id()
+    }
+
+    pub fn variables(&self) -> &[i16] {
+        &self.ctx.variables
+    }
+
+    pub fn set_variable(&mut self, var_id: u8, val: i16) {
+        self.ctx.variables[var_id as usize] = val;
+    }
+
+    pub fn threads(&self) -> &[ThreadData] {
+        &self.ctx.threads_data
+    }
+
+    /// Disassemble the commands around `addr` in the current part.
+    pub fn disassemble(&self, addr: u16, before: usize, after: usize) -> Vec<(u16, String)> {
+        self.programs
+            .get(&self.program_id)
+            .map(|program| program.disassemble(addr, before, after, &self.ctx.symbols))
+            .unwrap_or_default()
+    }
+
+    /// Parse the bytecode of a part, loaded or not.
+    pub(crate) fn part_program(&self, part_id: u16) -> Result<Program> {
+        let code_idx = self
+            .res
+            .get()
+            .variant()
+            .part(part_id)
+            .map(|part| part[MEMLIST_PART_CODE] as usize)
+            .ok_or_else(|| AwbiError::bad_data(format!("Unknown part 0x{:04X}", part_id)))?;
+        let mut code = self.res.get_mut().entry_data(code_idx)?.to_vec();
+        self.res.get().variant().patch(part_id, &mut code);
+        let mut program = Program::new(code_idx, part_id, code);
+        program.parse()?;
+
+        Ok(program)
+    }
+
+    /// Snapshot of the VM state at the last fault, without the savestate.
+    pub fn crash_dump(&self, fault: VmFault) -> CrashDump {
+        let (thread_id, addr) = self.fault_location.unwrap_or_default();
+        let program = self.programs.get(&self.program_id);
+
+        CrashDump {
+            fault: fault.to_string(),
+            part_id: self.part_id(),
+            thread_id,
+            addr,
+            disassembly: self.disassemble(addr, 10, 10),
+            call_stack: program.map(|p| p.call_stack()).unwrap_or_default(),
+            variables: self.ctx.variables.to_vec(),
+            threads: self.ctx.threads_data.to_vec(),
+            savestate: Vec::new(),
+        }
+    }
+
+    /// Continue the frame of `dump` from its faulting command, its savestate being loaded.
+    pub(crate) fn restore_crash(&mut self, dump: &CrashDump) -> Result<()> {
+        let part_id = self.part_id();
+        ensure!(
+            dump.part_id == part_id,
+            AwbiError::bad_data(format!(
+                "Crash dump of part 0x{:04X} with a savestate of part 0x{:04X}",
+                dump.part_id, part_id
+            ))
+        );
+        ensure!(
+            dump.threads.len() == VM_NUM_THREADS && dump.thread_id < VM_NUM_THREADS,
+            AwbiError::bad_data(format!(
+                "Crash dump of thread {} with {} threads",
+                dump.thread_id,
+                dump.threads.len()
+            ))
+        );
+
+        self.program_mut()?.resume_at(dump.addr, &dump.call_stack)?;
+        self.ctx.threads_data.copy_from_slice(&dump.threads);
+        self.ctx.goto_next_thread = false;
+        self.fault_location = Some((dump.thread_id, dump.addr));
+        self.watchdog.start_thread(part_id, dump.thread_id);
+        self.frame_thread = Some(dump.thread_id);
+
+        Ok(())
+    }
+
     /*
          This is called every frames in the infinite loop.
     */
-    // #[trace]
-    pub fn check_thread_requests(&mut self) -> Result<()> {
+    pub(crate) fn check_thread_requests(&mut self) -> Result<()> {
+        self.ctx.update_music_mark();
+
         //Check if a part switch has been requested.
         let requested_next_part = self.res.get().requested_next_part;
         if let Some(requested_next_part) = requested_next_part {
-            println!("\requested_next_part={:#04x}", requested_next_part);
+            debug!(
+                DBG_VM,
+                "VirtualMachine::check_thread_requests() requested_next_part={:#04x}",
+                requested_next_part
+            );
             self.init_for_part(requested_next_part)?;
             self.res.get_mut().requested_next_part = None;
         }
@@ -100,14 +298,10 @@ impl VirtualMachine {
 
             let n = self.ctx.threads_data[thread_id].requested_pc_offset;
 
-            // println!(
-            //     "VirtualMachine::check_thread_requests() thr_id={} n={}",
-            //     thread_id, n
-            // );
             if n != VM_NO_SETVEC_REQUESTED {
-                println!(
-                    "VirtualMachine::check_thread_requests() thr_id={} n={}",
-                    thread_id, n
+                debug!(
+                    DBG_VM,
+                    "VirtualMachine::check_thread_requests() thr_id={} n={}", thread_id, n
                 );
                 self.ctx.threads_data[thread_id].pc_offset =
                     if n == 0xFFFE { VM_INACTIVE_THREAD } else { n };
@@ -118,24 +312,36 @@ impl VirtualMachine {
         Ok(())
     }
 
-    // #[trace]
-    pub fn host_frame(&mut self) -> Result<()> {
+    pub(crate) fn host_frame(&mut self) -> Result<Option<DebugStop>> {
         // Run the Virtual Machine for every active threads (one vm frame).
         // Inactive threads are marked with a thread instruction pointer set to 0xFFFF (VM_INACTIVE_THREAD).
         // A thread must feature a break opcode so the interpreter can move to the next thread.
 
-        for thread_id in 0..VM_NUM_THREADS {
-            if !self.ctx.threads_data[thread_id].cur_state_active {
-                println!("VirtualMachine::host_frame(skip) thr_id={}", thread_id);
-                continue;
-            }
+        // Continue the frame the debugger stopped in.
+        let resumed_thread = self.frame_thread.take();
+        let mut last_thread_id = 0;
+
+        for thread_id in resumed_thread.unwrap_or(0)..VM_NUM_THREADS {
+            let resumed = resumed_thread == Some(thread_id);
+
+            if !resumed {
+                if !self.ctx.threads_data[thread_id].cur_state_active {
+                    trace!(
+                        DBG_VM,
+                        "VirtualMachine::host_frame(skip) thr_id={}",
+                        thread_id
+                    );
+                    continue;
+                }
 
-            // println!("TEST");
+                let n = self.ctx.threads_data[thread_id].pc_offset;
 
-            let n = self.ctx.threads_data[thread_id].pc_offset;
+                if n == VM_INACTIVE_THREAD {
+                    continue;
+                }
 
-            if n != VM_INACTIVE_THREAD {
-                let program = self.programs.get_mut(&self.program_id).unwrap();
+                self.fault_location = Some((thread_id, n));
+                let program = self.program_mut()?;
                 program.goto_addr(n)?;
 
                 // Set the script pointer to the right location.
@@ -145,43 +351,174 @@ impl VirtualMachine {
                 self.stack_ptr = 0;
 
                 self.ctx.goto_next_thread = false;
-                println!("VirtualMachine::host_frame() thr_id={} n={}", thread_id, n);
-                self.execute_thread()?;
-
-                //Since .pc is going to be modified by this next loop iteration, we need to save it.
-                let program = self.programs.get(&self.program_id).unwrap();
-                self.ctx.threads_data[thread_id].pc_offset = if program.is_active() {
-                    program.addr() as _
-                } else {
-                    VM_INACTIVE_THREAD
-                };
-
-                println!(
-                    "VirtualMachine::host_frame() thr_id={} pos={}",
-                    thread_id, self.ctx.threads_data[thread_id].pc_offset
+                debug!(
+                    DBG_VM,
+                    "VirtualMachine::host_frame() thr_id={} n={:#06x}", thread_id, n
                 );
-                if self.sys.get().input().quit {
-                    break;
-                }
+            }
+
+            last_thread_id = thread_id;
+
+            if let Some(stop) = self.execute_thread(thread_id, resumed)? {
+                self.frame_thread = Some(thread_id);
+                return Ok(Some(stop));
+            }
+
+            //Since .pc is going to be modified by this next loop iteration, we need to save it.
+            let program = self.program_mut()?;
+            self.ctx.threads_data[thread_id].pc_offset = if program.is_active() {
+                program.addr() as _
             } else {
-                // println!("VirtualMachine::host_frame() thr_id={} n={}", thread_id, n);
+                VM_INACTIVE_THREAD
+            };
+
+            debug!(
+                DBG_VM,
+                "VirtualMachine::host_frame() thr_id={} pos={:#06x}",
+                thread_id,
+                self.ctx.threads_data[thread_id].pc_offset
+            );
+            if self.sys.get().input().quit {
+                break;
             }
         }
 
-        Ok(())
+        if let Some(trace) = &mut self.trace {
+            trace.end_frame();
+        }
+
+        if self.profiler.get().is_enabled() {
+            let mut profiler = self.profiler.get_mut();
+            profiler.end_frame();
+            debug!(DBG_VM, "Frame profile: {}", profiler.last_frame());
+        }
+
+        if self.debugger.stop_at_frame_end() {
+            let addr = self.ctx.threads_data[last_thread_id].pc_offset;
+            return Ok(Some(DebugStop::new(
+                StopReason::FrameEnd,
+                self.part_id(),
+                last_thread_id,
+                addr,
+            )));
+        }
+
+        Ok(None)
     }
 
-    // #[trace]
-    fn execute_thread(&mut self) -> Result<()> {
-        if let Some(program) = self.programs.get_mut(&self.program_id) {
-            // println!("TEST: {}-{}", self.program_id, program.is_active());
-            program.exec(&mut self.ctx)?;
+    fn program_mut(&mut self) -> Result<&mut Program> {
+        let part_id = self.part_id();
+        Ok(self
+            .programs
+            .get_mut(&self.program_id)
+            .ok_or(VmFault::ProgramNotLoaded { part_id })?)
+    }
+
+    fn execute_thread(&mut self, thread_id: usize, resumed: bool) -> Result<Option<DebugStop>> {
+        let part_id = self.part_id();
+
+        let program = self
+            .programs
+            .get_mut(&self.program_id)
+            .ok_or(VmFault::ProgramNotLoaded { part_id })?;
+
+        // Do not stop again at the breakpoint the thread was resumed from.
+        let mut check_breakpoint = !resumed;
+
+        if !resumed {
+            self.watchdog.start_thread(part_id, thread_id);
         }
 
-        Ok(())
+        let profile = self.profiler.get().is_enabled();
+
+        while !self.ctx.goto_next_thread {
+            let addr = program.addr() as u16;
+
+            if check_breakpoint && self.debugger.is_breakpoint(part_id, addr) {
+                return Ok(Some(DebugStop::new(
+                    StopReason::Breakpoint,
+                    part_id,
+                    thread_id,
+                    addr,
+                )));
+            }
+            check_breakpoint = true;
+
+            // Keep the previous values only if somebody is interested in variable writes.
+            let old_vars = if self.debugger.has_watchpoints() || self.trace.is_some() {
+                Some(self.ctx.variables)
+            } else {
+                None
+            };
+            let dst_var = program.command().and_then(|cmd| cmd.dst_var());
+            let name = program.command().map(|cmd| cmd.name());
+            let opcode = program.opcode();
+
+            if let Err(err) = program.step(&mut self.ctx) {
+                self.fault_location = Some((thread_id, addr));
+                return Err(err);
+            }
+
+            if let Some(coverage) = &mut self.coverage {
+                coverage.hit(part_id, addr);
+            }
+            if let (true, Some(name)) = (profile, name) {
+                self.profiler.get_mut().count_command(thread_id, name);
+            }
+
+            if let (Some(trace), Some(old_vars), Some(opcode)) =
+                (&mut self.trace, &old_vars, opcode)
+            {
+                trace.record(
+                    thread_id,
+                    part_id,
+                    addr,
+                    opcode,
+                    old_vars,
+                    &self.ctx.variables,
+                )?;
+            }
+
+            let loc = DebugStop::new(StopReason::Step, part_id, thread_id, addr);
+
+            if let (Some(old_vars), true) = (old_vars, self.debugger.has_watchpoints()) {
+                let stop = self
+                    .debugger
+                    .check_writes(loc, dst_var, &old_vars, &self.ctx.variables);
+                if stop.is_some() {
+                    return Ok(stop);
+                }
+            }
+
+            if let Some(policy) = self.watchdog.check(addr) {
+                match policy {
+                    LoopPolicy::Abort => {
+                        self.fault_location = Some((thread_id, addr));
+                        return Err(VmFault::InfiniteLoop {
+                            part_id,
+                            addr,
+                            thread_id: thread_id as u16,
+                        }
+                        .into());
+                    }
+                    LoopPolicy::Kill => {
+                        program.kill();
+                        self.ctx.goto_next_thread = true;
+                    }
+                    LoopPolicy::Pause => self.ctx.goto_next_thread = true,
+                }
+            }
+
+            if let Some(reason) = self.debugger.stop_after_step(self.ctx.goto_next_thread) {
+                return Ok(Some(DebugStop { reason, ..loc }));
+            }
+        }
+
+        self.watchdog.end_thread();
+
+        Ok(None)
     }
 
-    // #[trace]
     // fn execute_opcode(&mut self, opcode: u8) -> Result<()> {
     //     if let Some(program) = self.programs.get_mut(&self.program_id) {
     //         program.exec(&mut self.ctx)?;
@@ -189,12 +526,11 @@ impl VirtualMachine {
     //     Ok(())
     // }
 
-    // #[trace]
-    pub fn inp_update_player(&mut self) -> Result<()> {
+    pub(crate) fn inp_update_player(&mut self) -> Result<()> {
         self.ctx.inp_update_player()
     }
 
-    pub fn save_or_load(&mut self, ser: &mut Serializer) -> Result<()> {
+    pub(crate) fn save_or_load(&mut self, ser: &mut Serializer) -> Result<()> {
         self.ctx.save_or_load(ser)
     }
 
@@ -225,6 +561,8 @@ impl fmt::Debug for VirtualMachine {
             .field("ctx", &self.ctx)
             .field("programs.len", &self.programs.len())
             .field("program_id", &self.program_id)
+            .field("debugger", &self.debugger)
+            .field("frame_thread", &self.frame_thread)
             .finish()
     }
 }
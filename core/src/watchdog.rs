@@ -0,0 +1,224 @@
+// Protection against script threads that never reach `PauseThread` or `KillThread`: every
+// thread gets a budget of commands per frame, the policy decides what happens to a thread
+// that exceeds it. A thread is reported once, until it yields within its budget again.
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+pub const DEFAULT_BUDGET: u32 = 50_000;
+
+// Number of the last executed addresses kept to report the loop.
+const HISTORY_LEN: usize = 64;
+// Number of reports kept until they are taken, the oldest ones go first.
+const MAX_REPORTS: usize = 32;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LoopPolicy {
+    /// Stop the engine with a `VmFault::InfiniteLoop` error.
+    #[default]
+    Abort,
+    /// Kill the thread as `KillThread` does.
+    Kill,
+    /// Yield as `PauseThread` does, the thread continues from the same place next frame.
+    Pause,
+}
+
+#[derive(Clone, Debug)]
+pub struct LoopReport {
+    pub part_id: u16,
+    pub thread_id: usize,
+    pub instructions: u32,
+    /// Distinct addresses executed last, sorted.
+    pub addresses: Vec<u16>,
+    pub policy: LoopPolicy,
+}
+
+impl fmt::Display for LoopReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let addresses: Vec<_> = self
+            .addresses
+            .iter()
+            .map(|a| format!("0x{:04X}", a))
+            .collect();
+
+        write!(
+            f,
+            "Thread {} of part 0x{:04X} executed {} commands without yielding, looping over {} ({:?})",
+            self.thread_id,
+            self.part_id,
+            self.instructions,
+            addresses.join(", "),
+            self.policy
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct Watchdog {
+    budget: Option<u32>,
+    policy: LoopPolicy,
+    executed: u32,
+    history: [u16; HISTORY_LEN],
+    reports: Vec<LoopReport>,
+    // Part and thread running, whether it ran out of budget this frame.
+    thread: (u16, usize),
+    exceeded: bool,
+    // Threads already reported.
+    stuck: BTreeSet<(u16, usize)>,
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Self {
+            budget: Some(DEFAULT_BUDGET),
+            policy: LoopPolicy::default(),
+            executed: 0,
+            history: [0; HISTORY_LEN],
+            reports: Vec::new(),
+            thread: (0, 0),
+            exceeded: false,
+            stuck: BTreeSet::new(),
+        }
+    }
+}
+
+impl Watchdog {
+    pub fn budget(&self) -> Option<u32> {
+        self.budget
+    }
+
+    /// Commands a thread may execute per frame, `None` disables the watchdog.
+    pub fn set_budget(&mut self, budget: Option<u32>) {
+        self.budget = budget;
+    }
+
+    pub fn policy(&self) -> LoopPolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: LoopPolicy) {
+        self.policy = policy;
+    }
+
+    /// Reports of the threads stopped by the watchdog since the last call, at most the last
+    /// `MAX_REPORTS`.
+    pub fn take_reports(&mut self) -> Vec<LoopReport> {
+        std::mem::take(&mut self.reports)
+    }
+
+    pub(crate) fn start_thread(&mut self, part_id: u16, thread_id: usize) {
+        self.executed = 0;
+        self.thread = (part_id, thread_id);
+        self.exceeded = false;
+    }
+
+    /// The thread yielded, it recovered if it did so within its budget.
+    pub(crate) fn end_thread(&mut self) {
+        if !self.exceeded {
+            self.stuck.remove(&self.thread);
+        }
+    }
+
+    /// Account the command executed at `addr`. Returns the policy to apply if the thread ran
+    /// out of budget, the thread is reported the first time.
+    pub(crate) fn check(&mut self, addr: u16) -> Option<LoopPolicy> {
+        let budget = self.budget?;
+
+        self.history[self.executed as usize % HISTORY_LEN] = addr;
+        self.executed += 1;
+
+        if self.executed <= budget {
+            return None;
+        }
+
+        if self.stuck.insert(self.thread) {
+            let len = usize::min(self.executed as usize, HISTORY_LEN);
+            let mut addresses = self.history[..len].to_vec();
+            addresses.sort_unstable();
+            addresses.dedup();
+
+            let (part_id, thread_id) = self.thread;
+            let report = LoopReport {
+                part_id,
+                thread_id,
+                instructions: self.executed,
+                addresses,
+                policy: self.policy,
+            };
+            warning!("{}", report);
+
+            if self.reports.len() == MAX_REPORTS {
+                self.reports.remove(0);
+            }
+            self.reports.push(report);
+        }
+
+        self.executed = 0;
+        self.exceeded = true;
+
+        Some(self.policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog() {
+        let mut watchdog = Watchdog::default();
+        watchdog.set_budget(Some(100));
+        watchdog.set_policy(LoopPolicy::Pause);
+        watchdog.start_thread(0x3E81, 5);
+
+        let mut policy = None;
+        for i in 0..101 {
+            policy = watchdog.check(0x10 + (i % 3) * 4);
+            if i < 100 {
+                assert!(policy.is_none());
+            }
+        }
+        assert_eq!(policy, Some(LoopPolicy::Pause));
+        watchdog.end_thread();
+
+        let reports = watchdog.take_reports();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].thread_id, 5);
+        assert_eq!(reports[0].instructions, 101);
+        assert_eq!(reports[0].addresses, vec![0x10, 0x14, 0x18]);
+        assert_eq!(reports[0].policy, LoopPolicy::Pause);
+        assert!(watchdog.take_reports().is_empty());
+
+        // The budget is per thread and frame, a thread still looping is not reported again.
+        let run_frame = |watchdog: &mut Watchdog, commands| {
+            watchdog.start_thread(0x3E81, 5);
+            let policy = (0..commands).find_map(|_| watchdog.check(0x10));
+            watchdog.end_thread();
+            policy
+        };
+        for _ in 0..10 {
+            assert_eq!(run_frame(&mut watchdog, 200), Some(LoopPolicy::Pause));
+        }
+        assert!(watchdog.take_reports().is_empty());
+
+        // Until it recovers.
+        assert_eq!(run_frame(&mut watchdog, 50), None);
+        assert_eq!(run_frame(&mut watchdog, 200), Some(LoopPolicy::Pause));
+        assert_eq!(watchdog.take_reports().len(), 1);
+
+        // Reports pile up to a limit if nobody takes them.
+        for thread_id in 0..MAX_REPORTS + 10 {
+            watchdog.start_thread(0x3E82, thread_id);
+            while watchdog.check(0x10).is_none() {}
+        }
+        let reports = watchdog.take_reports();
+        assert_eq!(reports.len(), MAX_REPORTS);
+        assert_eq!(reports[0].thread_id, 10);
+
+        watchdog.set_budget(None);
+        watchdog.start_thread(0x3E81, 6);
+        for _ in 0..1000 {
+            assert!(watchdog.check(0x10).is_none());
+        }
+    }
+}
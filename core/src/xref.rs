@@ -0,0 +1,191 @@
+// Cross-reference index of the bytecode of all parts: who reads and writes every variable,
+// where threads are started, which resources the parts load and which strings they draw.
+
+use crate::{command::Command, program::Program};
+use serde::Serialize;
+use std::{collections::BTreeMap, fmt};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct Location {
+    pub part_id: u16,
+    pub addr: u16,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(&format!("0x{:04X}:0x{:04X}", self.part_id, self.addr))
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct VarRefs {
+    pub reads: Vec<Location>,
+    pub writes: Vec<Location>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ThreadStart {
+    /// Address the thread starts at.
+    pub target: u16,
+    /// `SetVect` command.
+    pub from: Location,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ResourceLoad {
+    pub res_id: u16,
+    /// `UpdateMemList` command.
+    pub from: Location,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Xref {
+    pub variables: BTreeMap<u8, VarRefs>,
+    pub threads: BTreeMap<u8, Vec<ThreadStart>>,
+    /// Resources loaded by every part.
+    pub resources: BTreeMap<u16, Vec<ResourceLoad>>,
+    /// `DrawString` commands by string id.
+    pub strings: BTreeMap<u16, Vec<Location>>,
+}
+
+impl Xref {
+    pub(crate) fn add_program(&mut self, program: &Program) {
+        let part_id = program.part_id();
+
+        for (addr, cmd) in program.commands() {
+            let loc = Location { part_id, addr };
+
+            for var_id in cmd.src_vars() {
+                self.variables.entry(var_id).or_default().reads.push(loc);
+            }
+            if let Some(var_id) = cmd.dst_var() {
+                self.variables.entry(var_id).or_default().writes.push(loc);
+            }
+
+            match cmd {
+                Command::SetVect { thr_id, offset } => {
+                    self.threads.entry(*thr_id).or_default().push(ThreadStart {
+                        target: *offset,
+                        from: loc,
+                    })
+                }
+                Command::UpdateMemList { res_id } if *res_id != 0 => self
+                    .resources
+                    .entry(part_id)
+                    .or_default()
+                    .push(ResourceLoad {
+                        res_id: *res_id,
+                        from: loc,
+                    }),
+                Command::DrawString { str_id, .. } => {
+                    self.strings.entry(*str_id).or_default().push(loc)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Commands reading and writing a variable.
+    pub fn variable(&self, var_id: u8) -> Option<&VarRefs> {
+        self.variables.get(&var_id)
+    }
+
+    /// `SetVect` commands starting a thread.
+    pub fn thread_starts(&self, thread_id: u8) -> &[ThreadStart] {
+        self.threads.get(&thread_id).map_or(&[], |v| v.as_slice())
+    }
+
+    /// `UpdateMemList` commands of a part.
+    pub fn part_resources(&self, part_id: u16) -> &[ResourceLoad] {
+        self.resources.get(&part_id).map_or(&[], |v| v.as_slice())
+    }
+
+    /// `DrawString` commands drawing a string.
+    pub fn string_users(&self, str_id: u16) -> &[Location] {
+        self.strings.get(&str_id).map_or(&[], |v| v.as_slice())
+    }
+}
+
+fn join<T: fmt::Display>(items: impl Iterator<Item = T>, sep: &str) -> String {
+    items.map(|i| i.to_string()).collect::<Vec<_>>().join(sep)
+}
+
+impl fmt::Display for Xref {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "[variables]")?;
+        for (var_id, refs) in &self.variables {
+            writeln!(f, "0x{:02X}:", var_id)?;
+            writeln!(f, "    writes: {}", join(refs.writes.iter(), " "))?;
+            writeln!(f, "    reads:  {}", join(refs.reads.iter(), " "))?;
+        }
+
+        writeln!(f, "\n[threads]")?;
+        for (thread_id, starts) in &self.threads {
+            let starts = starts
+                .iter()
+                .map(|s| format!("0x{:04X} from {}", s.target, s.from));
+            writeln!(f, "{:2}: {}", thread_id, join(starts, ", "))?;
+        }
+
+        writeln!(f, "\n[resources]")?;
+        for (part_id, loads) in &self.resources {
+            let loads = loads
+                .iter()
+                .map(|l| format!("0x{:02X}@0x{:04X}", l.res_id, l.from.addr));
+            writeln!(f, "0x{:04X}: {}", part_id, join(loads, " "))?;
+        }
+
+        writeln!(f, "\n[strings]")?;
+        for (str_id, users) in &self.strings {
+            writeln!(f, "0x{:03X}: {}", str_id, join(users.iter(), " "))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xref() -> anyhow::Result<()> {
+        #[rustfmt::skip]
+        let code = vec![
+            0x00, 0xE4, 0x00, 0x14,             // 0000: mov [0xE4], 20
+            0x08, 0x05, 0x00, 0x10,             // 0004: setvec 5, 0x0010
+            0x19, 0x00, 0x12,                   // 0008: update_mem_list 0x12
+            0x12, 0x00, 0x81, 0x01, 0x02, 0x0F, // 000B: draw_string 0x81
+            0x02, 0x10, 0xE4,                   // 0011: add [0x10], [0xE4]
+            0x11,                               // 0014: kill
+        ];
+        let mut program = Program::new(0, 0x3E81, code);
+        program.parse()?;
+
+        let mut xref = Xref::default();
+        xref.add_program(&program);
+
+        let at = |addr| Location {
+            part_id: 0x3E81,
+            addr,
+        };
+        let refs = xref.variable(0xE4).expect("Variable 0xE4 is used");
+        assert_eq!(refs.writes, vec![at(0x00)]);
+        assert_eq!(refs.reads, vec![at(0x11)]);
+        assert_eq!(xref.variable(0x10).map(|r| r.writes.len()), Some(1));
+        assert_eq!(
+            xref.thread_starts(5),
+            &[ThreadStart {
+                target: 0x10,
+                from: at(0x04)
+            }]
+        );
+        assert_eq!(xref.part_resources(0x3E81)[0].res_id, 0x12);
+        assert_eq!(xref.string_users(0x81), &[at(0x0B)]);
+        assert!(xref.string_users(0x82).is_empty());
+
+        assert!(xref.to_string().contains("    writes: 0x3E81:0x0000"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,18 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use std::sync::Once;
+
+static FIXTURES: Once = Once::new();
+
+// Savestates are loaded into the synthetic game, one copy per fuzzing process.
+fn data_dir() -> String {
+    let dir = std::env::temp_dir().join(format!("awbi-fuzz-{}", std::process::id()));
+    FIXTURES.call_once(|| {
+        awbi_core::fixtures::write_fixtures(&dir).expect("Unable to write the fixtures");
+    });
+    dir.to_string_lossy().into()
+}
+
+fuzz_target!(|data: &[u8]| {
+    let _ = awbi_core::fuzz::load_state(&data_dir(), data);
+});
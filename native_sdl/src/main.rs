@@ -1,5 +1,8 @@
 use anyhow::Result;
-use awbi_core::{engine::Engine, reference::Ref, system::System};
+use awbi_core::{
+    coverage::Coverage, engine::Engine, font::Font, reference::Ref, strings::StringTable,
+    subtitles::Subtitles, symbols::Symbols, system::System, trace::TraceWriter,
+};
 use sdl_system::SdlSystem;
 use std::path::PathBuf;
 
@@ -22,15 +25,88 @@ fn data_dir() -> Result<PathBuf> {
 
     dir.push("data");
 
+    // The game data zipped as distributed.
+    let zip = dir.with_extension("zip");
+    if !dir.exists() && zip.is_file() {
+        return Ok(zip);
+    }
+
     Ok(dir)
 }
 
 fn main() -> Result<()> {
+    awbi_core::logging::init_from_env()?;
+
     let data_dir = data_dir()?;
     let sys: Ref<Box<(dyn System)>> = Ref::new(Box::new(SdlSystem::new()?));
     let mut engine = Engine::new(sys, data_dir.to_str().unwrap(), data_dir.to_str().unwrap());
 
+    // Any symbols open the protection screen.
+    if std::env::var_os("AWBI_BYPASS_PROTECTION").is_some() {
+        engine.set_bypass_protection(true);
+    }
+
     engine.init()?;
+
+    // Font with the glyphs of a translation.
+    if let Some(path) = std::env::var_os("AWBI_FONT") {
+        engine.set_font(Font::load(path)?);
+    }
+
+    // Translated strings, the missing ones stay in English.
+    if let Some(path) = std::env::var_os("AWBI_STRINGS") {
+        engine.set_strings(Some(StringTable::load(path)?));
+    }
+
+    // Names for the trace output.
+    if let Some(path) = std::env::var_os("AWBI_SYMBOLS") {
+        engine.vm_mut().set_symbols(Symbols::load(path)?);
+    }
     // println!("=== Engine State ===\n{:#?}=== Engine State ===", engine);
-    engine.run()
+
+    // Captions for the cinematics and the alien dialogue.
+    if let Some(path) = std::env::var_os("AWBI_SUBTITLES") {
+        engine.vm_mut().set_subtitles(Some(Subtitles::load(path)?));
+    }
+
+    // Accumulate bytecode coverage of the play sessions into the given file.
+    let coverage_file = std::env::var_os("AWBI_COVERAGE").map(PathBuf::from);
+    if coverage_file.is_some() {
+        engine.vm_mut().set_coverage(Some(Coverage::default()));
+    }
+
+    // Instruction trace to compare with other implementations.
+    if let Some(path) = std::env::var_os("AWBI_TRACE") {
+        engine.vm_mut().set_trace(Some(TraceWriter::create(path)?));
+    }
+
+    // Print the profile of the whole session on exit.
+    let profile = std::env::var_os("AWBI_PROFILE").is_some();
+    engine.vm_mut().profiler_mut().set_enabled(profile);
+
+    let res = engine.run();
+
+    if let Some(mut trace) = engine.vm_mut().take_trace() {
+        trace.flush()?;
+    }
+
+    if profile {
+        eprint!("{}", engine.vm().profiler().session());
+    }
+
+    if let Some(path) = coverage_file {
+        let mut coverage = if path.exists() {
+            Coverage::load(&path)?
+        } else {
+            Coverage::default()
+        };
+        if let Some(session) = engine.vm_mut().take_coverage() {
+            coverage.merge(&session);
+        }
+        coverage.save(&path)?;
+    }
+
+    res?;
+
+    Ok(())
 }
@@ -0,0 +1,57 @@
+use crate::data_dir;
+use anyhow::{anyhow, bail, Result};
+use awbi_core::conformance::{Golden, Harness, InputScript};
+use std::path::Path;
+
+/// `conformance <script> <golden> [--record <frames>] [--dumps <dir>] [--out <dir>] [data_dir]`
+pub fn run(args: &[String]) -> Result<()> {
+    let mut files = Vec::new();
+    let mut record = None;
+    let mut dumps_dir = None;
+    let mut out_dir = ".";
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| anyhow!("Missing {} value", arg));
+        match arg.as_str() {
+            "--record" => {
+                let frames = value()?;
+                record = Some(
+                    frames
+                        .parse::<u64>()
+                        .map_err(|_| anyhow!("Invalid frame count '{}'", frames))?,
+                );
+            }
+            "--dumps" => dumps_dir = Some(Path::new(value()?)),
+            "--out" => out_dir = value()?,
+            _ => files.push(arg),
+        }
+    }
+
+    let (script, golden_file, data_arg) = match files[..] {
+        [script, golden] => (script, golden, None),
+        [script, golden, data] => (script, golden, Some(data)),
+        _ => bail!("Expected <script> <golden> [data_dir]"),
+    };
+
+    let script = InputScript::load(script)?;
+    let mut harness = Harness::new(&data_dir(data_arg)?)?;
+
+    if let Some(frames) = record {
+        let golden = harness.record(&script, frames, dumps_dir)?;
+        golden.save(golden_file)?;
+        println!("Recorded {} frames", frames);
+        return Ok(());
+    }
+
+    let golden = Golden::load(golden_file)?;
+    match harness.check(&script, &golden, Path::new(out_dir), dumps_dir)? {
+        Some(mismatch) => {
+            print!("{}", mismatch);
+            bail!("Conformance check failed");
+        }
+        None => println!("All {} frames match", golden.frames.len()),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use crate::{data_dir, headless_engine};
+use anyhow::{bail, Result};
+use awbi_core::coverage::Coverage;
+use std::fs;
+
+/// `coverage <coverage_file> [--html <out_file>] [data_dir]`
+pub fn run(args: &[String]) -> Result<()> {
+    let mut coverage_file = None;
+    let mut html_file = None;
+    let mut data_arg = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--html" => match args.next() {
+                Some(path) => html_file = Some(path),
+                None => bail!("Missing --html output file"),
+            },
+            _ if coverage_file.is_none() => coverage_file = Some(arg),
+            _ => data_arg = Some(arg),
+        }
+    }
+
+    let coverage_file = match coverage_file {
+        Some(path) => path,
+        None => bail!("Missing coverage file"),
+    };
+
+    let coverage = Coverage::load(coverage_file)?;
+    let engine = headless_engine(&data_dir(data_arg)?)?;
+
+    let mut parts = Vec::new();
+    for part_id in engine.parts() {
+        parts.push((part_id, engine.part_listing(part_id)?));
+    }
+
+    match html_file {
+        Some(path) => fs::write(path, coverage.html(&parts))?,
+        None => {
+            for (part_id, listing) in &parts {
+                print!("{}", coverage.annotate(*part_id, listing));
+                println!();
+            }
+        }
+    }
+
+    Ok(())
+}
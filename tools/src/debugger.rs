@@ -0,0 +1,392 @@
+use anyhow::{anyhow, bail, Result};
+use awbi_core::{
+    crash_dump::CrashDump,
+    debugger::{DebugStop, RunMode, WatchAction, WatchCondition},
+    engine::Engine,
+    symbols::Symbols,
+    trace::TraceWriter,
+    watchdog::LoopPolicy,
+};
+use std::io::{BufRead, Write};
+
+const PROMPT: &str = "(awbi) ";
+const HELP: &str = "\
+Commands:
+    b, break [part] <addr>      set a breakpoint (current part by default)
+    d, delete [[part] <addr>]   delete a breakpoint or all of them
+    bl, breakpoints             list breakpoints
+    w, watch <var> [cond]       stop when a variable is written, e.g. 'watch 0xE4 > 100'
+    log <var> [cond]            log writes of a variable
+    unwatch [var]               delete watchpoints of a variable or all of them
+    wl, watchpoints             list watchpoints
+    s, step                     execute a single command
+    p, pause                    run until the current thread pauses
+    f, frame                    run until the end of the frame
+    c, continue                 run until a breakpoint is hit
+    v, vars [first] [count]     print variables
+    set <var> <value>           change a variable
+    t, threads                  print active threads
+    l, list [addr] [count]      disassemble around an address
+    load <file>                 restore the state saved in a crash dump
+    symbols <file>              load names of variables, threads, labels and resources
+    watchdog [budget|off] [abort|kill|pause]
+                                commands a thread may execute per frame and what to do with
+                                the threads exceeding it
+    profile [on|off|frame|session|reset]
+                                command counts and primitive timings of the last frame or
+                                the whole session
+    trace <file>|off            write every executed command into the trace file
+    h, help                     print this help
+    q, quit                     exit the debugger
+Numbers are decimal or hexadecimal with 0x prefix. Variables and addresses can be given by
+their names from the symbol file.";
+
+pub(crate) fn parse_num(s: &str) -> Result<u16> {
+    let res = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        u16::from_str_radix(hex, 16)
+    } else {
+        s.parse::<u16>()
+    };
+
+    res.map_err(|_| anyhow!("Invalid number '{}'", s))
+}
+
+fn parse_var(engine: &Engine, s: &str) -> Result<u8> {
+    let vm = engine.vm();
+    if let Some(var_id) = vm.symbols().find_variable(vm.part_id(), s) {
+        return Ok(var_id);
+    }
+
+    let var_id = parse_num(s)?;
+    if var_id > 0xFF {
+        bail!("Variable id should be less than 0x100");
+    }
+    Ok(var_id as u8)
+}
+
+fn parse_watch(engine: &Engine, args: &[&str]) -> Result<(u8, WatchCondition)> {
+    match args.split_first() {
+        Some((var, cond)) => Ok((parse_var(engine, var)?, cond.join(" ").parse()?)),
+        None => bail!("Expected <var> [cond]"),
+    }
+}
+
+fn parse_policy(s: &str) -> Result<LoopPolicy> {
+    match s {
+        "abort" => Ok(LoopPolicy::Abort),
+        "kill" => Ok(LoopPolicy::Kill),
+        "pause" => Ok(LoopPolicy::Pause),
+        _ => bail!("Unknown policy '{}'", s),
+    }
+}
+
+// Address or code label of the part.
+fn parse_addr(engine: &Engine, part_id: u16, s: &str) -> Result<u16> {
+    match engine.vm().symbols().find_label(part_id, s) {
+        Some(addr) => Ok(addr),
+        None => parse_num(s),
+    }
+}
+
+fn parse_location(engine: &Engine, args: &[&str]) -> Result<(u16, u16)> {
+    match args {
+        [addr] => {
+            let part_id = engine.vm().part_id();
+            Ok((part_id, parse_addr(engine, part_id, addr)?))
+        }
+        [part, addr] => {
+            let part_id = parse_num(part)?;
+            Ok((part_id, parse_addr(engine, part_id, addr)?))
+        }
+        _ => bail!("Expected [part] <addr>"),
+    }
+}
+
+struct Repl<'a, W: Write> {
+    engine: &'a mut Engine,
+    out: W,
+    // Address to list by default.
+    cur_addr: u16,
+}
+
+impl<'a, W: Write> Repl<'a, W> {
+    fn print_stop(&mut self, stop: Option<DebugStop>) -> Result<()> {
+        if let Some(stop) = stop {
+            self.cur_addr = stop.addr;
+            writeln!(
+                self.out,
+                "{:?}: part 0x{:04X}, thread {}",
+                stop.reason, stop.part_id, stop.thread_id
+            )?;
+            self.list(stop.addr, 0, 0)
+        } else {
+            writeln!(self.out, "Game quit")?;
+            Ok(())
+        }
+    }
+
+    fn list(&mut self, addr: u16, before: usize, after: usize) -> Result<()> {
+        let vm = self.engine.vm();
+        for (a, cmd) in vm.disassemble(addr, before, after) {
+            if let Some(label) = vm.symbols().label(vm.part_id(), a) {
+                writeln!(self.out, "{}:", label)?;
+            }
+            let marker = if a == addr { '>' } else { ' ' };
+            writeln!(self.out, "{} {:04X}: {}", marker, a, cmd)?;
+        }
+        Ok(())
+    }
+
+    fn load_dump(&mut self, path: &str) -> Result<()> {
+        let dump = CrashDump::load(path)?;
+
+        self.engine.load_crash_dump(&dump)?;
+        self.cur_addr = dump.addr;
+
+        writeln!(self.out, "{}", dump.fault)?;
+        writeln!(
+            self.out,
+            "Part 0x{:04X}, thread {}",
+            dump.part_id, dump.thread_id
+        )?;
+        for addr in dump.call_stack.iter().rev() {
+            writeln!(self.out, "  called from 0x{:04X}", addr)?;
+        }
+        self.list(dump.addr, 5, 5)
+    }
+
+    fn resume(&mut self, mode: RunMode) -> Result<()> {
+        let stop = self.engine.resume(mode);
+
+        for write in self.engine.vm_mut().debugger_mut().take_write_log() {
+            match self
+                .engine
+                .vm()
+                .symbols()
+                .variable(write.part_id, write.var_id)
+            {
+                Some(name) => writeln!(self.out, "Write: {} ({})", write, name)?,
+                None => writeln!(self.out, "Write: {}", write)?,
+            }
+        }
+
+        self.print_stop(stop?)
+    }
+
+    /// Returns `false` when the debugger should exit.
+    fn exec(&mut self, line: &str) -> Result<bool> {
+        let words: Vec<_> = line.split_whitespace().collect();
+        let (cmd, args) = match words.split_first() {
+            Some((cmd, args)) => (*cmd, args),
+            None => return Ok(true),
+        };
+
+        match cmd {
+            "b" | "break" => {
+                let (part_id, addr) = parse_location(self.engine, args)?;
+                if !self
+                    .engine
+                    .vm_mut()
+                    .debugger_mut()
+                    .add_breakpoint(part_id, addr)
+                {
+                    writeln!(self.out, "Breakpoint already set")?;
+                }
+            }
+            "d" | "delete" => {
+                if args.is_empty() {
+                    self.engine.vm_mut().debugger_mut().clear_breakpoints();
+                } else {
+                    let (part_id, addr) = parse_location(self.engine, args)?;
+                    if !self
+                        .engine
+                        .vm_mut()
+                        .debugger_mut()
+                        .remove_breakpoint(part_id, addr)
+                    {
+                        writeln!(self.out, "No such breakpoint")?;
+                    }
+                }
+            }
+            "bl" | "breakpoints" => {
+                for bp in self.engine.vm().debugger().breakpoints() {
+                    writeln!(self.out, "{:?}", bp)?;
+                }
+            }
+            "w" | "watch" => {
+                let (var_id, cond) = parse_watch(self.engine, args)?;
+                self.engine
+                    .vm_mut()
+                    .debugger_mut()
+                    .add_watchpoint(var_id, cond, WatchAction::Stop);
+            }
+            "log" => {
+                let (var_id, cond) = parse_watch(self.engine, args)?;
+                self.engine
+                    .vm_mut()
+                    .debugger_mut()
+                    .add_watchpoint(var_id, cond, WatchAction::Log);
+            }
+            "unwatch" => match args.first() {
+                Some(var) => {
+                    let var_id = parse_var(self.engine, var)?;
+                    if !self
+                        .engine
+                        .vm_mut()
+                        .debugger_mut()
+                        .remove_watchpoint(var_id, None)
+                    {
+                        writeln!(self.out, "No such watchpoint")?;
+                    }
+                }
+                None => self.engine.vm_mut().debugger_mut().clear_watchpoints(),
+            },
+            "wl" | "watchpoints" => {
+                for w in self.engine.vm().debugger().watchpoints() {
+                    writeln!(
+                        self.out,
+                        "[0x{:02X}] {:?} {:?}",
+                        w.var_id, w.condition, w.action
+                    )?;
+                }
+            }
+            "s" | "step" => self.resume(RunMode::Step)?,
+            "p" | "pause" => self.resume(RunMode::NextPause)?,
+            "f" | "frame" => self.resume(RunMode::NextFrame)?,
+            "c" | "continue" => self.resume(RunMode::Continue)?,
+            "v" | "vars" => {
+                let first = args.first().map(|s| parse_num(s)).transpose()?.unwrap_or(0) as usize;
+                let count = args
+                    .get(1)
+                    .map(|s| parse_num(s))
+                    .transpose()?
+                    .unwrap_or(256) as usize;
+                let vars = self.engine.vm().variables();
+                let last = usize::min(first + count, vars.len());
+
+                for (i, chunk) in vars[first.min(last)..last].chunks(8).enumerate() {
+                    let values: Vec<_> = chunk.iter().map(|v| format!("{:6}", v)).collect();
+                    writeln!(self.out, "0x{:02X}: {}", first + i * 8, values.join(" "))?;
+                }
+            }
+            "set" => match args {
+                [var, val] => {
+                    let var_id = parse_var(self.engine, var)?;
+                    let val = val
+                        .parse::<i16>()
+                        .or_else(|_| parse_num(val).map(|v| v as i16))?;
+                    self.engine.vm_mut().set_variable(var_id, val);
+                }
+                _ => bail!("Expected <var> <value>"),
+            },
+            "t" | "threads" => {
+                let vm = self.engine.vm();
+                for (i, td) in vm.threads().iter().enumerate() {
+                    if td.pc_offset != 0xFFFF || td.requested_pc_offset != 0xFFFF {
+                        match vm.symbols().thread(vm.part_id(), i as u8) {
+                            Some(name) => writeln!(self.out, "{:2}: {:?} <{}>", i, td, name)?,
+                            None => writeln!(self.out, "{:2}: {:?}", i, td)?,
+                        }
+                    }
+                }
+            }
+            "l" | "list" => {
+                let addr = match args.first() {
+                    Some(addr) => parse_addr(self.engine, self.engine.vm().part_id(), addr)?,
+                    None => self.cur_addr,
+                };
+                let count = args.get(1).map(|s| parse_num(s)).transpose()?.unwrap_or(10) as usize;
+                self.list(addr, count / 2, count / 2)?;
+            }
+            "load" => match args {
+                [path] => self.load_dump(path)?,
+                _ => bail!("Expected <file>"),
+            },
+            "symbols" => match args {
+                [path] => self.engine.vm_mut().set_symbols(Symbols::load(path)?),
+                _ => bail!("Expected <file>"),
+            },
+            "watchdog" => {
+                let watchdog = self.engine.vm_mut().watchdog_mut();
+                if let Some(budget) = args.first() {
+                    watchdog.set_budget(match *budget {
+                        "off" => None,
+                        _ => Some(
+                            budget
+                                .parse()
+                                .map_err(|_| anyhow!("Invalid budget '{}'", budget))?,
+                        ),
+                    });
+                }
+                if let Some(policy) = args.get(1) {
+                    watchdog.set_policy(parse_policy(policy)?);
+                }
+                match watchdog.budget() {
+                    Some(budget) => writeln!(
+                        self.out,
+                        "Budget {} commands, {:?}",
+                        budget,
+                        watchdog.policy()
+                    )?,
+                    None => writeln!(self.out, "Watchdog is off")?,
+                }
+            }
+            "profile" => {
+                let mut profiler = self.engine.vm_mut().profiler_mut();
+                match args.first().copied().unwrap_or("frame") {
+                    "on" => profiler.set_enabled(true),
+                    "off" => profiler.set_enabled(false),
+                    "reset" => profiler.reset(),
+                    "frame" => write!(self.out, "{}", profiler.last_frame())?,
+                    "session" => write!(self.out, "{}", profiler.session())?,
+                    arg => bail!("Unknown profile argument '{}'", arg),
+                }
+                if !profiler.is_enabled() {
+                    writeln!(self.out, "Profiler is off")?;
+                }
+            }
+            "trace" => match args {
+                ["off"] => {
+                    if let Some(mut trace) = self.engine.vm_mut().take_trace() {
+                        trace.flush()?;
+                    }
+                }
+                [path] => self
+                    .engine
+                    .vm_mut()
+                    .set_trace(Some(TraceWriter::create(path)?)),
+                _ => bail!("Expected <file>|off"),
+            },
+            "h" | "help" => writeln!(self.out, "{}", HELP)?,
+            "q" | "quit" => return Ok(false),
+            _ => writeln!(self.out, "Unknown command '{}', type 'help'", cmd)?,
+        }
+
+        Ok(true)
+    }
+}
+
+/// Line based debugger frontend.
+pub fn repl<R: BufRead, W: Write>(engine: &mut Engine, input: R, out: W) -> Result<()> {
+    let mut repl = Repl {
+        engine,
+        out,
+        cur_addr: 0,
+    };
+
+    write!(repl.out, "{}", PROMPT)?;
+    repl.out.flush()?;
+
+    for line in input.lines() {
+        match repl.exec(&line?) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) => writeln!(repl.out, "Error: {}", err)?,
+        }
+
+        write!(repl.out, "{}", PROMPT)?;
+        repl.out.flush()?;
+    }
+
+    Ok(())
+}
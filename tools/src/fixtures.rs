@@ -0,0 +1,15 @@
+use anyhow::{bail, Result};
+use awbi_core::fixtures::write_fixtures;
+
+/// `fixtures <out_dir>`
+pub fn run(args: &[String]) -> Result<()> {
+    let dir = match args {
+        [dir] => dir,
+        _ => bail!("Expected fixtures <out_dir>"),
+    };
+
+    write_fixtures(dir)?;
+    println!("Fixtures written to {}", dir);
+
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use anyhow::{bail, Result};
+use awbi_core::{
+    engine::Engine,
+    reference::Ref,
+    symbols::Symbols,
+    system::{HeadlessSystem, System},
+};
+use std::path::PathBuf;
+
+mod conformance;
+mod coverage;
+mod debugger;
+mod fixtures;
+mod strings;
+mod trace_diff;
+mod validate;
+mod verify;
+mod xref;
+
+fn proj_dir() -> Result<PathBuf> {
+    let mut dir = std::env::current_exe()?;
+
+    // Go to project folder
+    dir.pop();
+    dir.pop();
+    dir.pop();
+
+    Ok(dir)
+}
+
+fn data_dir(arg: Option<&String>) -> Result<String> {
+    if let Some(dir) = arg {
+        return Ok(dir.clone());
+    }
+
+    let mut dir = proj_dir()?;
+
+    dir.push("data");
+
+    Ok(dir.to_string_lossy().into())
+}
+
+fn headless_engine(data_dir: &str) -> Result<Engine> {
+    let sys: Ref<Box<dyn System>> = Ref::new(Box::new(HeadlessSystem::default()));
+    let mut engine = Engine::new(sys, data_dir, data_dir);
+
+    engine.init()?;
+    // The tools go over every part.
+    engine.preload()?;
+
+    if let Some(path) = std::env::var_os("AWBI_SYMBOLS") {
+        engine.vm_mut().set_symbols(Symbols::load(path)?);
+    }
+
+    Ok(engine)
+}
+
+fn usage() {
+    eprintln!("Usage: awbi-tools <command> [args]");
+    eprintln!();
+    eprintln!("Commands:");
+    eprintln!("    debug [data_dir]    interactive bytecode debugger");
+    eprintln!("    coverage <coverage_file> [--html <out_file>] [data_dir]");
+    eprintln!("                        annotated disassembly with the command hit counts");
+    eprintln!(
+        "    conformance <script> <golden> [--record <frames>] [--dumps <dir>] [--out <dir>]"
+    );
+    eprintln!("                [data_dir]");
+    eprintln!("                        compare frame and mixer hashes with a golden list");
+    eprintln!("    fixtures <out_dir>  synthetic memlist and bank files for tests");
+    eprintln!("    strings <out_file>  English string table to translate");
+    eprintln!("    trace-diff <left> <right> [--context <n>]");
+    eprintln!("                        first divergence of two execution traces");
+    eprintln!("    validate [--strict] [data_dir]");
+    eprintln!("                        static checks of the bytecode, --strict fails on warnings");
+    eprintln!(
+        "    verify [data_dir]   identify the data release and check every file and resource"
+    );
+    eprintln!("    xref [--json] [--var|--thread|--part|--string <id>] [data_dir]");
+    eprintln!(
+        "                        cross-references of variables, threads, resources and strings"
+    );
+}
+
+fn main() -> Result<()> {
+    awbi_core::logging::init_from_env()?;
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.first().map(|s| s.as_str()) {
+        Some("debug") => {
+            let mut engine = headless_engine(&data_dir(args.get(1))?)?;
+            let stdin = std::io::stdin();
+            debugger::repl(&mut engine, stdin.lock(), std::io::stdout())
+        }
+        Some("conformance") => conformance::run(&args[1..]),
+        Some("coverage") => coverage::run(&args[1..]),
+        Some("fixtures") => fixtures::run(&args[1..]),
+        Some("strings") => strings::run(&args[1..]),
+        Some("trace-diff") => trace_diff::run(&args[1..]),
+        Some("validate") => validate::run(&args[1..]),
+        Some("verify") => verify::run(&args[1..]),
+        Some("xref") => xref::run(&args[1..]),
+        _ => {
+            usage();
+            bail!("Unknown command");
+        }
+    }
+}
@@ -0,0 +1,17 @@
+use anyhow::{bail, Context, Result};
+use awbi_core::strings::StringTable;
+
+/// `strings <out_file>`
+pub fn run(args: &[String]) -> Result<()> {
+    let path = match args {
+        [path] => path,
+        _ => bail!("Expected strings <out_file>"),
+    };
+
+    let english = StringTable::english();
+    std::fs::write(path, english.to_toml()?)
+        .with_context(|| format!("Unable to write {}", path))?;
+    println!("{} strings written to {}", english.len(), path);
+
+    Ok(())
+}
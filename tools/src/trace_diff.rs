@@ -0,0 +1,44 @@
+use anyhow::{anyhow, bail, Context, Result};
+use awbi_core::trace;
+use std::{fs::File, io::BufReader};
+
+const DEFAULT_CONTEXT: usize = 10;
+
+fn open(path: &str) -> Result<BufReader<File>> {
+    let file = File::open(path).with_context(|| format!("Unable to open trace {:?}", path))?;
+    Ok(BufReader::new(file))
+}
+
+/// `trace-diff <left> <right> [--context <n>]`
+pub fn run(args: &[String]) -> Result<()> {
+    let mut paths = Vec::new();
+    let mut context = DEFAULT_CONTEXT;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--context" => {
+                let n = args
+                    .next()
+                    .ok_or_else(|| anyhow!("Expected --context <n>"))?;
+                context = n.parse().map_err(|_| anyhow!("Invalid context '{}'", n))?;
+            }
+            _ => paths.push(arg.as_str()),
+        }
+    }
+
+    let (left, right) = match paths[..] {
+        [left, right] => (left, right),
+        _ => bail!("Expected <left> <right>"),
+    };
+
+    match trace::diff(open(left)?, open(right)?, context)? {
+        Some(divergence) => {
+            print!("{}", divergence);
+            bail!("Traces differ");
+        }
+        None => println!("Traces are identical"),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,29 @@
+use crate::{data_dir, headless_engine};
+use anyhow::{bail, Result};
+use awbi_core::validator::Severity;
+
+/// `validate [--strict] [data_dir]`
+pub fn run(args: &[String]) -> Result<()> {
+    let strict = args.iter().any(|arg| arg == "--strict");
+    let data_arg = args.iter().find(|arg| !arg.starts_with("--"));
+
+    let engine = headless_engine(&data_dir(data_arg)?)?;
+    let issues = engine.validate()?;
+
+    for issue in &issues {
+        println!("{}", issue);
+    }
+
+    let errors = issues
+        .iter()
+        .filter(|issue| issue.severity() == Severity::Error)
+        .count();
+    let warnings = issues.len() - errors;
+    println!("{} errors, {} warnings", errors, warnings);
+
+    if errors > 0 || (strict && warnings > 0) {
+        bail!("Bytecode validation failed");
+    }
+
+    Ok(())
+}
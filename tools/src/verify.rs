@@ -0,0 +1,13 @@
+use crate::data_dir;
+use anyhow::Result;
+use awbi_core::{integrity, vfs};
+
+/// `verify [data_dir]`
+pub fn run(args: &[String]) -> Result<()> {
+    let data_dir = data_dir(args.first())?;
+
+    let report = integrity::verify(&*vfs::open(&data_dir))?;
+    print!("{}", report);
+
+    report.ensure_ok()
+}
@@ -0,0 +1,91 @@
+use crate::{data_dir, debugger::parse_num, headless_engine};
+use anyhow::{anyhow, bail, Result};
+use awbi_core::xref::Xref;
+use serde::Serialize;
+
+enum Query {
+    All,
+    Var(u8),
+    Thread(u8),
+    Part(u16),
+    String(u16),
+}
+
+fn print<T: Serialize>(val: &T, json: bool, text: impl FnOnce() -> String) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(val)?);
+    } else {
+        print!("{}", text());
+    }
+    Ok(())
+}
+
+fn lines<T: ToString>(items: &[T]) -> String {
+    items.iter().map(|i| i.to_string() + "\n").collect()
+}
+
+/// `xref [--json] [--var|--thread|--part|--string <id>] [data_dir]`
+pub fn run(args: &[String]) -> Result<()> {
+    let mut json = false;
+    let mut query = Query::All;
+    let mut data_arg = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut id = || -> Result<u16> {
+            let val = args
+                .next()
+                .ok_or_else(|| anyhow!("Missing {} argument", arg))?;
+            parse_num(val)
+        };
+
+        match arg.as_str() {
+            "--json" => json = true,
+            "--var" => query = Query::Var(id()? as u8),
+            "--thread" => query = Query::Thread(id()? as u8),
+            "--part" => query = Query::Part(id()?),
+            "--string" => query = Query::String(id()?),
+            _ if arg.starts_with("--") => bail!("Unknown option '{}'", arg),
+            _ => data_arg = Some(arg),
+        }
+    }
+
+    let engine = headless_engine(&data_dir(data_arg)?)?;
+    let xref: Xref = engine.xref()?;
+
+    match query {
+        Query::All => print(&xref, json, || xref.to_string()),
+        Query::Var(var_id) => {
+            let refs = xref.variable(var_id).cloned().unwrap_or_default();
+            print(&refs, json, || {
+                format!(
+                    "writes:\n{}reads:\n{}",
+                    lines(&refs.writes),
+                    lines(&refs.reads)
+                )
+            })
+        }
+        Query::Thread(thread_id) => {
+            let starts = xref.thread_starts(thread_id);
+            print(&starts, json, || {
+                starts
+                    .iter()
+                    .map(|s| format!("0x{:04X} from {}\n", s.target, s.from))
+                    .collect()
+            })
+        }
+        Query::Part(part_id) => {
+            let loads = xref.part_resources(part_id);
+            print(&loads, json, || {
+                loads
+                    .iter()
+                    .map(|l| format!("0x{:02X} at {}\n", l.res_id, l.from))
+                    .collect()
+            })
+        }
+        Query::String(str_id) => {
+            let users = xref.string_users(str_id);
+            print(&users, json, || lines(users))
+        }
+    }
+}